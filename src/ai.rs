@@ -0,0 +1,616 @@
+//! Computer opponent
+//!
+//! An `Ai` plays one player by returning the same `CommandKind`s a human would
+//! give, so it needs nothing special to work in single player, on a dedicated
+//! server or in replays. It only acts on what its player can see.
+//!
+//! Thinking is split into tasks that run round robin until the time budget
+//! for the tick is spent. The commands an AI gives therefore depend on how
+//! fast the machine is, so in a networked match only one machine runs each
+//! AI and its commands are shared like a human's.
+
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use cgmath::{InnerSpace, Vector2};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use super::command::CommandKind;
+use super::influence::InfluenceMap;
+use super::math::Vec2;
+use super::pathfinding;
+use super::sim::{Order, PlayerId, Tech, Unit, UnitId, UnitKind, World, DIRECTIONS};
+
+/// Time an AI may spend thinking in one tick unless told otherwise
+pub const DEFAULT_BUDGET: Duration = Duration::from_micros(500);
+/// A budget every task fits in, so what the AI does depends only on the
+/// world. For runs that have to be reproducible.
+pub const UNLIMITED_BUDGET: Duration = Duration::MAX;
+/// Enemies this close to one of our buildings pull the whole army back
+const DEFEND_RADIUS: f32 = 15.;
+/// Enemies this close to the army are fought where they stand
+const ENGAGE_RADIUS: f32 = 12.;
+/// How far from the headquarters new buildings go
+const BUILD_DISTANCE: f32 = 6.;
+/// Resource nodes within this distance of a headquarters belong to its base
+const BASE_RADIUS: f32 = 12.;
+/// Ticks before the first scout goes out
+const SCOUT_DELAY: u64 = 600;
+/// Most units a building is asked to queue at once, so money isn't tied up
+const MAX_QUEUED: usize = 2;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    Normal,
+    Hard,
+}
+
+impl Difficulty {
+    /// Ticks between decisions. Slow reactions are the main handicap.
+    fn think_interval(self) -> u64 {
+        match self {
+            Difficulty::Easy => 90,
+            Difficulty::Normal => 30,
+            Difficulty::Hard => 10,
+        }
+    }
+
+    fn max_workers(self) -> usize {
+        match self {
+            Difficulty::Easy => 8,
+            Difficulty::Normal => 14,
+            Difficulty::Hard => 20,
+        }
+    }
+
+    fn max_barracks(self) -> usize {
+        match self {
+            Difficulty::Easy => 1,
+            Difficulty::Normal => 2,
+            Difficulty::Hard => 3,
+        }
+    }
+
+    /// Soldiers gathered before going on the attack
+    fn attack_size(self) -> usize {
+        match self {
+            Difficulty::Easy => 12,
+            Difficulty::Normal => 8,
+            Difficulty::Hard => 6,
+        }
+    }
+
+    fn build_order(self) -> &'static [Step] {
+        use Step::*;
+        match self {
+            Difficulty::Easy => &[
+                Train(UnitKind::Worker), Train(UnitKind::Worker), Build(UnitKind::Barracks),
+                Train(UnitKind::Soldier), Train(UnitKind::Soldier),
+            ],
+            Difficulty::Normal => &[
+                Train(UnitKind::Worker), Train(UnitKind::Worker), Train(UnitKind::Worker),
+                Build(UnitKind::Barracks), Train(UnitKind::Worker), Train(UnitKind::Soldier),
+                Train(UnitKind::Soldier), Research(Tech::Weapons), Train(UnitKind::Soldier),
+            ],
+            Difficulty::Hard => &[
+                Train(UnitKind::Worker), Train(UnitKind::Worker), Train(UnitKind::Worker),
+                Build(UnitKind::Barracks), Train(UnitKind::Worker), Train(UnitKind::Worker),
+                Build(UnitKind::Barracks), Train(UnitKind::Soldier), Train(UnitKind::Soldier),
+                Research(Tech::Weapons), Train(UnitKind::Soldier), Train(UnitKind::Soldier),
+                Build(UnitKind::Headquarters), Research(Tech::Armor),
+            ],
+        }
+    }
+}
+
+impl FromStr for Difficulty {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "easy" => Ok(Difficulty::Easy),
+            "normal" => Ok(Difficulty::Normal),
+            "hard" => Ok(Difficulty::Hard),
+            _ => Err(format!("unknown difficulty {}, expected easy, normal or hard", name)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Step {
+    Train(UnitKind),
+    Build(UnitKind),
+    Research(Tech),
+}
+
+#[derive(Copy, Clone, Debug)]
+enum Task {
+    Economy,
+    BuildOrder,
+    Production,
+    Scouting,
+    Army,
+}
+
+const TASKS: [Task; 5] = [Task::Economy, Task::BuildOrder, Task::Production, Task::Scouting, Task::Army];
+
+/// What the AI knows this tick
+struct View<'a> {
+    world: &'a World,
+    /// Resources not yet promised to anything
+    resources: u32,
+    own: BTreeMap<UnitId, &'a Unit>,
+    enemies: Vec<(UnitId, &'a Unit)>,
+    /// Units queued at each building by commands given this tick
+    queued: BTreeMap<UnitId, usize>,
+}
+
+impl<'a> View<'a> {
+    fn own_of(&self, kind: UnitKind) -> impl Iterator<Item = (UnitId, &'a Unit)> + '_ {
+        self.own.iter().map(|(&id, &unit)| (id, unit)).filter(move |(_, unit)| unit.kind == kind)
+    }
+
+    fn count(&self, kind: UnitKind) -> usize {
+        self.own_of(kind).count()
+    }
+
+    fn cost(&self, kind: UnitKind) -> u32 {
+        self.world.stats(kind).cost
+    }
+
+    /// Where the main base is
+    fn home(&self) -> Option<Vector2<f32>> {
+        self.own_of(UnitKind::Headquarters)
+            .chain(self.own.iter().map(|(&id, &unit)| (id, unit)))
+            .map(|(_, unit)| unit.position.to_f32())
+            .next()
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct Ai {
+    player: PlayerId,
+    difficulty: Difficulty,
+    /// Next step of the build order
+    build_step: usize,
+    /// Next task to run, so thinking cut short by the budget resumes where it stopped
+    next_task: usize,
+    /// A worker on its way to put up a building
+    builder: Option<(UnitId, UnitKind)>,
+    scout: Option<UnitId>,
+    /// Places still to look at, the next one last
+    scout_targets: Vec<Vector2<f32>>,
+    /// Enemy buildings we know of, where they were last seen
+    enemy_buildings: BTreeMap<UnitId, Vector2<f32>>,
+    attacking: bool,
+    influence: InfluenceMap,
+    rng: ChaCha8Rng,
+}
+
+impl Ai {
+    pub fn new(world: &World, player: PlayerId, difficulty: Difficulty, seed: u64) -> Self {
+        let home = world.map.starts.iter().find(|start| start.player == player).map(|start| start.position);
+        let mut scout_targets: Vec<_> = world.map.starts.iter()
+            .filter(|start| start.player != player)
+            .map(|start| start.position)
+            .collect();
+        // Nearest last, so it's visited first
+        if let Some(home) = home {
+            scout_targets.sort_by(|a, b| (b - home).magnitude2().partial_cmp(&(a - home).magnitude2()).unwrap());
+        }
+
+        Self {
+            player,
+            difficulty,
+            build_step: 0,
+            next_task: 0,
+            builder: None,
+            scout: None,
+            scout_targets,
+            enemy_buildings: BTreeMap::new(),
+            attacking: false,
+            influence: InfluenceMap::new(world, player),
+            rng: ChaCha8Rng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn player(&self) -> PlayerId {
+        self.player
+    }
+
+    pub fn difficulty(&self) -> Difficulty {
+        self.difficulty
+    }
+
+    /// What the AI knows about the map, for the debug overlay
+    pub fn influence(&self) -> &InfluenceMap {
+        &self.influence
+    }
+
+    /// Decide what to do this tick, taking roughly no longer than `budget`.
+    /// Call once per tick, before stepping the world.
+    pub fn update(&mut self, world: &World, budget: Duration) -> Vec<CommandKind> {
+        let interval = self.difficulty.think_interval();
+        // Spread AIs across ticks rather than have them all think at once
+        if !(world.tick + self.player as u64).is_multiple_of(interval) {
+            return Vec::new();
+        }
+        let started = Instant::now();
+
+        if self.influence.is_stale(world.tick) {
+            self.influence.update(world);
+        }
+        let view = self.look(world);
+        let mut view = match view {
+            Some(view) => view,
+            // Nothing left to command
+            None => return Vec::new(),
+        };
+
+        let mut commands = Vec::new();
+        for _ in 0..TASKS.len() {
+            let task = TASKS[self.next_task];
+            self.next_task = (self.next_task + 1) % TASKS.len();
+            match task {
+                Task::Economy => self.economy(&view, &mut commands),
+                Task::BuildOrder => self.build_order(&mut view, &mut commands),
+                Task::Production => self.production(&mut view, &mut commands),
+                Task::Scouting => self.scouting(&view, &mut commands),
+                Task::Army => self.army(&view, &mut commands),
+            }
+            if started.elapsed() >= budget {
+                break;
+            }
+        }
+        commands
+    }
+
+    /// Gather what our player knows, and update what we remember
+    fn look<'a>(&mut self, world: &'a World) -> Option<View<'a>> {
+        let mut own = BTreeMap::new();
+        let mut enemies = Vec::new();
+        for (id, unit) in world.visible_units(self.player) {
+            if unit.owner == self.player {
+                own.insert(id, unit);
+            } else {
+                enemies.push((id, unit));
+            }
+        }
+        if own.is_empty() {
+            return None;
+        }
+
+        for &(id, unit) in &enemies {
+            if unit.kind.is_building() {
+                self.enemy_buildings.insert(id, unit.position.to_f32());
+            }
+        }
+        // Forget buildings we can see are gone
+        let fog = &world.fog;
+        let player = self.player;
+        self.enemy_buildings.retain(|id, &mut position| {
+            !fog.is_visible(player, position) || enemies.iter().any(|(enemy, _)| enemy == id)
+        });
+
+        // Money is spent when the builder arrives, so keep it aside until then
+        let mut resources = world.players[self.player as usize].resources;
+        match self.builder {
+            Some((builder, kind)) if own.get(&builder).is_some_and(|unit| matches!(unit.order, Order::Build { .. })) => {
+                resources = resources.saturating_sub(world.stats(kind).cost);
+            }
+            _ => self.builder = None,
+        }
+        if self.scout.is_some_and(|scout| !own.contains_key(&scout)) {
+            self.scout = None;
+        }
+
+        Some(View { world, resources, own, enemies, queued: BTreeMap::new() })
+    }
+
+    /// Send idle workers to the nearest resources
+    fn economy(&mut self, view: &View, commands: &mut Vec<CommandKind>) {
+        let mut idle: BTreeMap<usize, Vec<UnitId>> = BTreeMap::new();
+        for (id, unit) in view.own_of(UnitKind::Worker) {
+            if !matches!(unit.order, Order::Idle) || self.is_busy(id) {
+                continue;
+            }
+            if let Some(node) = nearest_node(view.world, unit.position.to_f32()) {
+                idle.entry(node).or_default().push(id);
+            }
+        }
+        for (node, units) in idle {
+            commands.push(CommandKind::Gather { units, node });
+        }
+    }
+
+    fn build_order(&mut self, view: &mut View, commands: &mut Vec<CommandKind>) {
+        let order = self.difficulty.build_order();
+        while let Some(&step) = order.get(self.build_step) {
+            let issued = match step {
+                Step::Train(kind) => self.train(view, kind, commands),
+                Step::Build(kind) => self.build(view, kind, commands),
+                Step::Research(tech) => self.research(view, tech, commands),
+            };
+            if !issued {
+                break;
+            }
+            self.build_step += 1;
+        }
+    }
+
+    /// Once the build order is done: keep the economy growing and the army coming
+    fn production(&mut self, view: &mut View, commands: &mut Vec<CommandKind>) {
+        if self.build_step < self.difficulty.build_order().len() {
+            return;
+        }
+
+        let queued_workers = view.own_of(UnitKind::Headquarters)
+            .flat_map(|(_, unit)| unit.queue.iter())
+            .filter(|&&kind| kind == UnitKind::Worker)
+            .count();
+        if view.count(UnitKind::Worker) + queued_workers < self.difficulty.max_workers() {
+            self.train(view, UnitKind::Worker, commands);
+        }
+
+        if view.count(UnitKind::Barracks) < self.difficulty.max_barracks() && view.resources >= view.cost(UnitKind::Barracks) + 100 {
+            self.build(view, UnitKind::Barracks, commands);
+        }
+        if self.difficulty != Difficulty::Easy && view.count(UnitKind::Headquarters) < 2 && self.base_running_dry(view) {
+            self.build(view, UnitKind::Headquarters, commands);
+        }
+
+        while self.train(view, UnitKind::Soldier, commands) {}
+
+        if self.difficulty != Difficulty::Easy && view.resources >= 250 {
+            for &tech in &[Tech::Weapons, Tech::Armor] {
+                self.research(view, tech, commands);
+            }
+        }
+    }
+
+    /// Send a worker to find the enemy
+    fn scouting(&mut self, view: &View, commands: &mut Vec<CommandKind>) {
+        if view.world.tick < SCOUT_DELAY {
+            return;
+        }
+        // Found them. Put the scout back to work.
+        if !self.enemy_buildings.is_empty() {
+            if let Some(scout) = self.scout.take() {
+                if let Some(node) = nearest_node(view.world, view.own[&scout].position.to_f32()) {
+                    commands.push(CommandKind::Gather { units: vec![scout], node });
+                }
+            }
+            return;
+        }
+
+        if self.scout.is_none() {
+            let builder = self.builder.map(|(builder, _)| builder);
+            self.scout = view.own_of(UnitKind::Worker)
+                .map(|(id, _)| id)
+                .find(|&id| Some(id) != builder);
+        }
+        let scout = match self.scout {
+            Some(scout) => view.own[&scout],
+            None => return,
+        };
+
+        let position = scout.position.to_f32();
+        while let Some(&target) = self.scout_targets.last() {
+            if (target - position).magnitude() > scout.sight_radius / 2. {
+                break;
+            }
+            self.scout_targets.pop();
+        }
+        if self.scout_targets.is_empty() {
+            // Looked everywhere we expected them. Try somewhere random.
+            let extent = view.world.map.terrain.extent();
+            let target = Vector2::new(self.rng.gen_range(0.0..extent.x), self.rng.gen_range(0.0..extent.y));
+            self.scout_targets.push(target);
+        }
+
+        let target = *self.scout_targets.last().unwrap();
+        let heading_there = matches!(scout.order, Order::Move { target: current } if current == Vec2::from_f32(target));
+        if !heading_there {
+            let units = vec![self.scout.unwrap()];
+            commands.push(CommandKind::Move { units, target: Vec2::from_f32(target) });
+        }
+    }
+
+    /// Defend the base, and attack once the army is big enough
+    fn army(&mut self, view: &View, commands: &mut Vec<CommandKind>) {
+        let soldiers: Vec<(UnitId, &Unit)> = view.own_of(UnitKind::Soldier).collect();
+        if soldiers.is_empty() {
+            self.attacking = false;
+            return;
+        }
+        let ids = || soldiers.iter().map(|&(id, _)| id).collect::<Vec<_>>();
+
+        let buildings: Vec<Vector2<f32>> = view.own.values()
+            .filter(|unit| unit.kind.is_building())
+            .map(|unit| unit.position.to_f32())
+            .collect();
+        let threat = view.enemies.iter()
+            .find(|(_, enemy)| buildings.iter().any(|&building| (enemy.position.to_f32() - building).magnitude() < DEFEND_RADIUS))
+            .map(|&(id, _)| id);
+        if let Some(target) = threat {
+            commands.push(CommandKind::Attack { units: ids(), target });
+            return;
+        }
+
+        let attack_size = self.difficulty.attack_size();
+        if !self.attacking && soldiers.len() >= attack_size {
+            self.attacking = true;
+        } else if self.attacking && soldiers.len() < (attack_size / 3).max(1) {
+            // Beaten. Regroup at home.
+            self.attacking = false;
+            if let Some(home) = view.home() {
+                commands.push(CommandKind::Move { units: ids(), target: Vec2::from_f32(home) });
+            }
+            return;
+        }
+        if !self.attacking {
+            return;
+        }
+
+        let center = soldiers.iter().map(|(_, unit)| unit.position.to_f32()).fold(Vector2::new(0., 0.), |sum, position| sum + position)
+            / soldiers.len() as f32;
+        let nearest_enemy = view.enemies.iter()
+            .map(|&(id, enemy)| (id, (enemy.position.to_f32() - center).magnitude()))
+            .filter(|&(_, distance)| distance < ENGAGE_RADIUS)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(id, _)| id);
+        if let Some(target) = nearest_enemy {
+            let units: Vec<UnitId> = soldiers.iter()
+                .filter(|(_, unit)| !matches!(unit.order, Order::Attack { target: current } if current == target))
+                .map(|&(id, _)| id)
+                .collect();
+            if !units.is_empty() {
+                commands.push(CommandKind::Attack { units, target });
+            }
+            return;
+        }
+
+        // Nothing close. Push where they're weakest, or head for their base.
+        let destination = self.influence.weakest_enemy_front()
+            .or_else(|| self.enemy_buildings.values()
+                .cloned()
+                .min_by(|a, b| (a - center).magnitude2().partial_cmp(&(b - center).magnitude2()).unwrap()))
+            .or_else(|| self.scout_targets.last().cloned());
+        if let Some(destination) = destination {
+            let units: Vec<UnitId> = soldiers.iter()
+                .filter(|(_, unit)| matches!(unit.order, Order::Idle))
+                .map(|&(id, _)| id)
+                .collect();
+            if !units.is_empty() {
+                commands.push(CommandKind::Move { units, target: Vec2::from_f32(destination) });
+            }
+        }
+    }
+
+    /// Queue `kind` at whichever of our buildings has the shortest queue
+    fn train(&mut self, view: &mut View, kind: UnitKind, commands: &mut Vec<CommandKind>) -> bool {
+        let cost = view.cost(kind);
+        if view.resources < cost {
+            return false;
+        }
+        let queued = &view.queued;
+        let queue_length = |id: &UnitId, unit: &Unit| unit.queue.len() + queued.get(id).cloned().unwrap_or(0);
+        let building = view.own.iter()
+            .filter(|&(id, unit)| unit.kind.trains().contains(&kind) && queue_length(id, unit) < MAX_QUEUED)
+            .min_by_key(|&(id, unit)| queue_length(id, unit))
+            .map(|(&id, _)| id);
+        let building = match building {
+            Some(building) => building,
+            None => return false,
+        };
+
+        commands.push(CommandKind::Train { building, kind });
+        view.resources -= cost;
+        *view.queued.entry(building).or_insert(0) += 1;
+        true
+    }
+
+    /// Send a worker to put up `kind`, if nobody is already building
+    fn build(&mut self, view: &mut View, kind: UnitKind, commands: &mut Vec<CommandKind>) -> bool {
+        let cost = view.cost(kind);
+        if self.builder.is_some() || view.resources < cost {
+            return false;
+        }
+        let position = match self.placement(view, kind) {
+            Some(position) => position,
+            None => return false,
+        };
+        let worker = view.own_of(UnitKind::Worker)
+            .filter(|&(id, _)| Some(id) != self.scout)
+            .map(|(id, unit)| (id, (unit.position.to_f32() - position).magnitude2()))
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(id, _)| id);
+        let worker = match worker {
+            Some(worker) => worker,
+            None => return false,
+        };
+
+        commands.push(CommandKind::Build { worker, kind, position: Vec2::from_f32(position) });
+        view.resources -= cost;
+        self.builder = Some((worker, kind));
+        true
+    }
+
+    fn research(&mut self, view: &mut View, tech: Tech, commands: &mut Vec<CommandKind>) -> bool {
+        let player = &view.world.players[self.player as usize];
+        if player.research.current.is_some() || player.has(tech) || view.resources < tech.cost() || view.count(UnitKind::Headquarters) == 0 {
+            return false;
+        }
+        commands.push(CommandKind::Research { tech });
+        view.resources -= tech.cost();
+        true
+    }
+
+    /// Where to put a new building: barracks next to the base, headquarters
+    /// at the richest, safest and nearest resources nobody has claimed
+    fn placement(&self, view: &View, kind: UnitKind) -> Option<Vector2<f32>> {
+        let home = view.home()?;
+        let map = &view.world.map;
+        let claimed = |position: Vector2<f32>| {
+            view.own.values().any(|unit| unit.kind == UnitKind::Headquarters && (unit.position.to_f32() - position).magnitude() < BASE_RADIUS)
+                || self.enemy_buildings.values().any(|&building| (building - position).magnitude() < BASE_RADIUS)
+        };
+
+        if kind == UnitKind::Headquarters {
+            // Measured along the way workers would walk, so a site across
+            // water or behind cliffs counts as far away, and one that can't
+            // be reached at all isn't considered
+            let walk = |to: Vector2<f32>| {
+                let path = pathfinding::find_path(map, home, to, BASE_RADIUS / 3.)?;
+                let (length, _) = path.iter().rev().fold((0., home), |(length, from), &to| (length + (to - from).magnitude(), to));
+                Some(length)
+            };
+            let unclaimed = map.resources.iter()
+                .filter(|node| node.amount > 0 && !claimed(node.position))
+                .filter_map(|node| Some((node.position, walk(node.position)?)));
+            let site = self.influence.safest_expansion_site(unclaimed)?;
+            // Beside the resources, on the side facing home
+            let toward_home = (home - site).normalize();
+            let position = site + toward_home * (BASE_RADIUS / 3.);
+            return Some(position).filter(|&position| map.is_passable(position));
+        }
+
+        let crowded = |position: Vector2<f32>| {
+            view.own.values().any(|unit| unit.kind.is_building() && (unit.position.to_f32() - position).magnitude() < 3.)
+                || map.resources.iter().any(|node| (node.position - position).magnitude() < 4.)
+        };
+        // Every compass direction, at two distances
+        [BUILD_DISTANCE, BUILD_DISTANCE * 2.].iter()
+            .flat_map(|&distance| DIRECTIONS.iter().map(move |&(x, y)| home + Vector2::new(x, y) * distance))
+            .find(|&position| map.is_passable(position) && !crowded(position))
+    }
+
+    /// Whether the resources around our headquarters are nearly gone
+    fn base_running_dry(&self, view: &View) -> bool {
+        let bases: Vec<Vector2<f32>> = view.own_of(UnitKind::Headquarters).map(|(_, unit)| unit.position.to_f32()).collect();
+        let remaining: u32 = view.world.map.resources.iter()
+            .filter(|node| bases.iter().any(|&base| (node.position - base).magnitude() < BASE_RADIUS))
+            .map(|node| node.amount)
+            .sum();
+        remaining < 500 || view.resources > 600
+    }
+
+    /// Whether a worker has a job the economy task shouldn't take it off
+    fn is_busy(&self, id: UnitId) -> bool {
+        self.scout == Some(id) || self.builder.is_some_and(|(builder, _)| builder == id)
+    }
+}
+
+/// The closest resource node with anything left in it
+fn nearest_node(world: &World, position: Vector2<f32>) -> Option<usize> {
+    world.map.resources.iter()
+        .enumerate()
+        .filter(|(_, node)| node.amount > 0)
+        .map(|(index, node)| (index, (node.position - position).magnitude2()))
+        .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+        .map(|(index, _)| index)
+}
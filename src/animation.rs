@@ -0,0 +1,472 @@
+//! Skinned meshes and their animations, loaded from glTF
+//!
+//! A skinned mesh is bent by a skeleton of joints, each vertex following up
+//! to four of them by weight. Clips move the joints over time, and an
+//! `AnimationState` says which clip an instance is playing, cross-fading
+//! from the last one for a moment after a change.
+
+use std::fmt;
+use std::path::Path;
+
+use cgmath::{InnerSpace, Matrix4, Quaternion, SquareMatrix, Vector3, VectorSpace};
+use wgpu::vertex_attr_array;
+
+use super::model::VertexDesc;
+
+/// Seconds taken to fade from one animation into the next
+pub const BLEND_TIME: f32 = 0.2;
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct SkinnedVertex {
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    pub tex_coords: [f32; 2],
+    /// The joints this vertex follows
+    pub joints: [u32; 4],
+    /// How much it follows each of `joints`, adding up to 1
+    pub weights: [f32; 4],
+}
+
+const SKINNED_VERTEX_ATTRS: [wgpu::VertexAttribute; 5] = vertex_attr_array![
+    0 => Float32x3,
+    1 => Float32x3,
+    2 => Float32x2,
+    3 => Uint32x4,
+    4 => Float32x4,
+];
+
+impl VertexDesc for SkinnedVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<SkinnedVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &SKINNED_VERTEX_ATTRS,
+        }
+    }
+}
+
+/// The animations every unit model is expected to have, found by clip name
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum AnimationKind {
+    Idle,
+    Walk,
+    Attack,
+    Die,
+}
+
+impl AnimationKind {
+    pub const ALL: [AnimationKind; 4] = [AnimationKind::Idle, AnimationKind::Walk, AnimationKind::Attack, AnimationKind::Die];
+
+    /// Name of the glTF animation to play
+    pub fn clip_name(self) -> &'static str {
+        match self {
+            AnimationKind::Idle => "idle",
+            AnimationKind::Walk => "walk",
+            AnimationKind::Attack => "attack",
+            AnimationKind::Die => "die",
+        }
+    }
+
+    /// Dying plays once and holds the last frame. Everything else loops.
+    pub fn loops(self) -> bool {
+        self != AnimationKind::Die
+    }
+}
+
+/// A joint's placement relative to its parent
+#[derive(Copy, Clone, Debug)]
+pub struct Transform {
+    pub translation: Vector3<f32>,
+    pub rotation: Quaternion<f32>,
+    pub scale: Vector3<f32>,
+}
+
+impl Transform {
+    pub fn to_matrix(&self) -> Matrix4<f32> {
+        Matrix4::from_translation(self.translation)
+            * Matrix4::from(self.rotation)
+            * Matrix4::from_nonuniform_scale(self.scale.x, self.scale.y, self.scale.z)
+    }
+
+    /// `t` of the way from `self` to `other`
+    pub fn blend(&self, other: &Transform, t: f32) -> Transform {
+        Transform {
+            translation: self.translation.lerp(other.translation, t),
+            rotation: nlerp(self.rotation, other.rotation, t),
+            scale: self.scale.lerp(other.scale, t),
+        }
+    }
+}
+
+/// Interpolate rotations the short way round
+fn nlerp(a: Quaternion<f32>, b: Quaternion<f32>, t: f32) -> Quaternion<f32> {
+    let b = if a.dot(b) < 0. { -b } else { b };
+    (a * (1. - t) + b * t).normalize()
+}
+
+#[derive(Clone, Debug)]
+pub struct Joint {
+    pub parent: Option<usize>,
+    /// Takes a vertex from the mesh's bind pose into this joint's space
+    pub inverse_bind: Matrix4<f32>,
+    /// Where the joint sits when no clip moves it
+    pub rest: Transform,
+    /// For joints without a parent joint, the placement of the nodes above
+    /// them in the scene. Identity for every other joint.
+    pub base: Matrix4<f32>,
+}
+
+#[derive(Clone, Debug)]
+pub struct Skeleton {
+    /// In the order vertices refer to them by
+    pub joints: Vec<Joint>,
+    /// Joint indices with every parent before its children
+    order: Vec<usize>,
+}
+
+/// One transform per joint
+pub type Pose = Vec<Transform>;
+
+impl Skeleton {
+    pub fn rest_pose(&self) -> Pose {
+        self.joints.iter().map(|joint| joint.rest).collect()
+    }
+
+    /// Append the matrix that takes bind pose vertices to `pose` for each joint
+    pub fn joint_matrices(&self, pose: &[Transform], out: &mut Vec<[[f32; 4]; 4]>) {
+        let mut global = vec![Matrix4::identity(); self.joints.len()];
+        for &index in &self.order {
+            let joint = &self.joints[index];
+            let parent = joint.parent.map_or(joint.base, |parent| global[parent]);
+            global[index] = parent * pose[index].to_matrix();
+        }
+        out.extend(self.joints.iter().zip(&global).map(|(joint, global)| -> [[f32; 4]; 4] { (global * joint.inverse_bind).into() }));
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Keyframes {
+    Translation(Vec<Vector3<f32>>),
+    Rotation(Vec<Quaternion<f32>>),
+    Scale(Vec<Vector3<f32>>),
+}
+
+#[derive(Clone, Debug)]
+struct Channel {
+    joint: usize,
+    /// Seconds, increasing
+    times: Vec<f32>,
+    keyframes: Keyframes,
+    /// Hold each key until the next rather than interpolating
+    step: bool,
+}
+
+impl Channel {
+    /// The keys either side of `time` and how far it is between them
+    fn keys_at(&self, time: f32) -> (usize, usize, f32) {
+        let next = self.times.iter().position(|&key| key > time).unwrap_or(self.times.len());
+        if next == 0 {
+            return (0, 0, 0.);
+        }
+        if next == self.times.len() {
+            return (next - 1, next - 1, 0.);
+        }
+        let (start, end) = (self.times[next - 1], self.times[next]);
+        let t = if self.step { 0. } else { (time - start) / (end - start) };
+        (next - 1, next, t)
+    }
+
+    fn apply(&self, pose: &mut [Transform], time: f32) {
+        let (a, b, t) = self.keys_at(time);
+        let transform = &mut pose[self.joint];
+        match &self.keyframes {
+            Keyframes::Translation(keys) => transform.translation = keys[a].lerp(keys[b], t),
+            Keyframes::Rotation(keys) => transform.rotation = nlerp(keys[a], keys[b], t),
+            Keyframes::Scale(keys) => transform.scale = keys[a].lerp(keys[b], t),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Clip {
+    pub name: String,
+    /// Seconds
+    pub duration: f32,
+    channels: Vec<Channel>,
+}
+
+impl Clip {
+    /// Move the joints this clip animates to where they are `time` seconds in
+    pub fn apply(&self, pose: &mut [Transform], time: f32) {
+        for channel in &self.channels {
+            channel.apply(pose, time);
+        }
+    }
+}
+
+/// A skeleton with the clips that move it
+#[derive(Clone, Debug)]
+pub struct Rig {
+    pub skeleton: Skeleton,
+    pub clips: Vec<Clip>,
+}
+
+impl Rig {
+    pub fn clip(&self, kind: AnimationKind) -> Option<&Clip> {
+        self.clips.iter().find(|clip| clip.name.eq_ignore_ascii_case(kind.clip_name()))
+    }
+
+    /// The pose `elapsed` seconds into `kind`. A model without that clip stands at rest.
+    pub fn sample(&self, kind: AnimationKind, elapsed: f32) -> Pose {
+        let mut pose = self.skeleton.rest_pose();
+        if let Some(clip) = self.clip(kind) {
+            let time = if !kind.loops() {
+                elapsed.min(clip.duration)
+            } else if clip.duration > 0. {
+                elapsed % clip.duration
+            } else {
+                0.
+            };
+            clip.apply(&mut pose, time);
+        }
+        pose
+    }
+
+    /// The pose of an instance at `time`, blending out of the previous
+    /// animation if it changed less than `BLEND_TIME` ago
+    pub fn pose(&self, state: &AnimationState, time: f32) -> Pose {
+        let current = self.sample(state.current.kind, time - state.current.started);
+        let previous = match state.previous {
+            Some(previous) => previous,
+            None => return current,
+        };
+        let t = (time - state.current.started) / BLEND_TIME;
+        if t >= 1. {
+            return current;
+        }
+        let previous = self.sample(previous.kind, time - previous.started);
+        previous.iter().zip(&current).map(|(from, to)| from.blend(to, t.max(0.))).collect()
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+struct Playing {
+    kind: AnimationKind,
+    /// When it began, in the same seconds as the times passed to `Rig::pose`
+    started: f32,
+}
+
+/// What one instance is playing
+#[derive(Copy, Clone, Debug)]
+pub struct AnimationState {
+    current: Playing,
+    previous: Option<Playing>,
+}
+
+impl AnimationState {
+    pub fn new(kind: AnimationKind, time: f32) -> Self {
+        Self {
+            current: Playing { kind, started: time },
+            previous: None,
+        }
+    }
+
+    pub fn kind(&self) -> AnimationKind {
+        self.current.kind
+    }
+
+    /// Switch to `kind` at `time`, fading out of what was playing. Asking for
+    /// what is already playing carries on with it rather than restarting.
+    pub fn play(&mut self, kind: AnimationKind, time: f32) {
+        if kind != self.current.kind {
+            self.previous = Some(self.current);
+            self.current = Playing { kind, started: time };
+        }
+    }
+}
+
+pub struct SkinnedMesh {
+    pub vertices: Vec<SkinnedVertex>,
+    pub indices: Vec<u32>,
+    pub rig: Rig,
+}
+
+impl SkinnedMesh {
+    /// Load the first skinned mesh in a glTF file, with the file's animations
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, AnimationError> {
+        let (document, buffers, _) = gltf::import(path)?;
+        let node = document.nodes()
+            .find(|node| node.mesh().is_some() && node.skin().is_some())
+            .ok_or(AnimationError::NoSkin)?;
+        let (mesh, skin) = (node.mesh().unwrap(), node.skin().unwrap());
+
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for primitive in mesh.primitives() {
+            let reader = primitive.reader(|buffer| Some(&buffers[buffer.index()]));
+            let base = vertices.len() as u32;
+            let positions: Vec<[f32; 3]> = match reader.read_positions() {
+                Some(positions) => positions.collect(),
+                None => continue,
+            };
+            let count = positions.len();
+            let normals: Vec<[f32; 3]> = reader.read_normals().map_or_else(|| vec![[0., 1., 0.]; count], Iterator::collect);
+            let tex_coords: Vec<[f32; 2]> = reader.read_tex_coords(0).map_or_else(|| vec![[0., 0.]; count], |uvs| uvs.into_f32().collect());
+            let joints: Vec<[u16; 4]> = reader.read_joints(0).map_or_else(|| vec![[0; 4]; count], |joints| joints.into_u16().collect());
+            let weights: Vec<[f32; 4]> = reader.read_weights(0).map_or_else(|| vec![[1., 0., 0., 0.]; count], |weights| weights.into_f32().collect());
+            for index in 0..count {
+                let [a, b, c, d] = joints[index];
+                vertices.push(SkinnedVertex {
+                    position: positions[index],
+                    normal: normals[index],
+                    tex_coords: tex_coords[index],
+                    joints: [a as u32, b as u32, c as u32, d as u32],
+                    weights: weights[index],
+                });
+            }
+            match reader.read_indices() {
+                Some(read) => indices.extend(read.into_u32().map(|index| base + index)),
+                None => indices.extend(base..base + count as u32),
+            }
+        }
+
+        let skeleton = load_skeleton(&document, &buffers, &skin);
+        let clips = document.animations()
+            .map(|animation| load_clip(&animation, &buffers, &skin))
+            .collect();
+        Ok(Self { vertices, indices, rig: Rig { skeleton, clips } })
+    }
+}
+
+fn load_skeleton(document: &gltf::Document, buffers: &[gltf::buffer::Data], skin: &gltf::Skin) -> Skeleton {
+    let mut node_parents = vec![None; document.nodes().count()];
+    for node in document.nodes() {
+        for child in node.children() {
+            node_parents[child.index()] = Some(node.index());
+        }
+    }
+    let joint_nodes: Vec<usize> = skin.joints().map(|node| node.index()).collect();
+    let nodes: Vec<gltf::Node> = document.nodes().collect();
+    let local = |node: usize| Matrix4::from(nodes[node].transform().matrix());
+
+    let inverse_binds: Vec<Matrix4<f32>> = match skin.reader(|buffer| Some(&buffers[buffer.index()])).read_inverse_bind_matrices() {
+        Some(matrices) => matrices.map(Matrix4::from).collect(),
+        None => vec![Matrix4::identity(); joint_nodes.len()],
+    };
+
+    let joints: Vec<Joint> = joint_nodes.iter().enumerate().map(|(index, &node)| {
+        let parent = node_parents[node].and_then(|parent| joint_nodes.iter().position(|&joint| joint == parent));
+        // Anything above the skeleton's root, like an armature node, still places it
+        let mut base = Matrix4::identity();
+        if parent.is_none() {
+            let mut ancestor = node_parents[node];
+            while let Some(above) = ancestor {
+                base = local(above) * base;
+                ancestor = node_parents[above];
+            }
+        }
+        let (translation, rotation, scale) = nodes[node].transform().decomposed();
+        Joint {
+            parent,
+            inverse_bind: inverse_binds[index],
+            rest: Transform {
+                translation: translation.into(),
+                rotation: quaternion(rotation),
+                scale: scale.into(),
+            },
+            base,
+        }
+    }).collect();
+
+    let depth = |mut joint: usize| {
+        let mut depth = 0;
+        while let Some(parent) = joints[joint].parent {
+            joint = parent;
+            depth += 1;
+        }
+        depth
+    };
+    let mut order: Vec<usize> = (0..joints.len()).collect();
+    order.sort_by_key(|&joint| depth(joint));
+
+    Skeleton { joints, order }
+}
+
+/// Only channels on the skin's joints are kept. Cubic spline keys are
+/// followed linearly through their values.
+fn load_clip(animation: &gltf::Animation, buffers: &[gltf::buffer::Data], skin: &gltf::Skin) -> Clip {
+    use gltf::animation::util::ReadOutputs;
+    use gltf::animation::Interpolation;
+
+    let joint_nodes: Vec<usize> = skin.joints().map(|node| node.index()).collect();
+    let mut channels = Vec::new();
+    for channel in animation.channels() {
+        let joint = match joint_nodes.iter().position(|&node| node == channel.target().node().index()) {
+            Some(joint) => joint,
+            None => continue,
+        };
+        let reader = channel.reader(|buffer| Some(&buffers[buffer.index()]));
+        let times: Vec<f32> = match reader.read_inputs() {
+            Some(inputs) => inputs.collect(),
+            None => continue,
+        };
+        let interpolation = channel.sampler().interpolation();
+        // Cubic splines store an in-tangent, value and out-tangent per key
+        let values = |index: usize| interpolation != Interpolation::CubicSpline || index % 3 == 1;
+        let keyframes = match reader.read_outputs() {
+            Some(ReadOutputs::Translations(keys)) => Keyframes::Translation(keys.enumerate().filter(|(i, _)| values(*i)).map(|(_, key)| key.into()).collect()),
+            Some(ReadOutputs::Rotations(keys)) => Keyframes::Rotation(keys.into_f32().enumerate().filter(|(i, _)| values(*i)).map(|(_, key)| quaternion(key)).collect()),
+            Some(ReadOutputs::Scales(keys)) => Keyframes::Scale(keys.enumerate().filter(|(i, _)| values(*i)).map(|(_, key)| key.into()).collect()),
+            Some(ReadOutputs::MorphTargetWeights(_)) | None => continue,
+        };
+        channels.push(Channel {
+            joint,
+            times,
+            keyframes,
+            step: interpolation == Interpolation::Step,
+        });
+    }
+
+    Clip {
+        name: animation.name().unwrap_or_default().to_string(),
+        duration: channels.iter().filter_map(|channel| channel.times.last()).cloned().fold(0., f32::max),
+        channels,
+    }
+}
+
+/// glTF stores rotations as [x, y, z, w]
+fn quaternion([x, y, z, w]: [f32; 4]) -> Quaternion<f32> {
+    Quaternion::new(w, x, y, z)
+}
+
+#[derive(Debug)]
+pub enum AnimationError {
+    Gltf(gltf::Error),
+    /// The file has no mesh with a skin to animate it by
+    NoSkin,
+}
+
+impl fmt::Display for AnimationError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AnimationError::Gltf(err) => write!(f, "{}", err),
+            AnimationError::NoSkin => write!(f, "no skinned mesh"),
+        }
+    }
+}
+
+impl std::error::Error for AnimationError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            AnimationError::Gltf(err) => Some(err),
+            AnimationError::NoSkin => None,
+        }
+    }
+}
+
+impl From<gltf::Error> for AnimationError {
+    fn from(err: gltf::Error) -> Self {
+        AnimationError::Gltf(err)
+    }
+}
@@ -0,0 +1,130 @@
+//! Unit stats, and the stat files designers tune them with
+//!
+//! A stat file is JSON with an entry per unit kind, like
+//! `{ "soldier": { "sight_radius": 8, "max_health": 90, "cost": 75,
+//! "build_time": 900, "speed": 0.06, "weapon": { "damage": 8, "range": 5,
+//! "cooldown": 60 } } }`. Kinds left out keep their default stats.
+//!
+//! The stats are part of the match rules, so they travel with replays and to
+//! every player in a networked match.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::sim::{UnitKind, Weapon};
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct UnitStats {
+    pub sight_radius: f32,
+    pub max_health: u32,
+    pub cost: u32,
+    /// Ticks to train. Buildings go up as soon as the worker arrives.
+    pub build_time: u32,
+    /// Distance covered per tick
+    pub speed: f32,
+    pub weapon: Option<Weapon>,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatTable {
+    pub worker: UnitStats,
+    pub soldier: UnitStats,
+    pub headquarters: UnitStats,
+    pub barracks: UnitStats,
+}
+
+impl Default for StatTable {
+    fn default() -> Self {
+        Self {
+            worker: UnitStats {
+                sight_radius: 6.,
+                max_health: 40,
+                cost: 50,
+                build_time: 600,
+                speed: 0.05,
+                weapon: Some(Weapon { damage: 3, range: 1., cooldown: 90 }),
+            },
+            soldier: UnitStats {
+                sight_radius: 8.,
+                max_health: 80,
+                cost: 75,
+                build_time: 900,
+                speed: 0.06,
+                weapon: Some(Weapon { damage: 8, range: 5., cooldown: 60 }),
+            },
+            headquarters: UnitStats {
+                sight_radius: 10.,
+                max_health: 1500,
+                cost: 400,
+                build_time: 0,
+                speed: 0.,
+                weapon: None,
+            },
+            barracks: UnitStats {
+                sight_radius: 7.,
+                max_health: 800,
+                cost: 150,
+                build_time: 0,
+                speed: 0.,
+                weapon: None,
+            },
+        }
+    }
+}
+
+impl StatTable {
+    pub fn get(&self, kind: UnitKind) -> &UnitStats {
+        match kind {
+            UnitKind::Worker => &self.worker,
+            UnitKind::Soldier => &self.soldier,
+            UnitKind::Headquarters => &self.headquarters,
+            UnitKind::Barracks => &self.barracks,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, StatFileError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+#[derive(Debug)]
+pub enum StatFileError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for StatFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StatFileError::Io(err) => write!(f, "{}", err),
+            StatFileError::Parse(err) => write!(f, "invalid stat file: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for StatFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StatFileError::Io(err) => Some(err),
+            StatFileError::Parse(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for StatFileError {
+    fn from(err: io::Error) -> Self {
+        StatFileError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for StatFileError {
+    fn from(err: serde_json::Error) -> Self {
+        StatFileError::Parse(err)
+    }
+}
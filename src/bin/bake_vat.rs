@@ -0,0 +1,80 @@
+//! Vertex animation baker: turns a skinned glTF model into a file of vertex
+//! animation textures for drawing crowds
+//!
+//! bake_vat <model.gltf> [--output <path>] [--frame-rate <fps>]
+//!
+//! Every clip the game knows (idle, walk, attack, die) is sampled
+//! `--frame-rate` times a second (30 by default) and the skinned vertices of
+//! each frame written out. The output goes next to the model with the
+//! extension `.vat` unless `--output` says otherwise.
+
+use std::path::PathBuf;
+
+use rts::animation::{AnimationKind, SkinnedMesh};
+use rts::vat::{self, VertexAnimation};
+
+struct Options {
+    model: PathBuf,
+    output: Option<PathBuf>,
+    frame_rate: f32,
+}
+
+fn main() {
+    let options = parse_options();
+
+    let mesh = SkinnedMesh::load(&options.model)
+        .unwrap_or_else(|err| exit(&format!("{}: {}", options.model.display(), err)));
+    for &kind in AnimationKind::ALL.iter() {
+        if mesh.rig.clip(kind).is_none() {
+            eprintln!("No {} clip, baking the rest pose instead", kind.clip_name());
+        }
+    }
+
+    let animation = VertexAnimation::bake(&mesh, options.frame_rate);
+    if let Err(reason) = vat::validate(&animation) {
+        exit(&format!("Can't bake {}: {}", options.model.display(), reason));
+    }
+
+    let output = match options.output {
+        Some(output) => output,
+        None => options.model.with_extension("vat"),
+    };
+    vat::save(&animation, &output).unwrap_or_else(|err| exit(&format!("{}: {}", output.display(), err)));
+    println!("Baked {} vertices over {} frames to {}", animation.vertex_count, animation.frame_count(), output.display());
+}
+
+fn parse_options() -> Options {
+    let mut model = None;
+    let mut output = None;
+    let mut frame_rate = vat::DEFAULT_FRAME_RATE;
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| exit(&format!("{} needs a value", flag)));
+        match flag.as_str() {
+            "--output" => output = Some(PathBuf::from(value())),
+            "--frame-rate" => frame_rate = parse(&value()),
+            _ if flag.starts_with("--") => exit(&format!("Unknown option {}", flag)),
+            _ if model.is_none() => model = Some(PathBuf::from(&flag)),
+            _ => exit(&format!("Unexpected argument {}", flag)),
+        }
+    }
+    if frame_rate.is_nan() || frame_rate <= 0. {
+        exit("--frame-rate must be above 0");
+    }
+
+    Options {
+        model: model.unwrap_or_else(|| exit("Usage: bake_vat <model.gltf> [--output <path>] [--frame-rate <fps>]")),
+        output,
+        frame_rate,
+    }
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| exit(&format!("Invalid value {}", value)))
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
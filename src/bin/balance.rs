@@ -0,0 +1,316 @@
+//! Balance testing: AI-vs-AI matches in bulk, with no window or GPU
+//!
+//! balance [--stats <file>]... [--runs <count>] [--map <path>]
+//!         [--difficulty <difficulty>] [--ticks <limit>] [--seed <first seed>]
+//!         [--threads <count>] [--format <csv|json>] [--output <path>]
+//!
+//! Every stat file is played `--runs` times, run n with seed `--seed + n`, so
+//! each stat file is tried on the same set of matches and any run can be
+//! played again exactly. Without `--stats` the default stats are used.
+//! Matches run as fast as they can, spread over `--threads` (every core by
+//! default), and a match still going at `--ticks` goes to the highest score.
+//!
+//! The output has one row per stat file: how often each player won, the
+//! average match length, and what an average player produced of each kind of
+//! unit, lost and gathered in a match.
+
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{mpsc, Arc};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use rts::ai::{self, Ai, Difficulty};
+use rts::balance::StatTable;
+use rts::command::Command;
+use rts::lifecycle::{self, MatchRules, Standing, Stats};
+use rts::map::Map;
+use rts::mapfile;
+use rts::mapgen::{self, MapGenParams};
+use rts::sim::{PlayerId, UnitKind, World};
+
+/// Half an hour at 60 ticks a second
+const DEFAULT_TICKS: u64 = 108_000;
+
+#[derive(Copy, Clone)]
+enum Format {
+    Csv,
+    Json,
+}
+
+struct Options {
+    stats: Vec<String>,
+    runs: usize,
+    map: Option<String>,
+    difficulty: Difficulty,
+    ticks: u64,
+    seed: u64,
+    threads: usize,
+    format: Format,
+    output: Option<String>,
+}
+
+/// One match to play
+struct Job {
+    table: usize,
+    seed: u64,
+}
+
+struct MatchResult {
+    /// `None` for a draw
+    winner: Option<PlayerId>,
+    ticks: u64,
+    stats: Vec<Stats>,
+}
+
+/// What came of every match played with one stat file
+#[derive(Serialize)]
+struct Row {
+    stats: String,
+    runs: usize,
+    draws: usize,
+    /// By player
+    win_rate: Vec<f64>,
+    average_ticks: f64,
+    /// Per player per match, by unit kind
+    average_produced: BTreeMap<String, f64>,
+    average_lost: f64,
+    average_gathered: f64,
+}
+
+fn main() {
+    let options = parse_options();
+
+    let map = match &options.map {
+        Some(path) => mapfile::load(path).unwrap_or_else(|err| exit(&format!("{}: {}", path, err))),
+        None => mapgen::generate(&MapGenParams { seed: 0, size: 128, players: 2 }),
+    };
+    let tables: Vec<(String, StatTable)> = if options.stats.is_empty() {
+        vec![("default".to_string(), StatTable::default())]
+    } else {
+        options.stats.iter()
+            .map(|path| (path.clone(), StatTable::load(path).unwrap_or_else(|err| exit(&format!("{}: {}", path, err)))))
+            .collect()
+    };
+
+    let jobs: Vec<Job> = (0..tables.len())
+        .flat_map(|table| (0..options.runs).map(move |run| (table, run)))
+        .map(|(table, run)| Job { table, seed: options.seed + run as u64 })
+        .collect();
+    eprintln!("Playing {} matches on {} threads", jobs.len(), options.threads);
+
+    let started = Instant::now();
+    let results = run_all(&options, map, &tables, jobs);
+    eprintln!("Done in {:.1}s", started.elapsed().as_secs_f32());
+
+    let rows: Vec<Row> = tables.iter()
+        .zip(results)
+        .map(|((name, _), results)| summarize(name, &results))
+        .collect();
+
+    let mut out: Box<dyn Write> = match &options.output {
+        Some(path) => Box::new(BufWriter::new(File::create(path).unwrap_or_else(|err| exit(&format!("{}: {}", path, err))))),
+        None => Box::new(io::stdout()),
+    };
+    let written = match options.format {
+        Format::Csv => write_csv(&mut out, &rows),
+        Format::Json => serde_json::to_writer_pretty(&mut out, &rows).map_err(io::Error::from).and_then(|()| writeln!(out)),
+    };
+    if let Err(err) = written.and_then(|()| out.flush()) {
+        exit(&format!("Failed to write results: {}", err));
+    }
+}
+
+/// Play every job across the worker threads. Returns the results grouped by
+/// stat table, in seed order, however the threads happened to finish.
+fn run_all(options: &Options, map: Map, tables: &[(String, StatTable)], jobs: Vec<Job>) -> Vec<Vec<MatchResult>> {
+    let map = Arc::new(map);
+    let jobs = Arc::new(jobs);
+    let next_job = Arc::new(AtomicUsize::new(0));
+    let (sender, receiver) = mpsc::channel();
+
+    let workers: Vec<_> = (0..options.threads)
+        .map(|_| {
+            let (map, jobs, next_job, sender) = (map.clone(), jobs.clone(), next_job.clone(), sender.clone());
+            let tables: Vec<StatTable> = tables.iter().map(|(_, table)| *table).collect();
+            let (difficulty, ticks) = (options.difficulty, options.ticks);
+            std::thread::spawn(move || loop {
+                let index = next_job.fetch_add(1, Ordering::Relaxed);
+                let job = match jobs.get(index) {
+                    Some(job) => job,
+                    None => break,
+                };
+                let rules = MatchRules { time_limit: Some(ticks), units: tables[job.table], ..MatchRules::default() };
+                let result = play(&map, rules, job.seed, difficulty);
+                if sender.send((index, result)).is_err() {
+                    break;
+                }
+            })
+        })
+        .collect();
+    drop(sender);
+
+    let mut results: BTreeMap<usize, MatchResult> = BTreeMap::new();
+    for (index, result) in receiver {
+        results.insert(index, result);
+        if results.len().is_multiple_of(100) {
+            eprintln!("{}/{}", results.len(), jobs.len());
+        }
+    }
+    for worker in workers {
+        if worker.join().is_err() {
+            exit("A match panicked");
+        }
+    }
+
+    let mut grouped: Vec<Vec<MatchResult>> = tables.iter().map(|_| Vec::new()).collect();
+    for (index, result) in results {
+        grouped[jobs[index].table].push(result);
+    }
+    grouped
+}
+
+/// Play one match to the end with the computer on every side
+fn play(map: &Map, rules: MatchRules, seed: u64, difficulty: Difficulty) -> MatchResult {
+    let mut world = World::with_rules(map.clone(), seed, rules);
+    let mut ais: Vec<Ai> = (0..world.players.len() as PlayerId)
+        .map(|player| Ai::new(&world, player, difficulty, seed + player as u64))
+        .collect();
+
+    while !lifecycle::is_over(&world) {
+        let mut commands = Vec::new();
+        for ai in &mut ais {
+            // However long thinking takes, so the run can be reproduced
+            for kind in ai.update(&world, ai::UNLIMITED_BUDGET) {
+                commands.push(Command { tick: world.tick, player: ai.player(), kind });
+            }
+        }
+        for command in &commands {
+            world.apply(command);
+        }
+        world.step();
+    }
+
+    let winners: Vec<PlayerId> = world.players.iter()
+        .enumerate()
+        .filter(|(_, player)| matches!(player.standing, Standing::Won { .. }))
+        .map(|(id, _)| id as PlayerId)
+        .collect();
+    MatchResult {
+        winner: if winners.len() == 1 { Some(winners[0]) } else { None },
+        ticks: world.tick,
+        stats: world.players.iter().map(|player| player.stats).collect(),
+    }
+}
+
+fn summarize(name: &str, results: &[MatchResult]) -> Row {
+    let runs = results.len();
+    let players = results.iter().map(|result| result.stats.len()).max().unwrap_or(0);
+    let player_matches = results.iter().map(|result| result.stats.len()).sum::<usize>().max(1) as f64;
+    let all_stats = || results.iter().flat_map(|result| result.stats.iter());
+
+    let win_rate = (0..players)
+        .map(|player| {
+            let wins = results.iter().filter(|result| result.winner == Some(player as PlayerId)).count();
+            wins as f64 / runs.max(1) as f64
+        })
+        .collect();
+    let average_produced = UnitKind::ALL.iter()
+        .map(|&kind| {
+            let total: u32 = all_stats().map(|stats| stats.produced[kind as usize]).sum();
+            (format!("{:?}", kind).to_lowercase(), total as f64 / player_matches)
+        })
+        .collect();
+
+    Row {
+        stats: name.to_string(),
+        runs,
+        draws: results.iter().filter(|result| result.winner.is_none()).count(),
+        win_rate,
+        average_ticks: results.iter().map(|result| result.ticks as f64).sum::<f64>() / runs.max(1) as f64,
+        average_produced,
+        average_lost: all_stats().map(|stats| stats.units_lost as f64).sum::<f64>() / player_matches,
+        average_gathered: all_stats().map(|stats| stats.resources_gathered as f64).sum::<f64>() / player_matches,
+    }
+}
+
+fn write_csv(out: &mut impl Write, rows: &[Row]) -> io::Result<()> {
+    let players = rows.iter().map(|row| row.win_rate.len()).max().unwrap_or(0);
+    let kinds: Vec<String> = UnitKind::ALL.iter().map(|kind| format!("{:?}", kind).to_lowercase()).collect();
+
+    let mut header = vec!["stats".to_string(), "runs".to_string(), "draws".to_string()];
+    header.extend((0..players).map(|player| format!("win_rate_{}", player)));
+    header.push("average_ticks".to_string());
+    header.extend(kinds.iter().map(|kind| format!("average_{}", kind)));
+    header.push("average_lost".to_string());
+    header.push("average_gathered".to_string());
+    writeln!(out, "{}", header.join(","))?;
+
+    for row in rows {
+        let mut fields = vec![csv_field(&row.stats), row.runs.to_string(), row.draws.to_string()];
+        fields.extend((0..players).map(|player| format!("{:.4}", row.win_rate.get(player).cloned().unwrap_or(0.))));
+        fields.push(format!("{:.1}", row.average_ticks));
+        fields.extend(kinds.iter().map(|kind| format!("{:.2}", row.average_produced[kind])));
+        fields.push(format!("{:.2}", row.average_lost));
+        fields.push(format!("{:.1}", row.average_gathered));
+        writeln!(out, "{}", fields.join(","))?;
+    }
+    Ok(())
+}
+
+/// Quote a field if it would otherwise break the row
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        stats: Vec::new(),
+        runs: 100,
+        map: None,
+        difficulty: Difficulty::Normal,
+        ticks: DEFAULT_TICKS,
+        seed: 0,
+        threads: std::thread::available_parallelism().map_or(1, |threads| threads.get()),
+        format: Format::Csv,
+        output: None,
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| exit(&format!("{} needs a value", flag)));
+        match flag.as_str() {
+            "--stats" => options.stats.push(value()),
+            "--runs" => options.runs = parse(&value()),
+            "--map" => options.map = Some(value()),
+            "--difficulty" => options.difficulty = value().parse().unwrap_or_else(|err: String| exit(&err)),
+            "--ticks" => options.ticks = parse(&value()),
+            "--seed" => options.seed = parse(&value()),
+            "--threads" => options.threads = parse::<usize>(&value()).max(1),
+            "--format" => options.format = match value().as_str() {
+                "csv" => Format::Csv,
+                "json" => Format::Json,
+                other => exit(&format!("Unknown format {}, expected csv or json", other)),
+            },
+            "--output" => options.output = Some(value()),
+            _ => exit(&format!("Unknown option {}", flag)),
+        }
+    }
+    options
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| exit(&format!("Invalid value {}", value)))
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
@@ -0,0 +1,163 @@
+//! Dedicated server: hosts a match with no window or GPU
+//!
+//! server [--map <path>] [--players <clients>] [--seed <seed>] [--port <port>]
+//!        [--ticks <limit>] [--fast] [--replay <path>] [--bots <difficulty>]
+//!        [--elimination <rule>] [--score-limit <score>] [--time-limit <ticks>]
+//!        [--stats <stat file>]
+//!
+//! Waits for `--players` clients, then runs until the match is decided, the
+//! tick limit is reached or every client has left, and writes the match out as
+//! a replay. The match rules are sent to clients as they join. `--fast` runs
+//! ticks as quickly as possible instead of in real time. `--bots` puts the
+//! computer in charge of every player without a client, so `--players 0
+//! --bots hard --fast --ticks 100000` is a bot-vs-bot soak test.
+
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+use rts::ai::{self, Ai, Difficulty};
+use rts::balance::StatTable;
+use rts::lifecycle::{self, MatchRules, Summary};
+use rts::mapfile;
+use rts::mapgen::{self, MapGenParams};
+use rts::server::{self, Server};
+use rts::sim::PlayerId;
+
+const DEFAULT_REPLAY_PATH: &str = "server.replay";
+
+struct Options {
+    map: Option<String>,
+    players: Option<usize>,
+    seed: u64,
+    port: u16,
+    ticks: Option<u64>,
+    fast: bool,
+    replay: String,
+    bots: Option<Difficulty>,
+    rules: MatchRules,
+}
+
+fn main() {
+    let options = parse_options();
+
+    let map = match &options.map {
+        Some(path) => mapfile::load(path).unwrap_or_else(|err| exit(&format!("{}: {}", path, err))),
+        // Same as the game's default map, so clients can join without a map file
+        None => mapgen::generate(&MapGenParams { seed: 0, size: 128, players: 2 }),
+    };
+    let players = options.players.unwrap_or(map.starts.len());
+    if players > map.starts.len() {
+        exit(&format!("The map only has room for {} players", map.starts.len()));
+    }
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], options.port));
+    let mut server = Server::bind(addr, map, options.seed, options.rules, players)
+        .unwrap_or_else(|err| exit(&format!("Failed to bind {}: {}", addr, err)));
+    println!("Listening on {}, waiting for {} players", addr, players);
+
+    let mut bots: Vec<Ai> = match options.bots {
+        Some(difficulty) => (players..server.world().players.len())
+            .map(|player| Ai::new(server.world(), player as PlayerId, difficulty, options.seed + player as u64))
+            .collect(),
+        None => Vec::new(),
+    };
+
+    let dt = Duration::from_millis(16);
+    let started = Instant::now();
+    let mut next_tick = started;
+    let mut was_started = false;
+    loop {
+        server.update(started.elapsed());
+
+        if server.is_abandoned() {
+            println!("Every player left");
+            break;
+        }
+        if options.ticks.is_some_and(|limit| server.world().tick >= limit) {
+            break;
+        }
+        if lifecycle::is_over(server.world()) {
+            print!("{}", Summary::new(server.world()));
+            break;
+        }
+
+        if server.is_started() {
+            if !was_started {
+                println!("Match started");
+                was_started = true;
+                next_tick = Instant::now();
+            }
+            if options.fast {
+                step(&mut server, &mut bots);
+                continue;
+            }
+            while Instant::now() >= next_tick {
+                step(&mut server, &mut bots);
+                next_tick += dt;
+            }
+        }
+        std::thread::sleep(Duration::from_millis(1));
+    }
+
+    println!("Match ended after {} ticks", server.world().tick);
+    match server.replay().save(&options.replay) {
+        Ok(()) => println!("Replay written to {}", options.replay),
+        Err(err) => exit(&format!("Failed to write replay: {}", err)),
+    }
+}
+
+fn step(server: &mut Server, bots: &mut [Ai]) {
+    for bot in bots {
+        for kind in bot.update(server.world(), ai::DEFAULT_BUDGET) {
+            server.issue(bot.player(), kind);
+        }
+    }
+    server.step();
+}
+
+fn parse_options() -> Options {
+    let mut options = Options {
+        map: None,
+        players: None,
+        seed: 0,
+        port: server::DEFAULT_PORT,
+        ticks: None,
+        fast: false,
+        replay: DEFAULT_REPLAY_PATH.to_string(),
+        bots: None,
+        rules: MatchRules::default(),
+    };
+
+    let mut args = std::env::args().skip(1);
+    while let Some(flag) = args.next() {
+        let mut value = || args.next().unwrap_or_else(|| exit(&format!("{} needs a value", flag)));
+        match flag.as_str() {
+            "--map" => options.map = Some(value()),
+            "--players" => options.players = Some(parse(&value())),
+            "--seed" => options.seed = parse(&value()),
+            "--port" => options.port = parse(&value()),
+            "--ticks" => options.ticks = Some(parse(&value())),
+            "--replay" => options.replay = value(),
+            "--fast" => options.fast = true,
+            "--bots" => options.bots = Some(value().parse().unwrap_or_else(|err: String| exit(&err))),
+            "--elimination" => options.rules.elimination = value().parse().unwrap_or_else(|err: String| exit(&err)),
+            "--score-limit" => options.rules.score_limit = Some(parse(&value())),
+            "--time-limit" => options.rules.time_limit = Some(parse(&value())),
+            "--stats" => {
+                let path = value();
+                options.rules.units = StatTable::load(&path).unwrap_or_else(|err| exit(&format!("{}: {}", path, err)));
+            }
+            _ => exit(&format!("Unknown option {}", flag)),
+        }
+    }
+    options
+}
+
+fn parse<T: std::str::FromStr>(value: &str) -> T {
+    value.parse().unwrap_or_else(|_| exit(&format!("Invalid value {}", value)))
+}
+
+fn exit(message: &str) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
@@ -0,0 +1,67 @@
+//! The camera the match is watched through: orthographic, looking down on
+//! the map from one corner, panned with the keyboard and zoomed with the
+//! mouse wheel
+
+use cgmath::{InnerSpace, Matrix4, Point3, Vector2, Vector3};
+
+#[rustfmt::skip]
+const OPENGL_TO_WGPU_MATRIX: Matrix4<f32> = Matrix4::new(
+    1.0, 0.0, 0.0, 0.0,
+    0.0, 1.0, 0.0, 0.0,
+    0.0, 0.0, 0.5, 0.0,
+    0.0, 0.0, 0.5, 1.0,
+);
+
+/// Where the eye is from the point it looks at, as in the demo
+const EYE_OFFSET: Vector3<f32> = Vector3::new(std::f32::consts::SQRT_2, 1., std::f32::consts::SQRT_2);
+/// How far in front of and behind the eye things are still drawn
+const DEPTH: f32 = 100.;
+const DEFAULT_ZOOM: f32 = 0.0625;
+const MIN_ZOOM: f32 = 0.01;
+const MAX_ZOOM: f32 = 0.5;
+/// Zoom is multiplied by this for each notch of the mouse wheel
+const ZOOM_STEP: f32 = 1.1;
+/// Share of the screen's height each pan moves the view
+const PAN_STEP: f32 = 0.1;
+
+pub struct Camera {
+    /// The point on the ground in the middle of the screen
+    pub target: Vector2<f32>,
+    /// Screen width over height
+    pub aspect: f32,
+    /// The screen shows `2 / zoom` world units from bottom to top
+    pub zoom: f32,
+}
+
+impl Camera {
+    pub fn new(target: Vector2<f32>, aspect: f32) -> Self {
+        Self {
+            target,
+            aspect,
+            zoom: DEFAULT_ZOOM,
+        }
+    }
+
+    pub fn view_projection(&self) -> Matrix4<f32> {
+        let target = Point3::new(self.target.x, 0., self.target.y);
+        let view = Matrix4::look_at_rh(target + EYE_OFFSET, target, Vector3::unit_y());
+        let (half_width, half_height) = (self.aspect / self.zoom, 1. / self.zoom);
+        let proj = cgmath::ortho(-half_width, half_width, -half_height, half_height, -DEPTH, DEPTH);
+        OPENGL_TO_WGPU_MATRIX * proj * view
+    }
+
+    /// Zoom in by `notches` of the mouse wheel, or out if negative
+    pub fn zoom_by(&mut self, notches: f32) {
+        self.zoom = (self.zoom * ZOOM_STEP.powf(notches)).clamp(MIN_ZOOM, MAX_ZOOM);
+    }
+
+    /// Move the view along the ground, by a step in each of the screen's
+    /// right and up directions
+    pub fn pan(&mut self, right: f32, up: f32) {
+        // The eye looks along -EYE_OFFSET, which is up the screen once flattened
+        let forward = -Vector2::new(EYE_OFFSET.x, EYE_OFFSET.z).normalize();
+        let rightward = Vector2::new(-forward.y, forward.x);
+        let step = PAN_STEP * 2. / self.zoom;
+        self.target += (rightward * right + forward * up) * step;
+    }
+}
@@ -0,0 +1,200 @@
+//! Playing a match hosted by a dedicated `server`
+//!
+//! The client sends its commands to the server and runs each tick once the
+//! server says what happened in it.
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::{Duration, Instant};
+
+use super::command::{Command, CommandKind};
+use super::lifecycle::MatchRules;
+use super::server::{ClientMessage, ServerMessage, MAX_BATCH, RESEND_INTERVAL};
+use super::sim::PlayerId;
+use super::transport::MAX_DATAGRAM;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug)]
+pub enum ConnectError {
+    Io(io::Error),
+    /// The server turned us away
+    Rejected(String),
+    TimedOut,
+}
+
+impl fmt::Display for ConnectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ConnectError::Io(err) => write!(f, "{}", err),
+            ConnectError::Rejected(reason) => write!(f, "rejected by server: {}", reason),
+            ConnectError::TimedOut => write!(f, "server didn't answer"),
+        }
+    }
+}
+
+impl std::error::Error for ConnectError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConnectError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ConnectError {
+    fn from(err: io::Error) -> Self {
+        ConnectError::Io(err)
+    }
+}
+
+pub struct Client {
+    socket: UdpSocket,
+    player: PlayerId,
+    seed: u64,
+    rules: MatchRules,
+    /// Commands the server hasn't acknowledged, the first numbered `first_unacked`
+    unacked: Vec<CommandKind>,
+    first_unacked: u64,
+    /// Ticks received but not yet run
+    ticks: BTreeMap<u64, Vec<Command>>,
+    next_tick: u64,
+    last_resend: Duration,
+}
+
+impl Client {
+    /// Join the match hosted at `server`, waiting until it gives us a player.
+    /// `map_hash` is `server::map_hash` of the map we'll be playing on.
+    pub fn connect(server: SocketAddr, map_hash: u64) -> Result<Self, ConnectError> {
+        let local: SocketAddr = if server.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().unwrap();
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(server)?;
+        socket.set_read_timeout(Some(RESEND_INTERVAL))?;
+
+        let started = Instant::now();
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        let (player, seed, rules) = loop {
+            if started.elapsed() > CONNECT_TIMEOUT {
+                return Err(ConnectError::TimedOut);
+            }
+            send(&socket, &ClientMessage::Join { map_hash });
+            let len = match socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(err) if matches!(err.kind(), io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut) => continue,
+                // Nothing listening yet
+                Err(err) if err.kind() == io::ErrorKind::ConnectionRefused => continue,
+                Err(err) => return Err(err.into()),
+            };
+            match bincode::deserialize(&buffer[..len]) {
+                Ok(ServerMessage::Welcome { player, seed, rules }) => break (player, seed, rules),
+                Ok(ServerMessage::Rejected { reason }) => return Err(ConnectError::Rejected(reason)),
+                _ => continue,
+            }
+        };
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            socket,
+            player,
+            seed,
+            rules,
+            unacked: Vec::new(),
+            first_unacked: 0,
+            ticks: BTreeMap::new(),
+            next_tick: 0,
+            last_resend: Duration::from_millis(0),
+        })
+    }
+
+    pub fn player(&self) -> PlayerId {
+        self.player
+    }
+
+    /// Seed the server's world was created with
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Rules the server's match is played by
+    pub fn rules(&self) -> MatchRules {
+        self.rules
+    }
+
+    pub fn issue(&mut self, command: CommandKind) {
+        self.unacked.push(command);
+    }
+
+    /// Whether the server has started the match
+    pub fn is_started(&self) -> bool {
+        self.next_tick > 0 || !self.ticks.is_empty()
+    }
+
+    /// The commands for the next tick, once the server has sent them
+    pub fn next_tick(&mut self) -> Option<Vec<Command>> {
+        let commands = self.ticks.remove(&self.next_tick)?;
+        self.next_tick += 1;
+        Some(commands)
+    }
+
+    /// Send and receive. Call once per frame.
+    pub fn update(&mut self, now: Duration) {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let len = match self.socket.recv(&mut buffer) {
+                Ok(len) => len,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => continue,
+            };
+            match bincode::deserialize(&buffer[..len]) {
+                Ok(ServerMessage::Ticks { first_tick, ticks }) => {
+                    for (tick, commands) in (first_tick..).zip(ticks) {
+                        if tick >= self.next_tick {
+                            self.ticks.entry(tick).or_insert(commands);
+                        }
+                    }
+                    if let Some(through) = self.contiguous_tick() {
+                        send(&self.socket, &ClientMessage::Ack { through });
+                    }
+                }
+                Ok(ServerMessage::CommandAck { next }) => {
+                    let acked = next.saturating_sub(self.first_unacked).min(self.unacked.len() as u64);
+                    self.unacked.drain(..acked as usize);
+                    self.first_unacked += acked;
+                }
+                _ => (),
+            }
+        }
+
+        if now >= self.last_resend + RESEND_INTERVAL {
+            self.last_resend = now;
+            let commands: Vec<_> = self.unacked.iter().take(MAX_BATCH).cloned().collect();
+            if commands.is_empty() {
+                // So the server knows we're still here while we wait
+                send(&self.socket, &ClientMessage::Ping);
+            } else {
+                send(&self.socket, &ClientMessage::Commands { first: self.first_unacked, commands });
+            }
+        }
+    }
+
+    pub fn leave(&mut self) {
+        send(&self.socket, &ClientMessage::Leave);
+    }
+
+    /// The last tick we could run through without waiting
+    fn contiguous_tick(&self) -> Option<u64> {
+        let mut through = self.next_tick.checked_sub(1);
+        while self.ticks.contains_key(&through.map_or(0, |through| through + 1)) {
+            through = Some(through.map_or(0, |through| through + 1));
+        }
+        through
+    }
+}
+
+fn send(socket: &UdpSocket, message: &ClientMessage) {
+    let bytes = bincode::serialize(message).expect("Message serialization can't fail");
+    // Losing a packet is expected, so failures are treated the same way
+    let _ = socket.send(&bytes);
+}
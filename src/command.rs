@@ -0,0 +1,25 @@
+use serde::{Deserialize, Serialize};
+
+use super::math::Vec2;
+use super::sim::{PlayerId, Tech, UnitId, UnitKind};
+
+/// A player action, applied at the start of `tick`. Everything that changes a
+/// match from the outside goes through one of these, so a match can be
+/// replayed or sent over the network as its list of commands.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Command {
+    pub tick: u64,
+    pub player: PlayerId,
+    pub kind: CommandKind,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub enum CommandKind {
+    Move { units: Vec<UnitId>, target: Vec2 },
+    Attack { units: Vec<UnitId>, target: UnitId },
+    Gather { units: Vec<UnitId>, node: usize },
+    Build { worker: UnitId, kind: UnitKind, position: Vec2 },
+    Train { building: UnitId, kind: UnitKind },
+    Research { tech: Tech },
+    Stop { units: Vec<UnitId> },
+}
@@ -0,0 +1,99 @@
+//! Versioned file container shared by maps, saves and replays
+//!
+//! | bytes | contents                          |
+//! |-------|-----------------------------------|
+//! | 4     | magic identifying the file type   |
+//! | 4     | format version, little endian     |
+//! | 8     | payload length, little endian     |
+//! | 8     | FNV-1a hash of the payload        |
+//! | ...   | payload for that version          |
+
+use std::fmt;
+use std::io::{self, Read, Write};
+
+use super::hash::fnv1a;
+
+#[derive(Debug)]
+pub enum ContainerError {
+    Io(io::Error),
+    /// Not the expected kind of file at all
+    BadMagic,
+    /// Written by a newer version of the game, or an older one whose format
+    /// can no longer be read
+    UnsupportedVersion(u32),
+    /// The payload doesn't match the hash in the header, or ends early
+    Corrupt(String),
+}
+
+impl fmt::Display for ContainerError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ContainerError::Io(err) => write!(f, "{}", err),
+            ContainerError::BadMagic => write!(f, "wrong file type"),
+            ContainerError::UnsupportedVersion(version) => write!(f, "format version {} is not supported", version),
+            ContainerError::Corrupt(reason) => write!(f, "file is corrupt: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ContainerError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ContainerError::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ContainerError {
+    fn from(err: io::Error) -> Self {
+        ContainerError::Io(err)
+    }
+}
+
+impl From<bincode::Error> for ContainerError {
+    fn from(err: bincode::Error) -> Self {
+        ContainerError::Corrupt(err.to_string())
+    }
+}
+
+pub fn write(writer: &mut impl Write, magic: [u8; 4], version: u32, payload: &[u8]) -> io::Result<()> {
+    writer.write_all(&magic)?;
+    writer.write_all(&version.to_le_bytes())?;
+    writer.write_all(&(payload.len() as u64).to_le_bytes())?;
+    writer.write_all(&fnv1a(payload).to_le_bytes())?;
+    writer.write_all(payload)
+}
+
+/// Read a container, accepting versions 1 through `latest`. Returns the version and payload.
+pub fn read(reader: &mut impl Read, magic: [u8; 4], latest: u32) -> Result<(u32, Vec<u8>), ContainerError> {
+    let mut header = [0u8; 24];
+    reader.read_exact(&mut header).map_err(|err| match err.kind() {
+        io::ErrorKind::UnexpectedEof => ContainerError::BadMagic,
+        _ => ContainerError::Io(err),
+    })?;
+    if header[0..4] != magic {
+        return Err(ContainerError::BadMagic);
+    }
+    let version = u32::from_le_bytes([header[4], header[5], header[6], header[7]]);
+    let mut word = [0u8; 8];
+    word.copy_from_slice(&header[8..16]);
+    let length = u64::from_le_bytes(word);
+    word.copy_from_slice(&header[16..24]);
+    let hash = u64::from_le_bytes(word);
+
+    if version == 0 || version > latest {
+        return Err(ContainerError::UnsupportedVersion(version));
+    }
+
+    let mut payload = Vec::new();
+    reader.take(length).read_to_end(&mut payload)?;
+    if payload.len() as u64 != length {
+        return Err(ContainerError::Corrupt(format!("expected {} bytes of data, found {}", length, payload.len())));
+    }
+    if fnv1a(&payload) != hash {
+        return Err(ContainerError::Corrupt("checksum mismatch".to_string()));
+    }
+
+    Ok((version, payload))
+}
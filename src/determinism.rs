@@ -0,0 +1,168 @@
+//! Self-check that the simulation is deterministic: two worlds fed identical
+//! inputs must agree on their state hash after every tick, including a world
+//! that has been saved and loaded along the way. One of them runs its systems
+//! on a single thread and the other on every core, so splitting the work
+//! across threads is checked too.
+//!
+//! Also has the tools for tracking down a desync once one has happened.
+
+use std::fmt::Debug;
+
+use cgmath::InnerSpace;
+
+use super::ecs::{Entity, Storage};
+use super::map::Map;
+use super::savegame;
+use super::sim::{self, Order, PlayerId, UnitKind, World};
+
+/// The first tick at which two runs disagreed
+#[derive(Debug)]
+pub struct Divergence {
+    pub tick: u64,
+    pub expected: u64,
+    pub found: u64,
+}
+
+pub fn check(map: &Map, seed: u64, ticks: u64) -> Result<(), Divergence> {
+    let single_thread = rayon::ThreadPoolBuilder::new()
+        .num_threads(1)
+        .build()
+        .expect("Failed to start a thread pool");
+    let mut a = World::from_map(map.clone(), seed);
+    let mut b = World::from_map(map.clone(), seed);
+    give_inputs(&mut a);
+    give_inputs(&mut b);
+
+    for _ in 0..ticks {
+        if b.tick == ticks / 2 {
+            // Continuing from a load must be indistinguishable from never having saved
+            let mut bytes = Vec::new();
+            savegame::write(&b, &[], &mut bytes).expect("Saving to memory can't fail");
+            b = savegame::read(&mut bytes.as_slice()).expect("Reloading a fresh save can't fail").0;
+        }
+
+        single_thread.install(|| a.step());
+        b.step();
+
+        let (expected, found) = (a.state_hash(), b.state_hash());
+        if expected != found {
+            return Err(Divergence { tick: a.tick, expected, found });
+        }
+    }
+    Ok(())
+}
+
+/// Describe every difference between two worlds that the state hash would
+/// notice, one line each. Meant for comparing the snapshots peers dump on a desync.
+pub fn diff(a: &World, b: &World) -> Vec<String> {
+    let mut differences = Vec::new();
+
+    if a.tick != b.tick {
+        differences.push(format!("tick: {} != {}", a.tick, b.tick));
+    }
+    if a.rng.get_word_pos() != b.rng.get_word_pos() {
+        differences.push(format!("rng position: {} != {}", a.rng.get_word_pos(), b.rng.get_word_pos()));
+    }
+    for (node, (ra, rb)) in a.map.resources.iter().zip(&b.map.resources).enumerate() {
+        if ra.amount != rb.amount {
+            differences.push(format!("resource node {}: {} != {}", node, ra.amount, rb.amount));
+        }
+    }
+    for (id, (pa, pb)) in a.players.iter().zip(&b.players).enumerate() {
+        if sim::player_hash(id as PlayerId, pa) != sim::player_hash(id as PlayerId, pb) {
+            differences.push(format!("player {}: {:?} != {:?}", id, pa, pb));
+        }
+    }
+
+    diff_entities("unit", &a.units, &b.units, sim::unit_hash, &mut differences);
+    diff_entities("projectile", &a.projectiles, &b.projectiles, sim::projectile_hash, &mut differences);
+
+    differences
+}
+
+fn diff_entities<T: Debug>(what: &str, a: &Storage<T>, b: &Storage<T>, hash: fn(Entity, &T) -> u64, differences: &mut Vec<String>) {
+    for (id, va) in a.iter() {
+        match b.get(id) {
+            Some(vb) if hash(id, va) != hash(id, vb) => differences.push(format!("{} {}: {:?} != {:?}", what, id, va, vb)),
+            Some(_) => {}
+            None => differences.push(format!("{} {} only in first: {:?}", what, id, va)),
+        }
+    }
+    for (id, vb) in b.iter() {
+        if !a.contains(id) {
+            differences.push(format!("{} {} only in second: {:?}", what, id, vb));
+        }
+    }
+}
+
+/// Put the workers to work so there is something to simulate
+fn give_inputs(world: &mut World) {
+    let resources = world.map.resources.clone();
+    for unit in world.units.values_mut().iter_mut().filter(|unit| unit.kind == UnitKind::Worker) {
+        let position = unit.position.to_f32();
+        let nearest = resources.iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                (a.position - position).magnitude2().partial_cmp(&(b.position - position).magnitude2()).unwrap()
+            })
+            .map(|(node, _)| node);
+        if let Some(node) = nearest {
+            unit.order = Order::Gather { node, returning: false };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::{self, Ai, Difficulty};
+    use crate::command::Command;
+    use crate::mapgen::{self, MapGenParams};
+
+    #[test]
+    fn identical_inputs_give_identical_hashes() {
+        let map = mapgen::generate(&MapGenParams { seed: 7, size: 64, players: 2 });
+        if let Err(divergence) = check(&map, 7, 3000) {
+            panic!("Diverged: {:?}", divergence);
+        }
+    }
+
+    #[test]
+    fn thread_count_doesnt_change_hashes() {
+        let map = mapgen::generate(&MapGenParams { seed: 11, size: 64, players: 2 });
+        let single_thread = play_hashes(&map, 11, 2000, 1);
+        let many_threads = play_hashes(&map, 11, 2000, 4);
+        for (tick, (expected, found)) in single_thread.iter().zip(&many_threads).enumerate() {
+            assert_eq!(expected, found, "Diverged at tick {}", tick + 1);
+        }
+    }
+
+    /// The state hash after every tick of a match between computer players,
+    /// with the simulation on a pool of `threads` threads
+    fn play_hashes(map: &Map, seed: u64, ticks: u64, threads: usize) -> Vec<u64> {
+        let pool = rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build()
+            .expect("Failed to start a thread pool");
+        let mut world = World::from_map(map.clone(), seed);
+        let mut ais: Vec<Ai> = (0..world.players.len() as PlayerId)
+            .map(|player| Ai::new(&world, player, Difficulty::Hard, seed + player as u64))
+            .collect();
+
+        let mut hashes = Vec::new();
+        for _ in 0..ticks {
+            let mut commands = Vec::new();
+            for ai in &mut ais {
+                for kind in ai.update(&world, ai::UNLIMITED_BUDGET) {
+                    commands.push(Command { tick: world.tick, player: ai.player(), kind });
+                }
+            }
+            for command in &commands {
+                world.apply(command);
+            }
+            pool.install(|| world.step());
+            hashes.push(world.state_hash());
+        }
+        hashes
+    }
+}
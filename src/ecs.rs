@@ -0,0 +1,165 @@
+//! Entity-component storage
+//!
+//! An entity is only an id. Each kind of component lives in its own
+//! `Storage`, packed into a plain `Vec` so systems can run through it quickly,
+//! and systems are plain functions over the storages they need. Entities that
+//! have a component are kept sorted by id, which is also the order they were
+//! created in, so iterating is the same on every machine.
+
+use std::ops::{Index, IndexMut};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+pub type Entity = u32;
+
+/// Hands out entity ids in increasing order, never reusing one
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Entities {
+    next: Entity,
+}
+
+impl Entities {
+    pub fn create(&mut self) -> Entity {
+        let entity = self.next;
+        self.next += 1;
+        entity
+    }
+
+    /// The id `create` will return next
+    pub fn peek(&self) -> Entity {
+        self.next
+    }
+}
+
+/// The components of one type, with the entities they belong to
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Storage<T> {
+    /// Sorted
+    entities: Vec<Entity>,
+    /// Same length as `entities`, the component of each
+    values: Vec<T>,
+}
+
+impl<T> Default for Storage<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Storage<T> {
+    pub fn new() -> Self {
+        Self {
+            entities: Vec::new(),
+            values: Vec::new(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    fn slot(&self, entity: Entity) -> Result<usize, usize> {
+        // New entities have the highest id, so check the end first
+        match self.entities.last() {
+            Some(&last) if last < entity => Err(self.entities.len()),
+            None => Err(0),
+            _ => self.entities.binary_search(&entity),
+        }
+    }
+
+    pub fn contains(&self, entity: Entity) -> bool {
+        self.slot(entity).is_ok()
+    }
+
+    pub fn get(&self, entity: Entity) -> Option<&T> {
+        self.slot(entity).ok().map(|slot| &self.values[slot])
+    }
+
+    pub fn get_mut(&mut self, entity: Entity) -> Option<&mut T> {
+        match self.slot(entity) {
+            Ok(slot) => Some(&mut self.values[slot]),
+            Err(_) => None,
+        }
+    }
+
+    /// Give `entity` a component, returning the one it replaces
+    pub fn insert(&mut self, entity: Entity, value: T) -> Option<T> {
+        match self.slot(entity) {
+            Ok(slot) => Some(std::mem::replace(&mut self.values[slot], value)),
+            Err(slot) => {
+                self.entities.insert(slot, entity);
+                self.values.insert(slot, value);
+                None
+            }
+        }
+    }
+
+    pub fn remove(&mut self, entity: Entity) -> Option<T> {
+        let slot = self.slot(entity).ok()?;
+        self.entities.remove(slot);
+        Some(self.values.remove(slot))
+    }
+
+    /// Keep only the components `keep` returns true for
+    pub fn retain(&mut self, mut keep: impl FnMut(Entity, &mut T) -> bool) {
+        let mut kept = 0;
+        for slot in 0..self.values.len() {
+            if keep(self.entities[slot], &mut self.values[slot]) {
+                self.entities.swap(kept, slot);
+                self.values.swap(kept, slot);
+                kept += 1;
+            }
+        }
+        self.entities.truncate(kept);
+        self.values.truncate(kept);
+    }
+
+    /// Entities with this component, in order
+    pub fn entities(&self) -> &[Entity] {
+        &self.entities
+    }
+
+    /// Components in entity order
+    pub fn values(&self) -> &[T] {
+        &self.values
+    }
+
+    pub fn values_mut(&mut self) -> &mut [T] {
+        &mut self.values
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (Entity, &T)> + '_ {
+        self.entities.iter().cloned().zip(self.values.iter())
+    }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (Entity, &mut T)> + '_ {
+        self.entities.iter().cloned().zip(self.values.iter_mut())
+    }
+
+    /// Like `iter`, split across threads. Collecting keeps entity order.
+    pub fn par_iter(&self) -> impl IndexedParallelIterator<Item = (Entity, &T)> + '_
+    where
+        T: Sync,
+    {
+        self.entities.par_iter().cloned().zip(self.values.par_iter())
+    }
+}
+
+impl<T> Index<Entity> for Storage<T> {
+    type Output = T;
+
+    fn index(&self, entity: Entity) -> &T {
+        self.get(entity).expect("Entity has no such component")
+    }
+}
+
+impl<T> IndexMut<Entity> for Storage<T> {
+    fn index_mut(&mut self, entity: Entity) -> &mut T {
+        self.get_mut(entity).expect("Entity has no such component")
+    }
+}
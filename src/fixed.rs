@@ -0,0 +1,118 @@
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+use super::math::Scalar;
+
+const FRAC_BITS: u32 = 16;
+const ONE: i64 = 1 << FRAC_BITS;
+
+/// Signed 48.16 fixed-point number. Every operation is plain integer math, so
+/// results are identical on every platform and compiler.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Fixed(i64);
+
+impl Fixed {
+    pub const fn from_raw(raw: i64) -> Self {
+        Fixed(raw)
+    }
+
+    pub const fn raw(self) -> i64 {
+        self.0
+    }
+
+    pub const fn from_int(value: i32) -> Self {
+        Fixed((value as i64) << FRAC_BITS)
+    }
+}
+
+impl Scalar for Fixed {
+    const ZERO: Self = Fixed(0);
+
+    fn from_f32(value: f32) -> Self {
+        Fixed((value as f64 * ONE as f64).round() as i64)
+    }
+
+    fn to_f32(self) -> f32 {
+        (self.0 as f64 / ONE as f64) as f32
+    }
+
+    fn sqrt(self) -> Self {
+        if self.0 <= 0 {
+            return Fixed(0);
+        }
+        // sqrt(raw / ONE) * ONE == sqrt(raw * ONE)
+        Fixed(isqrt((self.0 as u128) << FRAC_BITS) as i64)
+    }
+
+    fn hash_bits(self) -> u64 {
+        self.0 as u64
+    }
+}
+
+/// Largest integer whose square is at most `n`
+fn isqrt(n: u128) -> u128 {
+    let mut result = 0u128;
+    let mut bit = 1u128 << 126;
+    while bit > n {
+        bit >>= 2;
+    }
+    let mut n = n;
+    while bit != 0 {
+        if n >= result + bit {
+            n -= result + bit;
+            result = (result >> 1) + bit;
+        } else {
+            result >>= 1;
+        }
+        bit >>= 2;
+    }
+    result
+}
+
+impl Add for Fixed {
+    type Output = Fixed;
+    fn add(self, other: Fixed) -> Fixed {
+        Fixed(self.0 + other.0)
+    }
+}
+
+impl AddAssign for Fixed {
+    fn add_assign(&mut self, other: Fixed) {
+        self.0 += other.0;
+    }
+}
+
+impl Sub for Fixed {
+    type Output = Fixed;
+    fn sub(self, other: Fixed) -> Fixed {
+        Fixed(self.0 - other.0)
+    }
+}
+
+impl SubAssign for Fixed {
+    fn sub_assign(&mut self, other: Fixed) {
+        self.0 -= other.0;
+    }
+}
+
+impl Mul for Fixed {
+    type Output = Fixed;
+    fn mul(self, other: Fixed) -> Fixed {
+        Fixed(((self.0 as i128 * other.0 as i128) >> FRAC_BITS) as i64)
+    }
+}
+
+impl Div for Fixed {
+    type Output = Fixed;
+    fn div(self, other: Fixed) -> Fixed {
+        Fixed((((self.0 as i128) << FRAC_BITS) / other.0 as i128) as i64)
+    }
+}
+
+impl Neg for Fixed {
+    type Output = Fixed;
+    fn neg(self) -> Fixed {
+        Fixed(-self.0)
+    }
+}
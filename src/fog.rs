@@ -0,0 +1,141 @@
+use cgmath::Vector2;
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use super::sim::PlayerId;
+
+/// What a player knows about a single cell of the map
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Visibility {
+    /// Never seen
+    Unexplored,
+    /// Seen before, but nothing currently has sight of it
+    Explored,
+    /// Inside the sight radius of one of the player's units
+    Visible,
+}
+
+impl Visibility {
+    /// Brightness used for the fog overlay texture
+    fn brightness(self) -> u8 {
+        match self {
+            Visibility::Unexplored => 0x20,
+            Visibility::Explored => 0x80,
+            Visibility::Visible => 0xff,
+        }
+    }
+}
+
+/// Per-player visibility grids covering the map
+#[derive(Clone, Serialize, Deserialize)]
+pub struct FogOfWar {
+    width: u32,
+    height: u32,
+    cell_size: f32,
+    // One grid per player, row-major
+    layers: Vec<Vec<Visibility>>,
+}
+
+impl FogOfWar {
+    pub fn new(width: u32, height: u32, cell_size: f32, num_players: usize) -> Self {
+        let cells = (width * height) as usize;
+        Self {
+            width,
+            height,
+            cell_size,
+            layers: vec![vec![Visibility::Unexplored; cells]; num_players],
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    /// Demote everything currently visible to explored, before revealing
+    /// this tick's sight circles
+    pub fn begin_update(&mut self) {
+        for layer in &mut self.layers {
+            demote(layer);
+        }
+    }
+
+    /// Mark every cell within `radius` of `center` visible to `player`
+    pub fn reveal(&mut self, player: PlayerId, center: Vector2<f32>, radius: f32) {
+        let (width, height, cell_size) = (self.width, self.height, self.cell_size);
+        reveal_circle(&mut self.layers[player as usize], width, height, cell_size, center, radius);
+    }
+
+    /// `begin_update` followed by a `reveal` for each of `sights`, given as
+    /// (player, center, radius), with the players' layers done in parallel
+    pub fn update(&mut self, sights: &[(PlayerId, Vector2<f32>, f32)]) {
+        let (width, height, cell_size) = (self.width, self.height, self.cell_size);
+        self.layers.par_iter_mut().enumerate().for_each(|(player, layer)| {
+            demote(layer);
+            for &(_, center, radius) in sights.iter().filter(|sight| sight.0 as usize == player) {
+                reveal_circle(layer, width, height, cell_size, center, radius);
+            }
+        });
+    }
+
+    /// Visibility of the cell containing `position`. Anything off the map is unexplored.
+    pub fn visibility(&self, player: PlayerId, position: Vector2<f32>) -> Visibility {
+        match self.cell_index(position) {
+            Some(index) => self.layers[player as usize][index],
+            None => Visibility::Unexplored,
+        }
+    }
+
+    pub fn is_visible(&self, player: PlayerId, position: Vector2<f32>) -> bool {
+        self.visibility(player, position) == Visibility::Visible
+    }
+
+    /// One byte of brightness per cell, laid out for an R8 texture
+    pub fn texture_data(&self, player: PlayerId) -> Vec<u8> {
+        self.layers[player as usize].iter().map(|cell| cell.brightness()).collect()
+    }
+
+    fn cell_index(&self, position: Vector2<f32>) -> Option<usize> {
+        let x = (position.x / self.cell_size).floor();
+        let y = (position.y / self.cell_size).floor();
+        if x < 0. || y < 0. || x >= self.width as f32 || y >= self.height as f32 {
+            return None;
+        }
+        Some(y as usize * self.width as usize + x as usize)
+    }
+}
+
+fn demote(layer: &mut [Visibility]) {
+    for cell in layer.iter_mut() {
+        if *cell == Visibility::Visible {
+            *cell = Visibility::Explored;
+        }
+    }
+}
+
+fn reveal_circle(layer: &mut [Visibility], width: u32, height: u32, cell_size: f32, center: Vector2<f32>, radius: f32) {
+    // TODO: Occlude sight by terrain height
+    let (width, height) = (width as i32, height as i32);
+    let min_x = ((center.x - radius) / cell_size).floor().max(0.) as i32;
+    let max_x = ((center.x + radius) / cell_size).ceil().min(width as f32 - 1.) as i32;
+    let min_y = ((center.y - radius) / cell_size).floor().max(0.) as i32;
+    let max_y = ((center.y + radius) / cell_size).ceil().min(height as f32 - 1.) as i32;
+
+    let radius2 = radius * radius;
+    for y in min_y..=max_y {
+        for x in min_x..=max_x {
+            let dx = (x as f32 + 0.5) * cell_size - center.x;
+            let dy = (y as f32 + 0.5) * cell_size - center.y;
+            if dx * dx + dy * dy <= radius2 {
+                layer[(y * width + x) as usize] = Visibility::Visible;
+            }
+        }
+    }
+}
@@ -0,0 +1,90 @@
+//! Testing what falls inside the camera's view volume, so what can't be seen
+//! is never sent to be drawn
+
+use cgmath::{InnerSpace, Matrix, Matrix4, Vector3, Vector4};
+
+use super::model::ModelInstance;
+
+pub struct Frustum {
+    /// Left, right, bottom, top, near, far. Each is (normal, distance) with
+    /// the normal pointing inwards and of unit length.
+    planes: [Vector4<f32>; 6],
+}
+
+impl Frustum {
+    /// The volume `view_proj` maps into clip space, with depth from 0 to 1
+    /// as wgpu has it. Works for the orthographic camera and perspective alike.
+    pub fn from_view_proj(view_proj: Matrix4<f32>) -> Self {
+        let rows = view_proj.transpose();
+        let (x, y, z, w) = (rows.x, rows.y, rows.z, rows.w);
+        let mut planes = [w + x, w - x, w + y, w - y, z, w - z];
+        for plane in planes.iter_mut() {
+            *plane /= plane.truncate().magnitude();
+        }
+        Self { planes }
+    }
+
+    /// Whether any of the sphere might be in view
+    pub fn intersects_sphere(&self, center: Vector3<f32>, radius: f32) -> bool {
+        self.planes.iter().all(|plane| plane.truncate().dot(center) + plane.w >= -radius)
+    }
+
+    /// Whether any of an instance of a mesh that fits within `mesh_radius`
+    /// of its origin might be in view
+    pub fn intersects_instance(&self, instance: &ModelInstance, mesh_radius: f32) -> bool {
+        let (center, radius) = instance.bounding_sphere(mesh_radius);
+        self.intersects_sphere(center, radius)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cgmath::{Deg, Matrix3, Point3};
+
+    /// An orthographic view of the box from -10 to 10 along x and y, looking
+    /// down -z from z = 10, with depth remapped to 0..1 as wgpu has it
+    fn frustum() -> Frustum {
+        let to_wgpu = Matrix4::from_translation(Vector3::new(0., 0., 0.5)) * Matrix4::from_nonuniform_scale(1., 1., 0.5);
+        let view = Matrix4::look_at_rh(Point3::new(0., 0., 10.), Point3::new(0., 0., 0.), Vector3::unit_y());
+        Frustum::from_view_proj(to_wgpu * cgmath::ortho(-10., 10., -10., 10., 0., 20.) * view)
+    }
+
+    #[test]
+    fn spheres_in_or_overlapping_the_view_intersect() {
+        let frustum = frustum();
+        assert!(frustum.intersects_sphere(Vector3::new(0., 0., 0.), 1.));
+        assert!(frustum.intersects_sphere(Vector3::new(9.5, -9.5, 9.5), 0.1));
+        // Centered outside each side, but reaching in
+        assert!(frustum.intersects_sphere(Vector3::new(10.5, 0., 0.), 1.));
+        assert!(frustum.intersects_sphere(Vector3::new(0., -10.5, 0.), 1.));
+        assert!(frustum.intersects_sphere(Vector3::new(0., 0., -10.5), 1.));
+    }
+
+    #[test]
+    fn spheres_past_any_side_dont_intersect() {
+        let frustum = frustum();
+        assert!(!frustum.intersects_sphere(Vector3::new(12., 0., 0.), 1.));
+        assert!(!frustum.intersects_sphere(Vector3::new(-12., 0., 0.), 1.));
+        assert!(!frustum.intersects_sphere(Vector3::new(0., 12., 0.), 1.));
+        assert!(!frustum.intersects_sphere(Vector3::new(0., -12., 0.), 1.));
+        // Behind the eye, and past the far plane
+        assert!(!frustum.intersects_sphere(Vector3::new(0., 0., 12.), 1.));
+        assert!(!frustum.intersects_sphere(Vector3::new(0., 0., -12.), 1.));
+    }
+
+    #[test]
+    fn instances_are_tested_by_their_scaled_bounds() {
+        let frustum = frustum();
+        let instance = |position: Vector3<f32>, scale: f32| ModelInstance {
+            model: (Matrix4::from_translation(position) * Matrix4::from_angle_y(Deg(30.)) * Matrix4::from_scale(scale)).into(),
+            normal: Matrix3::from_angle_y(Deg(30.)).into(),
+            color: [1.; 3],
+            layer: 0,
+        };
+        assert!(frustum.intersects_instance(&instance(Vector3::new(5., 5., 0.), 1.), 1.));
+        assert!(!frustum.intersects_instance(&instance(Vector3::new(13., 0., 0.), 1.), 1.));
+        // Only in reach once scaled up
+        assert!(frustum.intersects_instance(&instance(Vector3::new(13., 0., 0.), 4.), 1.));
+    }
+}
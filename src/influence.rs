@@ -0,0 +1,256 @@
+//! Influence maps
+//!
+//! A coarse grid over the map with several layers, each holding how much of
+//! something reaches every cell. Units and resources spread their influence
+//! over nearby cells, falling off linearly with distance, which turns
+//! questions like "where is it safe to expand" into picking the best cell.
+//!
+//! Maps are built from one player's point of view and only know about enemies
+//! that player can currently see.
+
+use cgmath::{InnerSpace, Matrix3, Matrix4, SquareMatrix, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+
+use super::balance::UnitStats;
+use super::fog::Visibility;
+use super::model::ModelInstance;
+use super::sim::{PlayerId, Unit, World};
+use super::terrain::Heightmap;
+
+/// World units per influence cell
+pub const CELL_SIZE: f32 = 4.;
+/// Ticks between rebuilds. Influence changes slowly, so there's no need to do it every tick.
+pub const UPDATE_INTERVAL: u64 = 30;
+/// How far resources make a place worth having
+const RESOURCE_RADIUS: f32 = 12.;
+/// Distance from home at which an expansion site is worth half as much
+const EXPANSION_FALLOFF: f32 = 32.;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Layer {
+    /// Damage enemies could deal here soon
+    Threat,
+    /// Damage our own units could deal here soon
+    Strength,
+    /// Resources left nearby
+    Resources,
+    /// 0 for unexplored, up to 1 for currently visible
+    Visibility,
+}
+
+impl Layer {
+    pub const ALL: [Layer; 4] = [Layer::Threat, Layer::Strength, Layer::Resources, Layer::Visibility];
+
+    /// Color of a fully saturated cell in the debug overlay
+    fn color(self) -> [f32; 3] {
+        match self {
+            Layer::Threat => [1., 0.1, 0.1],
+            Layer::Strength => [0.1, 0.3, 1.],
+            Layer::Resources => [1., 0.85, 0.1],
+            Layer::Visibility => [1., 1., 1.],
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct InfluenceMap {
+    player: PlayerId,
+    width: u32,
+    height: u32,
+    /// One grid per layer, row-major
+    layers: Vec<Vec<f32>>,
+    /// Tick of the last rebuild
+    updated: Option<u64>,
+}
+
+impl InfluenceMap {
+    pub fn new(world: &World, player: PlayerId) -> Self {
+        let extent = world.map.terrain.extent();
+        let width = (extent.x / CELL_SIZE).ceil().max(1.) as u32;
+        let height = (extent.y / CELL_SIZE).ceil().max(1.) as u32;
+        Self {
+            player,
+            width,
+            height,
+            layers: vec![vec![0.; (width * height) as usize]; Layer::ALL.len()],
+            updated: None,
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Whether it's been `UPDATE_INTERVAL` ticks since the last rebuild, or
+    /// the world has gone back to before it, as when seeking in a replay
+    pub fn is_stale(&self, tick: u64) -> bool {
+        self.updated.is_none_or(|updated| tick >= updated + UPDATE_INTERVAL || tick < updated)
+    }
+
+    /// Rebuild every layer from the world as our player sees it
+    pub fn update(&mut self, world: &World) {
+        for layer in &mut self.layers {
+            layer.iter_mut().for_each(|value| *value = 0.);
+        }
+
+        for (_, unit) in world.visible_units(self.player) {
+            let (power, reach) = match combat_influence(world.stats(unit.kind), unit) {
+                Some(influence) => influence,
+                None => continue,
+            };
+            let layer = if unit.owner == self.player { Layer::Strength } else { Layer::Threat };
+            self.spread(layer, unit.position.to_f32(), power, reach);
+        }
+        for node in world.map.resources.iter().filter(|node| node.amount > 0) {
+            self.spread(Layer::Resources, node.position, node.amount as f32, RESOURCE_RADIUS);
+        }
+
+        let player = self.player;
+        let fog = &world.fog;
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let value = match fog.visibility(player, self.cell_center(x, y)) {
+                    Visibility::Unexplored => 0.,
+                    Visibility::Explored => 0.5,
+                    Visibility::Visible => 1.,
+                };
+                self.layers[Layer::Visibility as usize][(y * self.width + x) as usize] = value;
+            }
+        }
+
+        self.updated = Some(world.tick);
+    }
+
+    /// Value of `layer` in the cell containing `position`. Off the map is 0.
+    pub fn get(&self, layer: Layer, position: Vector2<f32>) -> f32 {
+        match self.cell_index(position) {
+            Some(index) => self.layers[layer as usize][index],
+            None => 0.,
+        }
+    }
+
+    /// The most valuable of `candidates` to expand to, weighing the resources
+    /// around each against the enemy threat there and how far it is from
+    /// home. Each candidate comes with that distance.
+    pub fn safest_expansion_site(&self, candidates: impl IntoIterator<Item = (Vector2<f32>, f32)>) -> Option<Vector2<f32>> {
+        candidates.into_iter()
+            .map(|(site, distance)| {
+                let threat = (self.get(Layer::Threat, site) - self.get(Layer::Strength, site)).max(0.);
+                let remoteness = 1. + distance / EXPANSION_FALLOFF;
+                (site, self.get(Layer::Resources, site) / (1. + threat) / remoteness)
+            })
+            .filter(|&(_, score)| score > 0.)
+            .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(site, _)| site)
+    }
+
+    /// The center of the cell on the edge of the enemy's influence where they
+    /// are weakest compared to us. `None` if no enemy is in sight.
+    pub fn weakest_enemy_front(&self) -> Option<Vector2<f32>> {
+        let threat = &self.layers[Layer::Threat as usize];
+        let strength = &self.layers[Layer::Strength as usize];
+        let (width, height) = (self.width as i32, self.height as i32);
+
+        let mut weakest: Option<(u32, u32, f32)> = None;
+        for y in 0..height {
+            for x in 0..width {
+                let index = (y * width + x) as usize;
+                if threat[index] <= 0. {
+                    continue;
+                }
+                // On the front if any neighbour is out of their reach
+                let on_front = [(1, 0), (-1, 0), (0, 1), (0, -1)].iter().any(|&(dx, dy)| {
+                    let (nx, ny) = (x + dx, y + dy);
+                    nx < 0 || ny < 0 || nx >= width || ny >= height || threat[(ny * width + nx) as usize] <= 0.
+                });
+                if !on_front {
+                    continue;
+                }
+                let advantage = threat[index] - strength[index];
+                if weakest.is_none_or(|(_, _, best)| advantage < best) {
+                    weakest = Some((x as u32, y as u32, advantage));
+                }
+            }
+        }
+        weakest.map(|(x, y, _)| self.cell_center(x, y))
+    }
+
+    /// One flat colored tile per cell, brighter where `layer` is stronger,
+    /// for drawing the map over the terrain while debugging
+    pub fn debug_instances(&self, layer: Layer, terrain: &Heightmap) -> Vec<ModelInstance> {
+        let values = &self.layers[layer as usize];
+        let max = values.iter().cloned().fold(0., f32::max);
+        if max <= 0. {
+            return Vec::new();
+        }
+        let color = layer.color();
+        let half = CELL_SIZE / 2. * 0.9;
+
+        let mut instances = Vec::new();
+        for y in 0..self.height {
+            for x in 0..self.width {
+                let value = values[(y * self.width + x) as usize] / max;
+                if value <= 0. {
+                    continue;
+                }
+                let center = self.cell_center(x, y);
+                let position = Vector3::new(center.x, terrain.height_at(center) + 0.1, center.y);
+                let model = Matrix4::from_translation(position) * Matrix4::from_nonuniform_scale(half, 0.05, half);
+                instances.push(ModelInstance {
+                    model: model.into(),
+                    normal: Matrix3::identity().into(),
+                    color: [color[0] * value, color[1] * value, color[2] * value],
+                    layer: 0,
+                });
+            }
+        }
+        instances
+    }
+
+    fn cell_center(&self, x: u32, y: u32) -> Vector2<f32> {
+        Vector2::new((x as f32 + 0.5) * CELL_SIZE, (y as f32 + 0.5) * CELL_SIZE)
+    }
+
+    fn cell_index(&self, position: Vector2<f32>) -> Option<usize> {
+        let x = (position.x / CELL_SIZE).floor();
+        let y = (position.y / CELL_SIZE).floor();
+        if x < 0. || y < 0. || x >= self.width as f32 || y >= self.height as f32 {
+            return None;
+        }
+        Some(y as usize * self.width as usize + x as usize)
+    }
+
+    /// Add `amount` to `layer` at `center`, falling to nothing at `radius`
+    fn spread(&mut self, layer: Layer, center: Vector2<f32>, amount: f32, radius: f32) {
+        let (width, height) = (self.width as i32, self.height as i32);
+        let min_x = ((center.x - radius) / CELL_SIZE).floor().max(0.) as i32;
+        let max_x = ((center.x + radius) / CELL_SIZE).ceil().min(width as f32 - 1.) as i32;
+        let min_y = ((center.y - radius) / CELL_SIZE).floor().max(0.) as i32;
+        let max_y = ((center.y + radius) / CELL_SIZE).ceil().min(height as f32 - 1.) as i32;
+
+        let values = &mut self.layers[layer as usize];
+        for y in min_y..=max_y {
+            for x in min_x..=max_x {
+                let cell = Vector2::new((x as f32 + 0.5) * CELL_SIZE, (y as f32 + 0.5) * CELL_SIZE);
+                let falloff = 1. - (cell - center).magnitude() / radius;
+                if falloff > 0. {
+                    values[(y * width + x) as usize] += amount * falloff;
+                }
+            }
+        }
+    }
+}
+
+/// Damage per second a unit is good for, scaled by its remaining health, and
+/// how far that reaches within an update. `None` for the unarmed.
+fn combat_influence(stats: &UnitStats, unit: &Unit) -> Option<(f32, f32)> {
+    let weapon = stats.weapon?;
+    let per_second = weapon.damage as f32 * 60. / weapon.cooldown.max(1) as f32;
+    let health = unit.health as f32 / stats.max_health.max(1) as f32;
+    let reach = weapon.range + stats.speed * UPDATE_INTERVAL as f32 * 2.;
+    Some((per_second * health, reach))
+}
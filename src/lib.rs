@@ -0,0 +1,34 @@
+//! Everything needed to run a match without a window: the simulation, maps,
+//! saves, replays and networking. Shared by the game and the dedicated server.
+
+pub mod model;
+pub mod frustum;
+pub mod animation;
+pub mod vat;
+pub mod fog;
+pub mod sim;
+pub mod terrain;
+pub mod map;
+pub mod mapgen;
+pub mod mapfile;
+pub mod hash;
+pub mod container;
+pub mod savegame;
+pub mod math;
+pub mod fixed;
+pub mod determinism;
+pub mod command;
+pub mod replay;
+pub mod transport;
+pub mod lockstep;
+pub mod server;
+pub mod client;
+pub mod ai;
+pub mod influence;
+pub mod script;
+pub mod lifecycle;
+pub mod balance;
+pub mod ecs;
+mod systems;
+mod spatial;
+mod pathfinding;
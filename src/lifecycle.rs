@@ -0,0 +1,197 @@
+//! How a match is won, and what happened in it
+//!
+//! The rules are part of the world and players are judged at the end of every
+//! tick, so a match ends the same way on every peer and in its replay.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+
+use super::balance::StatTable;
+use super::sim::{PlayerId, UnitKind, World};
+
+/// The stages a match goes through, in order
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Waiting for every player to be there
+    Lobby,
+    /// Everyone is in. Getting ready to run the first tick.
+    Loading,
+    Playing,
+    /// The result is in. Nothing is simulated any more.
+    Ended,
+}
+
+/// What knocks a player out
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Elimination {
+    /// Losing every unit
+    Annihilation,
+    /// Losing every headquarters. Whatever else is left is removed.
+    Headquarters,
+}
+
+impl FromStr for Elimination {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "annihilation" => Ok(Elimination::Annihilation),
+            "headquarters" => Ok(Elimination::Headquarters),
+            _ => Err(format!("unknown elimination rule {}, expected annihilation or headquarters", name)),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MatchRules {
+    pub elimination: Elimination,
+    /// The first player to reach this score wins
+    pub score_limit: Option<u32>,
+    /// After this many ticks, the highest score wins
+    pub time_limit: Option<u64>,
+    pub units: StatTable,
+}
+
+impl Default for MatchRules {
+    fn default() -> Self {
+        Self {
+            elimination: Elimination::Annihilation,
+            score_limit: None,
+            time_limit: None,
+            units: StatTable::default(),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Standing {
+    Playing,
+    Won { tick: u64 },
+    Lost { tick: u64 },
+}
+
+/// Running totals for one player
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Stats {
+    pub resources_gathered: u32,
+    pub resources_spent: u32,
+    /// Units trained and buildings built, by `UnitKind as usize`
+    pub produced: [u32; 4],
+    pub kills: u32,
+    /// Including whatever was left standing when the player was eliminated
+    pub units_lost: u32,
+}
+
+impl Stats {
+    /// Resources earned and put to use, and a bonus for every kill
+    pub fn score(&self) -> u32 {
+        self.resources_gathered + self.resources_spent + 20 * self.kills
+    }
+
+    pub fn units_trained(&self) -> u32 {
+        UnitKind::ALL.iter().filter(|kind| !kind.is_building()).map(|&kind| self.produced[kind as usize]).sum()
+    }
+
+    pub fn buildings_built(&self) -> u32 {
+        UnitKind::ALL.iter().filter(|kind| kind.is_building()).map(|&kind| self.produced[kind as usize]).sum()
+    }
+}
+
+/// Whether every player has a result
+pub fn is_over(world: &World) -> bool {
+    world.players.iter().all(|player| player.standing != Standing::Playing)
+}
+
+/// Settle `player`'s result from outside the rules, as a mission script does
+pub fn declare(world: &mut World, player: PlayerId, won: bool) {
+    let tick = world.tick;
+    let standing = &mut world.players[player as usize].standing;
+    if *standing == Standing::Playing {
+        *standing = if won { Standing::Won { tick } } else { Standing::Lost { tick } };
+    }
+}
+
+/// Apply the rules after a tick. Called by `World::step`.
+pub(crate) fn judge(world: &mut World) {
+    // `World::step` only counts the tick once every system has run
+    let tick = world.tick + 1;
+    let rules = world.rules;
+
+    for player in 0..world.players.len() as PlayerId {
+        if world.players[player as usize].standing != Standing::Playing {
+            continue;
+        }
+        let eliminated = match rules.elimination {
+            Elimination::Annihilation => !world.units.values().iter().any(|unit| unit.owner == player),
+            Elimination::Headquarters => !world.units.values().iter().any(|unit| unit.owner == player && unit.kind == UnitKind::Headquarters),
+        };
+        if eliminated {
+            let stats = &mut world.players[player as usize].stats;
+            world.units.retain(|_, unit| {
+                if unit.owner == player {
+                    stats.units_lost += 1;
+                }
+                unit.owner != player
+            });
+            world.players[player as usize].standing = Standing::Lost { tick };
+        }
+    }
+
+    let playing: Vec<usize> = (0..world.players.len()).filter(|&player| world.players[player].standing == Standing::Playing).collect();
+    let scores: Vec<u32> = playing.iter().map(|&player| world.players[player].stats.score()).collect();
+    let best = scores.iter().cloned().max().unwrap_or(0);
+    let someone_won = world.players.iter().any(|player| matches!(player.standing, Standing::Won { .. }));
+    let out_of_time = rules.time_limit.is_some_and(|limit| tick >= limit);
+    let score_reached = rules.score_limit.is_some_and(|limit| best >= limit);
+    let last_standing = playing.len() == 1 && world.players.len() > 1;
+
+    if someone_won || out_of_time || score_reached || last_standing {
+        // Whoever is still in with the best score takes it, ties included,
+        // unless a winner has already been declared
+        for (&player, &score) in playing.iter().zip(&scores) {
+            let won = !someone_won && score == best;
+            world.players[player].standing = if won { Standing::Won { tick } } else { Standing::Lost { tick } };
+        }
+    }
+}
+
+/// End-of-match results, printable as a table
+pub struct Summary {
+    pub ticks: u64,
+    pub players: Vec<(PlayerId, Standing, Stats)>,
+}
+
+impl Summary {
+    pub fn new(world: &World) -> Self {
+        Self {
+            ticks: world.tick,
+            players: world.players.iter()
+                .enumerate()
+                .map(|(id, player)| (id as PlayerId, player.standing, player.stats))
+                .collect(),
+        }
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "Match over after {} ticks", self.ticks)?;
+        writeln!(f, "{:<8} {:<8} {:>7} {:>9} {:>7} {:>7} {:>7} {:>6} {:>6}", "player", "result", "score", "gathered", "spent", "trained", "built", "kills", "lost")?;
+        for (player, standing, stats) in &self.players {
+            let result = match standing {
+                Standing::Playing => "-",
+                Standing::Won { .. } => "won",
+                Standing::Lost { .. } => "lost",
+            };
+            writeln!(
+                f,
+                "{:<8} {:<8} {:>7} {:>9} {:>7} {:>7} {:>7} {:>6} {:>6}",
+                player, result, stats.score(), stats.resources_gathered, stats.resources_spent,
+                stats.units_trained(), stats.buildings_built(), stats.kills, stats.units_lost,
+            )?;
+        }
+        Ok(())
+    }
+}
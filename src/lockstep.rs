@@ -0,0 +1,617 @@
+//! Deterministic lockstep
+//!
+//! Every peer runs the whole simulation. A command given at tick `t` is
+//! scheduled for tick `t + input_delay` and sent to every other peer; a tick
+//! only runs once every player's input for it has arrived. Since all peers
+//! apply the same commands to the same world in the same order, they stay in
+//! sync without ever sending game state.
+//!
+//! Inputs are resent until acknowledged. A peer that goes silent for
+//! `DISCONNECT_TIMEOUT` is dropped: the remaining peers share whatever inputs
+//! they hold from it, agree on the last tick it gave input for, and treat it
+//! as idle from then on.
+//!
+//! Every `CHECKSUM_INTERVAL` ticks the peers also swap a hash of their world,
+//! so a desync is caught close to where it happened instead of showing up as
+//! two players watching different matches.
+
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::command::{Command, CommandKind};
+use super::sim::PlayerId;
+use super::transport::Transport;
+
+pub const DEFAULT_INPUT_DELAY: u64 = 4;
+const RESEND_INTERVAL: Duration = Duration::from_millis(50);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Ticks of inputs kept after they run, so they can be forwarded when a player drops
+const HISTORY: u64 = 256;
+/// Most ticks of input packed in one message
+const MAX_BATCH: usize = 32;
+/// Ticks between state hash exchanges
+pub const CHECKSUM_INTERVAL: u64 = 30;
+/// How many of our latest checksums are resent, in case the first send was lost
+const CHECKSUM_RESENDS: usize = 2;
+
+/// A peer's world hash didn't match ours after `tick`
+#[derive(Copy, Clone, Debug)]
+pub struct Desync {
+    pub tick: u64,
+    pub player: PlayerId,
+    pub local: u64,
+    pub remote: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+enum Message {
+    /// `player`'s commands for consecutive ticks starting at `first_tick`
+    Input { player: PlayerId, first_tick: u64, inputs: Vec<Vec<CommandKind>> },
+    /// The sender has every input from the receiver up to and including `through`
+    Ack { through: u64 },
+    /// Keeps the connection alive while there's nothing to send
+    Ping { sent: Duration },
+    Pong { sent: Duration },
+    /// The sender has given up on `player`. It holds `player`'s inputs up to
+    /// and including `through`, the most recent of which are attached starting at `first_tick`.
+    Drop { player: PlayerId, through: Option<u64>, first_tick: u64, inputs: Vec<Vec<CommandKind>> },
+    /// The sender is leaving the match
+    Leave,
+    /// Hash of the sender's world after `tick`
+    Checksum { tick: u64, hash: u64 },
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+enum PeerState {
+    Connected,
+    /// Collecting reports from the remaining peers about how far its input got
+    Dropping,
+    /// Gone. Its input is empty for every tick after `last_tick`.
+    Gone { last_tick: Option<u64> },
+}
+
+struct Peer {
+    state: PeerState,
+    /// Highest tick of our input this peer has acknowledged
+    acked: Option<u64>,
+    last_heard: Duration,
+    /// Whether anything has arrived from this peer yet
+    heard: bool,
+    /// Reports of how far this player's input reached, from each peer, while dropping
+    drop_reports: BTreeMap<PlayerId, Option<u64>>,
+}
+
+pub struct Lockstep {
+    local: PlayerId,
+    input_delay: u64,
+    /// The next tick to simulate
+    tick: u64,
+    /// The next tick local input hasn't been sealed for
+    next_input_tick: u64,
+    /// Commands waiting to be sealed into the next input
+    pending: Vec<CommandKind>,
+    /// Inputs by tick, then by player
+    inputs: BTreeMap<u64, Vec<Option<Vec<CommandKind>>>>,
+    peers: Vec<Peer>,
+    last_resend: Duration,
+    now: Duration,
+    /// Smoothed round trip time
+    rtt: Duration,
+    /// World hashes by tick, then by player
+    checksums: BTreeMap<u64, Vec<Option<u64>>>,
+    desync: Option<Desync>,
+    /// Set once a desync has been found, after which checksums aren't compared
+    desynced: bool,
+}
+
+impl Lockstep {
+    pub fn new(local: PlayerId, players: usize, input_delay: u64) -> Self {
+        let peers = (0..players).map(|player| Peer {
+            state: PeerState::Connected,
+            acked: None,
+            last_heard: Duration::from_millis(0),
+            heard: player == local as usize,
+            drop_reports: BTreeMap::new(),
+        }).collect();
+
+        let mut lockstep = Self {
+            local,
+            input_delay,
+            tick: 0,
+            next_input_tick: 0,
+            pending: Vec::new(),
+            inputs: BTreeMap::new(),
+            peers,
+            last_resend: Duration::from_millis(0),
+            now: Duration::from_millis(0),
+            rtt: Duration::from_millis(100),
+            checksums: BTreeMap::new(),
+            desync: None,
+            desynced: false,
+        };
+        lockstep.seal_inputs();
+        lockstep
+    }
+
+    pub fn local_player(&self) -> PlayerId {
+        self.local
+    }
+
+    /// The tick the next call to `next_tick` will return
+    pub fn tick(&self) -> u64 {
+        self.tick
+    }
+
+    pub fn rtt(&self) -> Duration {
+        self.rtt
+    }
+
+    pub fn input_delay(&self) -> u64 {
+        self.input_delay
+    }
+
+    /// Change how far ahead local commands are scheduled. Larger delays hide
+    /// more latency, smaller ones feel more responsive.
+    pub fn set_input_delay(&mut self, input_delay: u64) {
+        self.input_delay = input_delay;
+        self.seal_inputs();
+    }
+
+    /// The smallest input delay that hides the measured latency, with a tick to spare
+    pub fn recommended_input_delay(&self, tick_duration: Duration) -> u64 {
+        let one_way = self.rtt.as_secs_f32() / 2.;
+        (one_way / tick_duration.as_secs_f32()).ceil() as u64 + 1
+    }
+
+    pub fn is_connected(&self, player: PlayerId) -> bool {
+        self.peers[player as usize].state == PeerState::Connected
+    }
+
+    /// Whether every peer has shown up, or been given up on
+    pub fn is_ready(&self) -> bool {
+        self.peers.iter().all(|peer| peer.heard || peer.state != PeerState::Connected)
+    }
+
+    /// Queue a local command for the next input
+    pub fn issue(&mut self, command: CommandKind) {
+        self.pending.push(command);
+    }
+
+    /// If every player's input for the current tick is in, return the
+    /// commands to apply before stepping the world and move on
+    pub fn next_tick(&mut self) -> Option<Vec<Command>> {
+        let tick = self.tick;
+        let inputs = self.inputs.get(&tick)?;
+
+        let mut commands = Vec::new();
+        for (player, input) in inputs.iter().enumerate() {
+            match (input, self.peers[player].state) {
+                (Some(input), _) => commands.extend(input.iter().cloned().map(|kind| Command {
+                    tick,
+                    player: player as PlayerId,
+                    kind,
+                })),
+                (None, PeerState::Gone { last_tick }) if last_tick.is_none_or(|last| tick > last) => {}
+                (None, _) => return None,
+            }
+        }
+
+        self.tick += 1;
+        if let Some(&oldest) = self.inputs.keys().next() {
+            if oldest + HISTORY < self.tick {
+                self.inputs.remove(&oldest);
+            }
+        }
+        self.seal_inputs();
+        Some(commands)
+    }
+
+    /// Share the hash of our world after `tick`. Call each time the world
+    /// reaches a multiple of `CHECKSUM_INTERVAL`.
+    pub fn record_checksum(&mut self, tick: u64, hash: u64, transport: &mut impl Transport) {
+        self.store_checksum(self.local, tick, hash);
+        self.broadcast(transport, &Message::Checksum { tick, hash });
+        while let Some(&oldest) = self.checksums.keys().next() {
+            if oldest + HISTORY >= tick {
+                break;
+            }
+            self.checksums.remove(&oldest);
+        }
+    }
+
+    /// The latest tick every remaining peer has sent a matching checksum for.
+    /// State from before it never needs to be looked at again. None once a
+    /// desync has been found, as the state around it is wanted for the dump.
+    pub fn confirmed_checksum(&self) -> Option<u64> {
+        if self.desynced {
+            return None;
+        }
+        let players: Vec<usize> = (0..self.peers.len()).filter(|&player| self.peers[player].state == PeerState::Connected).collect();
+        self.checksums.iter()
+            .rev()
+            .find(|(_, hashes)| {
+                let mut hashes = players.iter().map(|&player| hashes[player]);
+                match hashes.next() {
+                    Some(Some(first)) => hashes.all(|hash| hash == Some(first)),
+                    Some(None) => false,
+                    None => true,
+                }
+            })
+            .map(|(&tick, _)| tick)
+    }
+
+    /// Whether a desync has been found, whether or not it's been taken yet
+    pub fn is_desynced(&self) -> bool {
+        self.desynced
+    }
+
+    /// The first desync found, returned once
+    pub fn take_desync(&mut self) -> Option<Desync> {
+        self.desync.take()
+    }
+
+    /// Announce that we're leaving so the others don't wait for the timeout
+    pub fn leave(&mut self, transport: &mut impl Transport) {
+        self.broadcast(transport, &Message::Leave);
+    }
+
+    /// Send and receive. Call once per frame.
+    pub fn update(&mut self, now: Duration, transport: &mut impl Transport) {
+        self.now = now;
+
+        while let Some((from, bytes)) = transport.recv() {
+            let message = match bincode::deserialize(&bytes) {
+                Ok(message) => message,
+                Err(_) => continue,
+            };
+            if from == self.local || from as usize >= self.peers.len() {
+                continue;
+            }
+            self.peers[from as usize].last_heard = now;
+            self.peers[from as usize].heard = true;
+            self.handle(from, message, transport);
+        }
+
+        for player in 0..self.peers.len() as PlayerId {
+            let peer = &self.peers[player as usize];
+            if player != self.local && peer.state == PeerState::Connected && now > peer.last_heard + DISCONNECT_TIMEOUT {
+                self.begin_drop(player, transport);
+            }
+        }
+
+        if now >= self.last_resend + RESEND_INTERVAL {
+            self.last_resend = now;
+            self.send_inputs(transport);
+            for player in 0..self.peers.len() as PlayerId {
+                if self.peers[player as usize].state == PeerState::Dropping {
+                    let report = self.drop_report(player);
+                    self.broadcast(transport, &report);
+                }
+            }
+            let local = self.local as usize;
+            let recent = self.checksums.iter().rev().filter_map(|(&tick, hashes)| Some((tick, hashes[local]?)));
+            for (tick, hash) in recent.take(CHECKSUM_RESENDS) {
+                self.broadcast(transport, &Message::Checksum { tick, hash });
+            }
+            self.broadcast(transport, &Message::Ping { sent: now });
+        }
+    }
+
+    fn handle(&mut self, from: PlayerId, message: Message, transport: &mut impl Transport) {
+        match message {
+            Message::Input { player, first_tick, inputs } => {
+                if player != from {
+                    return;
+                }
+                self.store_inputs(player, first_tick, inputs);
+                if let Some(through) = self.contiguous_input(player) {
+                    send(transport, from, &Message::Ack { through });
+                }
+            }
+            Message::Ack { through } => {
+                let peer = &mut self.peers[from as usize];
+                peer.acked = Some(peer.acked.map_or(through, |acked| acked.max(through)));
+            }
+            Message::Ping { sent } => send(transport, from, &Message::Pong { sent }),
+            Message::Pong { sent } => {
+                let sample = self.now.checked_sub(sent).unwrap_or_default();
+                self.rtt = (self.rtt * 7 + sample) / 8;
+            }
+            Message::Drop { player, through, first_tick, inputs } => {
+                if player == self.local || player as usize >= self.peers.len() {
+                    return;
+                }
+                self.store_inputs(player, first_tick, inputs);
+                match self.peers[player as usize].state {
+                    PeerState::Connected => self.begin_drop(player, transport),
+                    // We've moved on, but the sender may have missed our report
+                    PeerState::Gone { .. } => send(transport, from, &self.drop_report(player)),
+                    PeerState::Dropping => {}
+                }
+                self.peers[player as usize].drop_reports.insert(from, through);
+                self.try_finish_drop(player);
+            }
+            Message::Leave => {
+                // Handled like a timeout, since the others may still be missing
+                // some of the leaver's last inputs
+                if self.peers[from as usize].state == PeerState::Connected {
+                    self.begin_drop(from, transport);
+                }
+            }
+            Message::Checksum { tick, hash } => self.store_checksum(from, tick, hash),
+        }
+    }
+
+    /// Record `player`'s hash for `tick`, and compare it with ours if both are in
+    fn store_checksum(&mut self, player: PlayerId, tick: u64, hash: u64) {
+        // Too old to matter, and already compared if it ever could be
+        if tick + HISTORY < self.tick && !self.checksums.contains_key(&tick) {
+            return;
+        }
+        let players = self.peers.len();
+        let hashes = self.checksums.entry(tick).or_insert_with(|| vec![None; players]);
+        hashes[player as usize] = Some(hash);
+
+        if self.desynced {
+            return;
+        }
+        let local = match hashes[self.local as usize] {
+            Some(local) => local,
+            None => return,
+        };
+        let mismatch = hashes.iter()
+            .enumerate()
+            .find_map(|(player, hash)| hash.filter(|&hash| hash != local).map(|hash| (player, hash)));
+        if let Some((player, remote)) = mismatch {
+            self.desync = Some(Desync { tick, player: player as PlayerId, local, remote });
+            self.desynced = true;
+        }
+    }
+
+    /// Stop waiting on `player`, and tell everyone else what we have from them
+    fn begin_drop(&mut self, player: PlayerId, transport: &mut impl Transport) {
+        let through = self.contiguous_input(player);
+        let peer = &mut self.peers[player as usize];
+        peer.state = PeerState::Dropping;
+        peer.drop_reports.insert(self.local, through);
+
+        let report = self.drop_report(player);
+        self.broadcast(transport, &report);
+        self.try_finish_drop(player);
+    }
+
+    /// Our view of how far `player`'s input got, with the recent inputs
+    /// attached. Peers are never more than a few ticks apart, so the last
+    /// `MAX_BATCH` ticks cover anything another peer could be missing.
+    fn drop_report(&self, player: PlayerId) -> Message {
+        let through = self.peers[player as usize].drop_reports.get(&self.local).cloned().flatten();
+        let (first_tick, inputs) = self.collect_inputs(player, self.tick.saturating_sub(MAX_BATCH as u64), MAX_BATCH * 2);
+        Message::Drop { player, through, first_tick, inputs }
+    }
+
+    /// Once every remaining peer has reported, the furthest anyone got is
+    /// where `player`'s input ends for everyone
+    fn try_finish_drop(&mut self, player: PlayerId) {
+        let remaining: Vec<PlayerId> = (0..self.peers.len() as PlayerId)
+            .filter(|&other| other != player && matches!(self.peers[other as usize].state, PeerState::Connected))
+            .chain(std::iter::once(self.local))
+            .collect();
+        let peer = &mut self.peers[player as usize];
+        if peer.state != PeerState::Dropping || !remaining.iter().all(|other| peer.drop_reports.contains_key(other)) {
+            return;
+        }
+        let last_tick = peer.drop_reports.values().cloned().max().flatten();
+        peer.state = PeerState::Gone { last_tick };
+    }
+
+    fn store_inputs(&mut self, player: PlayerId, first_tick: u64, inputs: Vec<Vec<CommandKind>>) {
+        let players = self.peers.len();
+        for (tick, input) in (first_tick..).zip(inputs) {
+            // Already run and forgotten
+            if tick + HISTORY < self.tick && !self.inputs.contains_key(&tick) {
+                continue;
+            }
+            let slots = self.inputs.entry(tick).or_insert_with(|| vec![None; players]);
+            if slots[player as usize].is_none() {
+                slots[player as usize] = Some(input);
+            }
+        }
+    }
+
+    /// The last tick before the first gap in `player`'s input
+    fn contiguous_input(&self, player: PlayerId) -> Option<u64> {
+        let mut through = None;
+        for (&tick, inputs) in &self.inputs {
+            let follows = through.is_none_or(|through| tick == through + 1);
+            if !follows || inputs[player as usize].is_none() {
+                break;
+            }
+            through = Some(tick);
+        }
+        through
+    }
+
+    /// Up to `limit` consecutive ticks of `player`'s input, starting from the
+    /// first one we still hold at or after `from`
+    fn collect_inputs(&self, player: PlayerId, from: u64, limit: usize) -> (u64, Vec<Vec<CommandKind>>) {
+        let mut first_tick = None;
+        let mut inputs = Vec::new();
+        for (&tick, slots) in self.inputs.range(from..).take(limit) {
+            let first = *first_tick.get_or_insert(tick);
+            match &slots[player as usize] {
+                Some(input) if tick == first + inputs.len() as u64 => inputs.push(input.clone()),
+                _ => break,
+            }
+        }
+        (first_tick.unwrap_or(from), inputs)
+    }
+
+    /// Seal local input for every tick up to `tick + input_delay`
+    fn seal_inputs(&mut self) {
+        let players = self.peers.len();
+        while self.next_input_tick <= self.tick + self.input_delay {
+            let input = std::mem::take(&mut self.pending);
+            let slots = self.inputs.entry(self.next_input_tick).or_insert_with(|| vec![None; players]);
+            slots[self.local as usize] = Some(input);
+            self.next_input_tick += 1;
+        }
+    }
+
+    /// Send each connected peer our inputs it hasn't acknowledged yet
+    fn send_inputs(&mut self, transport: &mut impl Transport) {
+        for player in 0..self.peers.len() as PlayerId {
+            let peer = &self.peers[player as usize];
+            if player == self.local || peer.state != PeerState::Connected {
+                continue;
+            }
+            let unacked = peer.acked.map_or(0, |acked| acked + 1);
+            let (first_tick, inputs) = self.collect_inputs(self.local, unacked, MAX_BATCH);
+            if !inputs.is_empty() {
+                send(transport, player, &Message::Input { player: self.local, first_tick, inputs });
+            }
+        }
+    }
+
+    fn broadcast(&self, transport: &mut impl Transport, message: &Message) {
+        for player in 0..self.peers.len() as PlayerId {
+            if player != self.local && !matches!(self.peers[player as usize].state, PeerState::Gone { .. }) {
+                send(transport, player, message);
+            }
+        }
+    }
+}
+
+fn send(transport: &mut impl Transport, to: PlayerId, message: &Message) {
+    let bytes = bincode::serialize(message).expect("Message serialization can't fail");
+    transport.send(to, &bytes);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ai::{self, Ai, Difficulty};
+    use crate::mapgen::{self, MapGenParams};
+    use crate::sim::World;
+    use crate::transport::{SimulatedNetwork, SimulatedTransport};
+
+    const FRAME: Duration = Duration::from_millis(16);
+
+    /// One player's side of a match: its own copy of the world, with a
+    /// computer player giving its orders through lockstep
+    struct Peer {
+        lockstep: Lockstep,
+        transport: SimulatedTransport,
+        world: World,
+        ai: Ai,
+        /// Every command applied, in order
+        log: Vec<Command>,
+        /// Our world hash at each checksum tick
+        hashes: Vec<(u64, u64)>,
+        /// Set to stop updating it, as if its machine had died
+        crashed: bool,
+    }
+
+    impl Peer {
+        /// Send, receive, and run a tick if every input for it is in
+        fn update(&mut self, now: Duration) {
+            self.lockstep.update(now, &mut self.transport);
+            let commands = match self.lockstep.next_tick() {
+                Some(commands) => commands,
+                None => return,
+            };
+            for command in commands {
+                self.world.apply(&command);
+                self.log.push(command);
+            }
+            self.world.step();
+            if self.world.tick.is_multiple_of(CHECKSUM_INTERVAL) {
+                let hash = self.world.state_hash();
+                self.lockstep.record_checksum(self.world.tick, hash, &mut self.transport);
+                self.hashes.push((self.world.tick, hash));
+            }
+            for kind in self.ai.update(&self.world, ai::UNLIMITED_BUDGET) {
+                self.lockstep.issue(kind);
+            }
+        }
+    }
+
+    fn start(players: usize, network: &SimulatedNetwork) -> Vec<Peer> {
+        let map = mapgen::generate(&MapGenParams { seed: 5, size: 64, players: players as u8 });
+        (0..players as PlayerId).map(|player| {
+            let world = World::from_map(map.clone(), 5);
+            Peer {
+                lockstep: Lockstep::new(player, players, DEFAULT_INPUT_DELAY),
+                transport: network.endpoint(player),
+                ai: Ai::new(&world, player, Difficulty::Hard, player as u64),
+                world,
+                log: Vec::new(),
+                hashes: Vec::new(),
+                crashed: false,
+            }
+        }).collect()
+    }
+
+    /// Run the peers that haven't crashed until they've all simulated
+    /// `ticks` ticks, giving up after `frames` frames
+    fn run(network: &SimulatedNetwork, peers: &mut [Peer], now: &mut Duration, ticks: u64, frames: usize) {
+        for _ in 0..frames {
+            let running = peers.iter().filter(|peer| !peer.crashed);
+            if running.clone().all(|peer| peer.world.tick >= ticks) {
+                return;
+            }
+            *now += FRAME;
+            network.advance(*now);
+            for peer in peers.iter_mut().filter(|peer| !peer.crashed && peer.world.tick < ticks) {
+                peer.update(*now);
+            }
+        }
+        panic!("Stuck at ticks {:?}", peers.iter().map(|peer| peer.world.tick).collect::<Vec<_>>());
+    }
+
+    /// The logs and hashes of `peers` up to `ticks` are the same
+    fn assert_agree(peers: &[&Peer], ticks: u64) {
+        let first = peers[0];
+        assert!(!first.log.is_empty(), "Nothing was ordered");
+        for peer in &peers[1..] {
+            let player = peer.lockstep.local_player();
+            assert!(!peer.lockstep.is_desynced(), "Player {} desynced", player);
+            assert_eq!(first.log, peer.log, "Player {} applied different commands", player);
+            assert_eq!(first.hashes, peer.hashes, "Player {} has different hashes", player);
+            assert_eq!(peer.world.tick, ticks);
+        }
+    }
+
+    #[test]
+    fn peers_agree_over_a_lossy_network() {
+        let network = SimulatedNetwork::new(3, 0.2, Duration::from_millis(20), Duration::from_millis(120), 1);
+        let mut peers = start(3, &network);
+        let mut now = Duration::from_millis(0);
+        run(&network, &mut peers, &mut now, 300, 10_000);
+
+        assert_agree(&peers.iter().collect::<Vec<_>>(), 300);
+        for peer in &peers {
+            assert!(peer.lockstep.confirmed_checksum().is_some());
+        }
+    }
+
+    #[test]
+    fn a_crashed_peer_is_dropped_and_the_rest_carry_on() {
+        let network = SimulatedNetwork::new(3, 0.1, Duration::from_millis(10), Duration::from_millis(80), 2);
+        let mut peers = start(3, &network);
+        let mut now = Duration::from_millis(0);
+        run(&network, &mut peers, &mut now, 100, 10_000);
+
+        peers[2].crashed = true;
+        run(&network, &mut peers, &mut now, 400, 10_000);
+
+        for peer in &peers[..2] {
+            assert!(!peer.lockstep.is_connected(2));
+            assert!(peer.lockstep.is_connected(1 - peer.lockstep.local_player()));
+        }
+        assert_agree(&[&peers[0], &peers[1]], 400);
+        // Everything the crashed player got out before it went is kept
+        assert!(peers[0].log.iter().any(|command| command.player == 2));
+    }
+}
@@ -1,53 +1,776 @@
 use winit::{
-    event::{Event, WindowEvent},
+    event::{ElementState, Event, KeyboardInput, MouseScrollDelta, VirtualKeyCode, WindowEvent},
     event_loop::{ControlFlow, EventLoop},
     window::WindowBuilder,
 };
 
+use std::collections::BTreeMap;
 use std::time::{Duration, Instant};
 
-mod model;
 mod texture;
+mod camera;
 mod renderer;
-use renderer::Renderer;
+mod sync;
+mod particles;
+use rts::{ai, animation, determinism, ecs, frustum, lockstep, mapfile, mapgen, model, savegame, script, server, sim, vat};
+use camera::Camera;
+use renderer::{Msaa, PresentMode, RenderSettings, Renderer};
+use sync::{RenderSync, UnitModel};
+use particles::{EffectTable, ParticleSystem};
+use sim::{UnitKind, World};
+use mapgen::MapGenParams;
+use rts::ai::{Ai, Difficulty};
+use rts::balance::StatTable;
+use rts::client::Client;
+use rts::command::{Command, CommandKind};
+use rts::influence::{InfluenceMap, Layer};
+use rts::lifecycle::{self, MatchRules, Phase, Summary};
+use rts::replay::{Playback, Replay};
+use rts::script::{Mission, MissionScript, ScriptEvent};
+use lockstep::{Desync, Lockstep};
+use rts::transport::UdpTransport;
+
+const QUICKSAVE_PATH: &str = "quicksave.sav";
+const REPLAY_PATH: &str = "last.replay";
+/// Ticks skipped by each seek key in playback
+const SEEK_TICKS: u64 = 600;
+/// The player the person at the keyboard controls in single player
+const LOCAL_PLAYER: sim::PlayerId = 0;
+/// Pixels of a touchpad scroll that zoom as much as a notch of the mouse wheel
+const PIXELS_PER_NOTCH: f32 = 100.;
+
+/// What drives the world: live play, a networked match, a match on a
+/// dedicated server, or a recorded match
+enum Session {
+    Live {
+        world: World,
+        /// Commands given since the last tick
+        pending: Vec<Command>,
+        /// `None` once a save has been loaded, since the replay no longer
+        /// describes how this world came to be
+        recording: Option<Replay>,
+        /// Computer players
+        ais: Vec<Ai>,
+        mission: Option<Mission>,
+    },
+    Networked {
+        world: World,
+        lockstep: Lockstep,
+        transport: UdpTransport,
+        recording: Replay,
+        /// Copies of the world at each checksum not yet confirmed by every
+        /// peer, so the right one can be dumped if it turns out to differ
+        snapshots: BTreeMap<u64, World>,
+    },
+    Client {
+        world: World,
+        client: Client,
+        recording: Replay,
+    },
+    Playback(Playback),
+}
+
+impl Session {
+    fn world(&self) -> &World {
+        match self {
+            Session::Live { world, .. } => world,
+            Session::Networked { world, .. } => world,
+            Session::Client { world, .. } => world,
+            Session::Playback(playback) => playback.world(),
+        }
+    }
+
+    /// The player whose view is shown. A replay is watched as player 0.
+    fn local_player(&self) -> sim::PlayerId {
+        match self {
+            Session::Live { .. } => LOCAL_PLAYER,
+            Session::Networked { lockstep, .. } => lockstep.local_player(),
+            Session::Client { client, .. } => client.player(),
+            Session::Playback(_) => 0,
+        }
+    }
+
+    /// Whether everyone taking part is there and the match can start
+    fn is_ready(&self) -> bool {
+        match self {
+            Session::Networked { lockstep, .. } => lockstep.is_ready(),
+            Session::Client { client, .. } => client.is_started(),
+            Session::Live { .. } | Session::Playback(_) => true,
+        }
+    }
+
+    /// Whether the match has been decided. A replay just runs to its end.
+    fn is_over(&self) -> bool {
+        match self {
+            Session::Playback(_) => false,
+            _ => lifecycle::is_over(self.world()),
+        }
+    }
+
+    /// Give an order as the local player. It's applied on a later tick, once
+    /// the other players in a networked match have it too. A replay can't be
+    /// changed, so orders given while watching one are ignored.
+    fn issue(&mut self, kind: CommandKind) {
+        match self {
+            Session::Live { world, pending, .. } => pending.push(Command { tick: world.tick, player: LOCAL_PLAYER, kind }),
+            Session::Networked { lockstep, .. } => lockstep.issue(kind),
+            Session::Client { client, .. } => client.issue(kind),
+            Session::Playback(_) => (),
+        }
+    }
+
+    /// Advance one tick. Returns false if the world has to wait, because a
+    /// networked match is still missing someone's input.
+    fn step(&mut self) -> bool {
+        match self {
+            Session::Live { world, pending, recording, ais, mission } => {
+                for ai in ais.iter_mut() {
+                    for kind in ai.update(world, ai::DEFAULT_BUDGET) {
+                        pending.push(Command { tick: world.tick, player: ai.player(), kind });
+                    }
+                }
+                for mut command in pending.drain(..) {
+                    command.tick = world.tick;
+                    world.apply(&command);
+                    if let Some(replay) = recording {
+                        replay.record(command);
+                    }
+                }
+                world.step();
+                if let Some(mission) = mission {
+                    mission.update(world);
+                    mission.take_events().into_iter().for_each(show_event);
+                }
+                if let Some(replay) = recording {
+                    replay.extend_to(world.tick);
+                }
+            }
+            Session::Networked { world, lockstep, transport, recording, snapshots } => {
+                let commands = match lockstep.next_tick() {
+                    Some(commands) => commands,
+                    None => return false,
+                };
+                for command in commands {
+                    world.apply(&command);
+                    recording.record(command);
+                }
+                world.step();
+                recording.extend_to(world.tick);
+
+                if world.tick % lockstep::CHECKSUM_INTERVAL == 0 {
+                    // Once desynced, only the snapshots up to the desync are of any use
+                    if !lockstep.is_desynced() {
+                        snapshots.insert(world.tick, world.clone());
+                    }
+                    lockstep.record_checksum(world.tick, world.state_hash(), transport);
+                }
+                if let Some(confirmed) = lockstep.confirmed_checksum() {
+                    *snapshots = snapshots.split_off(&(confirmed + 1));
+                }
+            }
+            Session::Client { world, client, recording } => {
+                let commands = match client.next_tick() {
+                    Some(commands) => commands,
+                    None => return false,
+                };
+                for command in commands {
+                    world.apply(&command);
+                    recording.record(command);
+                }
+                world.step();
+                recording.extend_to(world.tick);
+            }
+            Session::Playback(playback) => {
+                playback.step();
+                playback.take_events().into_iter().for_each(show_event);
+            }
+        }
+        true
+    }
+
+    fn finish(&mut self) {
+        let replay = match self {
+            Session::Live { recording: Some(replay), .. } => replay,
+            Session::Networked { lockstep, transport, recording, .. } => {
+                lockstep.leave(transport);
+                recording
+            }
+            Session::Client { client, recording, .. } => {
+                client.leave();
+                recording
+            }
+            _ => return,
+        };
+        if let Err(err) = replay.save(REPLAY_PATH) {
+            eprintln!("Failed to write replay: {}", err);
+        }
+    }
+}
 
 fn main() {
+    let mut args: Vec<String> = std::env::args().skip(1).collect();
+    let rules = take_rules(&mut args);
+    let effects = take_effects(&mut args);
+    let unit_model = take_unit_model(&mut args);
+    let skins = take_skins(&mut args);
+    let mut args = args.into_iter().peekable();
+    let flag = match args.peek().map(String::as_str) {
+        Some(flag) if flag.starts_with("--") => args.next(),
+        _ => None,
+    };
+
+    let load_map = |path: Option<String>| match path {
+        Some(path) => mapfile::load(&path).unwrap_or_else(|err| {
+            eprintln!("{}: {}", path, err);
+            std::process::exit(1);
+        }),
+        None => mapgen::generate(&MapGenParams { seed: 0, size: 128, players: 2 }),
+    };
+
+    let mut session = match flag.as_deref() {
+        Some("--check-determinism") => {
+            let map = load_map(args.next());
+            match determinism::check(&map, 0, 20_000) {
+                Ok(()) => println!("Simulation is deterministic"),
+                Err(divergence) => {
+                    eprintln!("Simulation diverged at tick {}: {:016x} != {:016x}", divergence.tick, divergence.expected, divergence.found);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        Some("--diff-saves") => {
+            let mut load_save = |what| {
+                let path = args.next().unwrap_or_else(|| {
+                    eprintln!("--diff-saves needs a {} save", what);
+                    std::process::exit(1);
+                });
+                let (world, _) = savegame::load(&path).unwrap_or_else(|err| {
+                    eprintln!("{}: {}", path, err);
+                    std::process::exit(1);
+                });
+                world
+            };
+            let (a, b) = (load_save("first"), load_save("second"));
+            for difference in determinism::diff(&a, &b) {
+                println!("{}", difference);
+            }
+            return;
+        }
+        Some("--ai") => {
+            // --ai <difficulty> [map]: play against the computer
+            let difficulty: Difficulty = args.next().unwrap_or_else(|| "normal".to_string()).parse().unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                std::process::exit(1);
+            });
+            let path = args.next();
+            let map = load_map(path.clone());
+            let seed = 0;
+            let mut world = World::with_rules(map.clone(), seed, rules);
+            let mut recording = Replay::new(map, seed, rules);
+            let mission = path.and_then(|path| start_mission(&path, &mut world, &mut recording));
+            let ais = (0..world.players.len() as sim::PlayerId)
+                .filter(|&player| player != LOCAL_PLAYER)
+                .map(|player| Ai::new(&world, player, difficulty, seed + player as u64))
+                .collect();
+            Session::Live {
+                world,
+                pending: Vec::new(),
+                recording: Some(recording),
+                ais,
+                mission,
+            }
+        }
+        Some("--connect") => {
+            // --connect <server address> [map]
+            let addr = args.next().unwrap_or_else(|| format!("127.0.0.1:{}", server::DEFAULT_PORT));
+            let addr = addr.parse().unwrap_or_else(|err| {
+                eprintln!("{}: {}", addr, err);
+                std::process::exit(1);
+            });
+            let map = load_map(args.next());
+            let client = Client::connect(addr, server::map_hash(&map)).unwrap_or_else(|err| {
+                eprintln!("Failed to join {}: {}", addr, err);
+                std::process::exit(1);
+            });
+            println!("Joined {} as player {}", addr, client.player());
+            Session::Client {
+                world: World::with_rules(map.clone(), client.seed(), client.rules()),
+                recording: Replay::new(map, client.seed(), client.rules()),
+                client,
+            }
+        }
+        Some("--replay") => {
+            let path = args.next().unwrap_or_else(|| REPLAY_PATH.to_string());
+            let replay = Replay::load(&path).unwrap_or_else(|err| {
+                eprintln!("{}: {}", path, err);
+                std::process::exit(1);
+            });
+            Session::Playback(Playback::new(replay))
+        }
+        Some("--lockstep") => {
+            // --lockstep <local player> <address of player 0> <address of player 1> ...
+            let local = args.next().and_then(|player| player.parse().ok()).unwrap_or_else(|| {
+                eprintln!("--lockstep needs the local player number");
+                std::process::exit(1);
+            });
+            let peers: Vec<_> = args.map(|addr| addr.parse().unwrap_or_else(|err| {
+                eprintln!("{}: {}", addr, err);
+                std::process::exit(1);
+            })).collect();
+            if local as usize >= peers.len() {
+                eprintln!("Player {} has no address", local);
+                std::process::exit(1);
+            }
+            let transport = UdpTransport::bind(local, peers.clone()).unwrap_or_else(|err| {
+                eprintln!("Failed to bind {}: {}", peers[local as usize], err);
+                std::process::exit(1);
+            });
+
+            // Every peer has to start from the same world, so they all have
+            // to be given the same rules too
+            let map = mapgen::generate(&MapGenParams { seed: 0, size: 128, players: peers.len() as u8 });
+            let seed = 0;
+            Session::Networked {
+                world: World::with_rules(map.clone(), seed, rules),
+                lockstep: Lockstep::new(local, peers.len(), lockstep::DEFAULT_INPUT_DELAY),
+                transport,
+                recording: Replay::new(map, seed, rules),
+                snapshots: BTreeMap::new(),
+            }
+        }
+        Some(flag) => {
+            eprintln!("Unknown option {}", flag);
+            std::process::exit(1);
+        }
+        None => {
+            let path = args.next();
+            let map = load_map(path.clone());
+            let seed = 0;
+            let mut world = World::with_rules(map.clone(), seed, rules);
+            let mut recording = Replay::new(map, seed, rules);
+            let mission = path.and_then(|path| start_mission(&path, &mut world, &mut recording));
+            Session::Live {
+                world,
+                pending: Vec::new(),
+                recording: Some(recording),
+                ais: Vec::new(),
+                mission,
+            }
+        }
+    };
+
     let event_loop = EventLoop::new();
-    let _window = WindowBuilder::new()
+    let window = WindowBuilder::new()
         .with_title("simple strategy")
         .build(&event_loop)
         .expect("Failed to build a window :(");
+    // Cycled through with M and V, whatever the adapter turns out to support
+    let mut requested = RenderSettings::default();
+    let mut renderer = futures::executor::block_on(Renderer::new(&window, requested));
+    report_fallback(requested, renderer.settings());
+    let mut render_sync = RenderSync::new(&mut renderer, unit_model);
+    if let Err(err) = render_sync.load_skins(&mut renderer, &skins) {
+        eprintln!("Failed to load skins: {}", err);
+        std::process::exit(1);
+    }
+    let mut particles = ParticleSystem::new(effects);
+    let size = window.inner_size();
+    // Influence map layer drawn over the terrain, cycled with I
+    let mut overlay: Option<Layer> = None;
+    let mut influence = InfluenceMap::new(session.world(), session.local_player());
+    let mut camera = Camera::new(session.world().map.terrain.extent() / 2., size.width as f32 / size.height as f32);
 
     let dt = Duration::from_millis(16);
     let mut stepper = TimeStepper::new(Instant::now(), dt);
+    let started = Instant::now();
+    let mut last_frame = started;
+    let mut phase = Phase::Lobby;
 
     event_loop.run(move |event, _target, control_flow| {
         if let Some(flow) = handle_event(&event) {
+            // An ended match has already been wrapped up
+            if phase != Phase::Ended {
+                session.finish();
+            }
             *control_flow = flow;
             return;
         }
 
         *control_flow = ControlFlow::Poll;
 
-        stepper.advance(Instant::now());
+        match &event {
+            Event::WindowEvent { event: WindowEvent::Resized(size), .. } => {
+                renderer.resize(*size);
+                camera.aspect = size.width as f32 / size.height as f32;
+            }
+            Event::WindowEvent { event: WindowEvent::ScaleFactorChanged { new_inner_size, .. }, .. } => {
+                renderer.resize(**new_inner_size);
+                camera.aspect = new_inner_size.width as f32 / new_inner_size.height as f32;
+            }
+            Event::WindowEvent { event: WindowEvent::MouseWheel { delta, .. }, .. } => camera.zoom_by(match delta {
+                MouseScrollDelta::LineDelta(_, lines) => *lines,
+                MouseScrollDelta::PixelDelta(pixels) => pixels.y as f32 / PIXELS_PER_NOTCH,
+            }),
+            _ => (),
+        }
+
+        if let Some(key) = pressed_key(&event) {
+            // W, A, S and D pan the camera whatever is being played, and M
+            // and V switch anti-aliasing and present mode
+            match key {
+                VirtualKeyCode::W => camera.pan(0., 1.),
+                VirtualKeyCode::A => camera.pan(-1., 0.),
+                VirtualKeyCode::S => camera.pan(0., -1.),
+                VirtualKeyCode::D => camera.pan(1., 0.),
+                VirtualKeyCode::M | VirtualKeyCode::V => {
+                    if key == VirtualKeyCode::M {
+                        requested.msaa = cycle(&Msaa::ALL, requested.msaa);
+                    } else {
+                        requested.present_mode = cycle(&PresentMode::ALL, requested.present_mode);
+                    }
+                    renderer.set_settings(requested);
+                    report_fallback(requested, renderer.settings());
+                }
+                VirtualKeyCode::I => {
+                    overlay = match overlay {
+                        None => Some(Layer::ALL[0]),
+                        Some(layer) => Layer::ALL.get(layer as usize + 1).cloned(),
+                    };
+                    match overlay {
+                        Some(layer) => {
+                            influence.update(session.world());
+                            render_sync.set_overlay(influence.debug_instances(layer, &session.world().map.terrain), &mut renderer);
+                        }
+                        None => render_sync.set_overlay(Vec::new(), &mut renderer),
+                    }
+                }
+                _ => (),
+            }
+            // T trains a worker at each of our headquarters, and X stops all our units
+            let player = session.local_player();
+            let orders = match key {
+                VirtualKeyCode::T => session.world().units.iter()
+                    .filter(|(_, unit)| unit.owner == player && unit.kind == UnitKind::Headquarters)
+                    .map(|(building, _)| CommandKind::Train { building, kind: UnitKind::Worker })
+                    .collect(),
+                VirtualKeyCode::X => {
+                    let units: Vec<_> = session.world().units.iter()
+                        .filter(|(_, unit)| unit.owner == player && !unit.kind.is_building())
+                        .map(|(id, _)| id)
+                        .collect();
+                    vec![CommandKind::Stop { units }]
+                }
+                _ => Vec::new(),
+            };
+            orders.into_iter().for_each(|kind| session.issue(kind));
+            match &mut session {
+                // A save can't hold a mission's state, so there is no saving mid-mission
+                Session::Live { mission: Some(_), .. } => if let VirtualKeyCode::F5 | VirtualKeyCode::F9 = key {
+                    eprintln!("Can't save or load during a mission");
+                },
+                Session::Live { world, recording, ais, .. } => match key {
+                    VirtualKeyCode::F5 => if let Err(err) = savegame::save(world, ais, QUICKSAVE_PATH) {
+                        eprintln!("Failed to save: {}", err);
+                    },
+                    VirtualKeyCode::F9 => match savegame::load(QUICKSAVE_PATH) {
+                        Ok((loaded, loaded_ais)) => {
+                            *world = loaded;
+                            *ais = loaded_ais;
+                            *recording = None;
+                        }
+                        Err(err) => eprintln!("Failed to load: {}", err),
+                    },
+                    _ => (),
+                },
+                Session::Networked { .. } | Session::Client { .. } => (),
+                Session::Playback(playback) => match key {
+                    VirtualKeyCode::Space => stepper.set_paused(!stepper.paused),
+                    VirtualKeyCode::Key1 => stepper.set_speed(1),
+                    VirtualKeyCode::Key2 => stepper.set_speed(2),
+                    VirtualKeyCode::Key3 => stepper.set_speed(4),
+                    VirtualKeyCode::Key4 => stepper.set_speed(8),
+                    VirtualKeyCode::Left => playback.seek(playback.world().tick.saturating_sub(SEEK_TICKS)),
+                    VirtualKeyCode::Right => playback.seek(playback.world().tick + SEEK_TICKS),
+                    _ => (),
+                },
+            }
+        }
+
+        if let Session::Networked { lockstep, transport, snapshots, .. } = &mut session {
+            lockstep.update(started.elapsed(), transport);
+            if let Some(desync) = lockstep.take_desync() {
+                dump_desync(&desync, lockstep.local_player(), snapshots);
+            }
+        }
+        if let Session::Client { client, .. } = &mut session {
+            client.update(started.elapsed());
+        }
 
-        while stepper.tick() {
-            // TODO: Update
+        match phase {
+            Phase::Lobby => if session.is_ready() {
+                phase = Phase::Loading;
+            },
+            Phase::Loading => {
+                // The pings exchanged while waiting have measured the latency
+                if let Session::Networked { lockstep, .. } = &mut session {
+                    lockstep.set_input_delay(lockstep.recommended_input_delay(dt));
+                }
+                // Don't make up for the time spent waiting
+                stepper.advance(Instant::now());
+                stepper.stall();
+                phase = Phase::Playing;
+            }
+            Phase::Playing => {
+                stepper.advance(Instant::now());
+                while stepper.tick() {
+                    if !session.step() {
+                        stepper.stall();
+                        break;
+                    }
+                    if session.is_over() {
+                        print!("{}", Summary::new(session.world()));
+                        session.finish();
+                        phase = Phase::Ended;
+                        break;
+                    }
+                }
+            }
+            Phase::Ended => (),
         }
 
-        // TODO: Render
+        // Draw once all the events that came in together are dealt with
+        if let Event::MainEventsCleared = event {
+            let now = Instant::now();
+            let frame_time = (now - last_frame).as_secs_f32();
+            last_frame = now;
+
+            let world = session.world();
+            let player = session.local_player();
+            let time = started.elapsed().as_secs_f32();
+            render_sync.update(world, &mut renderer, &mut particles, player, time);
+            if let Some(layer) = overlay {
+                if influence.is_stale(world.tick) {
+                    influence.update(world);
+                    render_sync.set_overlay(influence.debug_instances(layer, &world.map.terrain), &mut renderer);
+                }
+            }
+            particles.update(frame_time, |unit| render_sync.position(unit));
+            // Zooming or resizing changes which level of detail things are
+            // drawn at, and panning which are in view at all
+            let view_proj = camera.view_projection();
+            renderer.set_zoom(camera.zoom);
+            renderer.set_view(view_proj);
+            renderer.upload_instances();
+            renderer.animate(time);
+
+            let (alpha, additive) = particles.instances(view_proj);
+            renderer.upload_particles(&alpha, &additive);
+            renderer.update_fog(world.fog.width(), world.fog.height(), &world.fog.texture_data(player));
+            let uniforms = renderer::Uniforms {
+                view_proj: view_proj.into(),
+                ..renderer::Uniforms::new()
+            }.with_fog(world.fog.width(), world.fog.height(), world.fog.cell_size());
+            match renderer.render(&uniforms) {
+                Ok(()) => {}
+                // Recreate the swap chain if lost
+                Err(wgpu::SwapChainError::Lost) => renderer.resize(window.inner_size()),
+                Err(wgpu::SwapChainError::OutOfMemory) => {
+                    eprintln!("Out of GPU memory");
+                    if phase != Phase::Ended {
+                        session.finish();
+                    }
+                    *control_flow = ControlFlow::Exit;
+                }
+                // The rest (outdated, timeout) sort themselves out by the next frame
+                Err(err) => eprintln!("{:?}", err),
+            }
+        }
     });
 }
 
+/// Pull the match rule options out of `args`, wherever they are:
+/// `--elimination <annihilation|headquarters>`, `--score-limit <score>`,
+/// `--time-limit <ticks>` and `--stats <stat file>`
+fn take_rules(args: &mut Vec<String>) -> MatchRules {
+    fn value<T: std::str::FromStr>(args: &mut Vec<String>, index: usize) -> T {
+        let flag = args.remove(index);
+        if index >= args.len() {
+            eprintln!("{} needs a value", flag);
+            std::process::exit(1);
+        }
+        let value = args.remove(index);
+        value.parse().unwrap_or_else(|_| {
+            eprintln!("Invalid value {} for {}", value, flag);
+            std::process::exit(1);
+        })
+    }
+
+    let mut rules = MatchRules::default();
+    let mut index = 0;
+    while index < args.len() {
+        match args[index].as_str() {
+            "--elimination" => rules.elimination = value(args, index),
+            "--score-limit" => rules.score_limit = Some(value(args, index)),
+            "--time-limit" => rules.time_limit = Some(value(args, index)),
+            "--stats" => {
+                let path: String = value(args, index);
+                rules.units = StatTable::load(&path).unwrap_or_else(|err| {
+                    eprintln!("{}: {}", path, err);
+                    std::process::exit(1);
+                });
+            }
+            _ => index += 1,
+        }
+    }
+    rules
+}
+
+/// Pull `--effects <effects file>` out of `args`, wherever it is. Without it
+/// the default effects are used.
+fn take_effects(args: &mut Vec<String>) -> EffectTable {
+    let index = match args.iter().position(|arg| arg == "--effects") {
+        Some(index) => index,
+        None => return EffectTable::default(),
+    };
+    args.remove(index);
+    if index >= args.len() {
+        eprintln!("--effects needs a value");
+        std::process::exit(1);
+    }
+    let path = args.remove(index);
+    EffectTable::load(&path).unwrap_or_else(|err| {
+        eprintln!("{}: {}", path, err);
+        std::process::exit(1);
+    })
+}
+
+/// Pull `--skins <worker,soldier,headquarters,barracks>` out of `args`,
+/// wherever it is: comma separated PNG or JPEG images to texture each unit
+/// kind with. Trailing kinds can be left out.
+fn take_skins(args: &mut Vec<String>) -> Vec<String> {
+    let index = match args.iter().position(|arg| arg == "--skins") {
+        Some(index) => index,
+        None => return Vec::new(),
+    };
+    args.remove(index);
+    if index >= args.len() {
+        eprintln!("--skins needs a value");
+        std::process::exit(1);
+    }
+    args.remove(index).split(',').map(String::from).collect()
+}
+
+/// Pull `--unit-model <model>` out of `args`, wherever it is: a glTF model
+/// to draw workers and soldiers skinned, or a `.vat` file from `bake_vat` to
+/// draw them as a crowd. Without it they're boxes.
+fn take_unit_model(args: &mut Vec<String>) -> UnitModel {
+    let index = match args.iter().position(|arg| arg == "--unit-model") {
+        Some(index) => index,
+        None => return UnitModel::Boxes,
+    };
+    args.remove(index);
+    if index >= args.len() {
+        eprintln!("--unit-model needs a value");
+        std::process::exit(1);
+    }
+    let path = std::path::PathBuf::from(args.remove(index));
+    let model = if path.extension().is_some_and(|extension| extension == "vat") {
+        vat::load(&path).map(UnitModel::Crowd).map_err(|err| err.to_string())
+    } else {
+        animation::SkinnedMesh::load(&path).map(UnitModel::Skinned).map_err(|err| err.to_string())
+    };
+    model.unwrap_or_else(|err| {
+        eprintln!("{}: {}", path.display(), err);
+        std::process::exit(1);
+    })
+}
+
+/// Start the mission script that goes with the map at `map_path`, if it has
+/// one, and store it in the replay. Script errors are reported and the match
+/// goes ahead without it.
+fn start_mission(map_path: &str, world: &mut World, recording: &mut Replay) -> Option<Mission> {
+    let path = script::script_path(map_path);
+    if !path.exists() {
+        return None;
+    }
+    let result = MissionScript::load(&path).and_then(|script| {
+        let mission = Mission::start(&script, world)?;
+        recording.script = Some(script);
+        Ok(mission)
+    });
+    match result {
+        Ok(mission) => Some(mission),
+        Err(err) => {
+            eprintln!("{}", err);
+            None
+        }
+    }
+}
+
+fn show_event(event: ScriptEvent) {
+    match event {
+        ScriptEvent::Message(text) => println!("{}", text),
+        ScriptEvent::Victory(player) => println!("Player {} is victorious", player),
+        ScriptEvent::Defeat(player) => println!("Player {} has been defeated", player),
+        ScriptEvent::Error(err) => eprintln!("{}", err),
+    }
+}
+
+/// Report a desync and write out our world as it was at the diverging tick.
+/// Every peer does the same, so the dumps can be compared with `--diff-saves`.
+fn dump_desync(desync: &Desync, local: sim::PlayerId, snapshots: &BTreeMap<u64, World>) {
+    eprintln!("Desync with player {} after tick {}: {:016x} != {:016x}", desync.player, desync.tick, desync.local, desync.remote);
+    let world = match snapshots.get(&desync.tick) {
+        Some(world) => world,
+        None => {
+            eprintln!("No snapshot kept for tick {}", desync.tick);
+            return;
+        }
+    };
+    let path = format!("desync-{}-player{}.sav", desync.tick, local);
+    match savegame::save(world, &[], &path) {
+        Ok(()) => eprintln!("State written to {}", path),
+        Err(err) => eprintln!("Failed to write {}: {}", path, err),
+    }
+}
+
 fn handle_event(event: &Event<()>) -> Option<ControlFlow> {
     match event {
         Event::WindowEvent {
-            event,
-            window_id,
-        } => match event {
-            WindowEvent::CloseRequested => Some(ControlFlow::Exit),
-            _ => None,
-        },
+            event: WindowEvent::CloseRequested,
+            ..
+        } => Some(ControlFlow::Exit),
+        _ => None,
+    }
+}
+
+/// The option after `current` in `all`, going round to the first after the last
+fn cycle<T: Copy + PartialEq>(all: &[T], current: T) -> T {
+    let index = all.iter().position(|&option| option == current).unwrap_or(0);
+    all[(index + 1) % all.len()]
+}
+
+/// Warn when the renderer couldn't do what was asked for
+fn report_fallback(requested: RenderSettings, applied: RenderSettings) {
+    if applied != requested {
+        eprintln!("{:?} isn't supported here, using {:?}", requested, applied);
+    }
+}
+
+fn pressed_key(event: &Event<()>) -> Option<VirtualKeyCode> {
+    match event {
+        Event::WindowEvent {
+            event: WindowEvent::KeyboardInput {
+                input: KeyboardInput {
+                    state: ElementState::Pressed,
+                    virtual_keycode: Some(key),
+                    ..
+                },
+                ..
+            },
+            ..
+        } => Some(*key),
         _ => None,
     }
 }
@@ -56,6 +779,9 @@ struct TimeStepper {
     current: Instant,
     dt: Duration,
     residual: Duration,
+    /// Simulated time per real time
+    speed: u32,
+    paused: bool,
 }
 
 impl TimeStepper {
@@ -64,11 +790,29 @@ impl TimeStepper {
             current: time,
             dt,
             residual: Duration::from_millis(0),
+            speed: 1,
+            paused: false,
         }
     }
 
+    fn set_speed(&mut self, speed: u32) {
+        self.speed = speed;
+    }
+
+    fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Drop the time built up while the simulation couldn't run, rather
+    /// than racing to catch up once it can
+    fn stall(&mut self) {
+        self.residual = Duration::from_millis(0);
+    }
+
     fn advance(&mut self, time: Instant) {
-        self.residual += time - self.current;
+        if !self.paused {
+            self.residual += (time - self.current) * self.speed;
+        }
         self.current = time;
     }
 
@@ -80,8 +824,4 @@ impl TimeStepper {
             false
         }
     }
-    
-    fn blend(&self) -> f32 {
-        self.residual.as_secs_f32() / self.dt.as_secs_f32()
-    }
 }
@@ -0,0 +1,137 @@
+use cgmath::Vector2;
+use serde::{Deserialize, Serialize};
+
+use super::sim::{PlayerId, UnitKind};
+use super::terrain::Heightmap;
+
+/// How a cell of the map can be traversed
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CellKind {
+    Ground,
+    Cliff,
+    Water,
+}
+
+impl CellKind {
+    pub fn is_passable(self) -> bool {
+        self == CellKind::Ground
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ResourceNode {
+    pub position: Vector2<f32>,
+    pub amount: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StartLocation {
+    pub player: PlayerId,
+    pub position: Vector2<f32>,
+}
+
+/// A unit or building that exists when the match begins
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct PlacedUnit {
+    pub kind: UnitKind,
+    pub owner: PlayerId,
+    pub position: Vector2<f32>,
+    pub rotation: f32,
+}
+
+/// Everything needed to set up a match before the first tick
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Map {
+    pub terrain: Heightmap,
+    /// One entry per terrain cell, row-major
+    pub cells: Vec<CellKind>,
+    pub resources: Vec<ResourceNode>,
+    pub starts: Vec<StartLocation>,
+    pub units: Vec<PlacedUnit>,
+}
+
+impl Map {
+    /// Number of cells along x and y
+    pub fn cells_size(&self) -> (u32, u32) {
+        (self.terrain.width() - 1, self.terrain.height() - 1)
+    }
+
+    /// Column and row of the cell containing `position`, or `None` off the
+    /// edge of the map
+    pub fn cell_coords(&self, position: Vector2<f32>) -> Option<(u32, u32)> {
+        let (width, height) = self.cells_size();
+        let x = (position.x / self.terrain.cell_size()).floor();
+        let y = (position.y / self.terrain.cell_size()).floor();
+        if x < 0. || y < 0. || x >= width as f32 || y >= height as f32 {
+            return None;
+        }
+        Some((x as u32, y as u32))
+    }
+
+    pub fn cell_center(&self, x: u32, y: u32) -> Vector2<f32> {
+        Vector2::new(x as f32 + 0.5, y as f32 + 0.5) * self.terrain.cell_size()
+    }
+
+    /// The cell containing `position`, or `None` off the edge of the map
+    pub fn cell_at(&self, position: Vector2<f32>) -> Option<CellKind> {
+        let (x, y) = self.cell_coords(position)?;
+        Some(self.cells[(y * self.cells_size().0 + x) as usize])
+    }
+
+    pub fn is_passable(&self, position: Vector2<f32>) -> bool {
+        self.cell_at(position).is_some_and(CellKind::is_passable)
+    }
+
+    /// Whether every cell the straight line from `from` to `to` crosses is passable
+    pub fn is_clear(&self, from: Vector2<f32>, to: Vector2<f32>) -> bool {
+        let (mut x, mut y) = match self.cell_coords(from) {
+            Some(cell) => cell,
+            None => return false,
+        };
+        let end = match self.cell_coords(to) {
+            Some(cell) => cell,
+            None => return false,
+        };
+        let (width, height) = self.cells_size();
+        let size = self.terrain.cell_size();
+        let delta = to - from;
+
+        // Walk the cells in the order the line enters them. `next` is how
+        // far along the line, from 0 to 1, it crosses the next column or
+        // row, and `across` how far it goes for each column or row.
+        let step_x = if delta.x > 0. { 1 } else { -1 };
+        let step_y = if delta.y > 0. { 1 } else { -1 };
+        let border = |cell: u32, step: i32| (cell as i32 + (step > 0) as i32) as f32 * size;
+        let (mut next_x, across_x) = if delta.x != 0. {
+            ((border(x, step_x) - from.x) / delta.x, size / delta.x.abs())
+        } else {
+            (f32::INFINITY, f32::INFINITY)
+        };
+        let (mut next_y, across_y) = if delta.y != 0. {
+            ((border(y, step_y) - from.y) / delta.y, size / delta.y.abs())
+        } else {
+            (f32::INFINITY, f32::INFINITY)
+        };
+        loop {
+            if !self.cells[(y * width + x) as usize].is_passable() {
+                return false;
+            }
+            if (x, y) == end {
+                return true;
+            }
+            let next = if next_x < next_y {
+                next_x += across_x;
+                (x as i32 + step_x, y as i32)
+            } else {
+                next_y += across_y;
+                (x as i32, y as i32 + step_y)
+            };
+            // Rounding can carry the walk past the end, but never forever
+            if next.0 < 0 || next.1 < 0 || next.0 >= width as i32 || next.1 >= height as i32 {
+                return false;
+            }
+            x = next.0 as u32;
+            y = next.1 as u32;
+        }
+    }
+}
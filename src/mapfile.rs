@@ -0,0 +1,204 @@
+//! On-disk map format
+//!
+//! Maps are stored in a `container` with magic `RTSM`. Older versions are read
+//! with their own payload type and migrated forward.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::container::{self, ContainerError};
+use super::map::{CellKind, Map, PlacedUnit, ResourceNode, StartLocation};
+use super::terrain::Heightmap;
+
+const MAGIC: [u8; 4] = *b"RTSM";
+pub const VERSION: u32 = 2;
+
+#[derive(Debug)]
+pub enum MapError {
+    /// Couldn't read a map file out of the container
+    File(ContainerError),
+    /// The payload decoded but describes an impossible map
+    Invalid(String),
+}
+
+impl fmt::Display for MapError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MapError::File(ContainerError::BadMagic) => write!(f, "not a map file"),
+            MapError::File(ContainerError::UnsupportedVersion(version)) => write!(f, "map format version {} is newer than supported version {}", version, VERSION),
+            MapError::File(err) => write!(f, "could not read map: {}", err),
+            MapError::Invalid(reason) => write!(f, "map is invalid: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for MapError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            MapError::File(err) => Some(err),
+            MapError::Invalid(_) => None,
+        }
+    }
+}
+
+impl From<ContainerError> for MapError {
+    fn from(err: ContainerError) -> Self {
+        MapError::File(err)
+    }
+}
+
+impl From<io::Error> for MapError {
+    fn from(err: io::Error) -> Self {
+        MapError::File(err.into())
+    }
+}
+
+impl From<bincode::Error> for MapError {
+    fn from(err: bincode::Error) -> Self {
+        MapError::File(err.into())
+    }
+}
+
+/// Version 1: no pre-placed units
+#[derive(Serialize, Deserialize)]
+struct MapFileV1 {
+    width: u32,
+    height: u32,
+    cell_size: f32,
+    heights: Vec<f32>,
+    cells: Vec<CellKind>,
+    resources: Vec<ResourceNode>,
+    starts: Vec<StartLocation>,
+}
+
+/// Version 2: current
+#[derive(Serialize, Deserialize)]
+struct MapFileV2 {
+    width: u32,
+    height: u32,
+    cell_size: f32,
+    heights: Vec<f32>,
+    cells: Vec<CellKind>,
+    resources: Vec<ResourceNode>,
+    starts: Vec<StartLocation>,
+    units: Vec<PlacedUnit>,
+}
+
+impl From<MapFileV1> for MapFileV2 {
+    fn from(v1: MapFileV1) -> Self {
+        Self {
+            width: v1.width,
+            height: v1.height,
+            cell_size: v1.cell_size,
+            heights: v1.heights,
+            cells: v1.cells,
+            resources: v1.resources,
+            starts: v1.starts,
+            units: Vec::new(),
+        }
+    }
+}
+
+pub fn save(map: &Map, path: impl AsRef<Path>) -> Result<(), MapError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(map, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<Map, MapError> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn write(map: &Map, writer: &mut impl Write) -> Result<(), MapError> {
+    let file = MapFileV2 {
+        width: map.terrain.width(),
+        height: map.terrain.height(),
+        cell_size: map.terrain.cell_size(),
+        heights: map.terrain.heights().to_vec(),
+        cells: map.cells.clone(),
+        resources: map.resources.clone(),
+        starts: map.starts.clone(),
+        units: map.units.clone(),
+    };
+    let payload = bincode::serialize(&file).expect("Map serialization can't fail");
+    container::write(writer, MAGIC, VERSION, &payload)?;
+    Ok(())
+}
+
+pub fn read(reader: &mut impl Read) -> Result<Map, MapError> {
+    let (version, payload) = container::read(reader, MAGIC, VERSION)?;
+    let file: MapFileV2 = match version {
+        1 => bincode::deserialize::<MapFileV1>(&payload)?.into(),
+        _ => bincode::deserialize(&payload)?,
+    };
+
+    validate(&file)?;
+
+    Ok(Map {
+        terrain: Heightmap::new(file.width, file.height, file.cell_size, file.heights),
+        cells: file.cells,
+        resources: file.resources,
+        starts: file.starts,
+        units: file.units,
+    })
+}
+
+fn validate(file: &MapFileV2) -> Result<(), MapError> {
+    let invalid = |reason: String| Err(MapError::Invalid(reason));
+
+    if file.width < 2 || file.height < 2 {
+        return invalid(format!("terrain is {}x{}, needs at least 2x2", file.width, file.height));
+    }
+    if file.cell_size.is_nan() || file.cell_size <= 0. {
+        return invalid(format!("cell size {} is not positive", file.cell_size));
+    }
+    let points = file.width as usize * file.height as usize;
+    if file.heights.len() != points {
+        return invalid(format!("expected {} heights, found {}", points, file.heights.len()));
+    }
+    if file.heights.iter().any(|height| !height.is_finite()) {
+        return invalid("terrain has non-finite heights".to_string());
+    }
+    let cells = (file.width - 1) as usize * (file.height - 1) as usize;
+    if file.cells.len() != cells {
+        return invalid(format!("expected {} nav cells, found {}", cells, file.cells.len()));
+    }
+    if file.starts.is_empty() {
+        return invalid("map has no start locations".to_string());
+    }
+
+    let extent_x = (file.width - 1) as f32 * file.cell_size;
+    let extent_y = (file.height - 1) as f32 * file.cell_size;
+    let on_map = |position: cgmath::Vector2<f32>| {
+        position.x >= 0. && position.y >= 0. && position.x <= extent_x && position.y <= extent_y
+    };
+
+    for (i, start) in file.starts.iter().enumerate() {
+        if start.player as usize != i {
+            return invalid(format!("start location {} belongs to player {}", i, start.player));
+        }
+        if !on_map(start.position) {
+            return invalid(format!("start location for player {} is off the map", start.player));
+        }
+    }
+    for (i, resource) in file.resources.iter().enumerate() {
+        if !on_map(resource.position) {
+            return invalid(format!("resource node {} is off the map", i));
+        }
+    }
+    for (i, unit) in file.units.iter().enumerate() {
+        if !on_map(unit.position) {
+            return invalid(format!("placed unit {} is off the map", i));
+        }
+        if unit.owner as usize >= file.starts.len() {
+            return invalid(format!("placed unit {} belongs to player {} but the map has {} players", i, unit.owner, file.starts.len()));
+        }
+    }
+
+    Ok(())
+}
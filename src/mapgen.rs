@@ -0,0 +1,155 @@
+use cgmath::{InnerSpace, Vector2};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::map::{CellKind, Map, ResourceNode, StartLocation};
+use super::math::sin_cos;
+use super::sim::PlayerId;
+use super::terrain::Heightmap;
+
+/// Inputs to the generator. The same parameters always produce the same map.
+#[derive(Copy, Clone, Debug)]
+pub struct MapGenParams {
+    pub seed: u64,
+    /// Width and height of the map in cells
+    pub size: u32,
+    pub players: u8,
+}
+
+const CELL_SIZE: f32 = 1.;
+const MAX_HEIGHT: f32 = 12.;
+/// Anything below this fraction of `MAX_HEIGHT` is flooded
+const WATER_LEVEL: f32 = 0.25;
+/// Rise over one cell above which the ground is too steep to walk
+const CLIFF_SLOPE: f32 = 0.8;
+/// Radius of the flattened, buildable area around each start
+const BASE_RADIUS: f32 = 8.;
+const RESOURCE_AMOUNT: u32 = 1500;
+const NODES_PER_CLUSTER: u32 = 6;
+const EXPANSIONS_PER_PLAYER: u32 = 2;
+
+pub fn generate(params: &MapGenParams) -> Map {
+    assert!(params.players > 0, "A map needs at least one player");
+
+    let mut rng = ChaCha8Rng::seed_from_u64(params.seed);
+    let size = params.size;
+    let extent = size as f32 * CELL_SIZE;
+    let center = Vector2::new(extent, extent) * 0.5;
+
+    let mut terrain = Heightmap::generate(size + 1, size + 1, CELL_SIZE, MAX_HEIGHT, rng.gen());
+
+    // Starts sit evenly around the middle so every player gets the same distances to
+    // each other and to the center
+    let angle_offset = rng.gen_range(0. ..std::f32::consts::PI * 2.);
+    let slice = std::f32::consts::PI * 2. / params.players as f32;
+    let start_radius = extent * 0.35;
+    let starts: Vec<_> = (0..params.players).map(|player| {
+        let angle = angle_offset + slice * player as f32;
+        StartLocation {
+            player: player as PlayerId,
+            position: center + direction(angle) * start_radius,
+        }
+    }).collect();
+
+    for start in &starts {
+        flatten(&mut terrain, start.position, BASE_RADIUS);
+    }
+
+    // Each player's main mineral line sits just behind their start, away from the center
+    let mut cluster_centers = Vec::new();
+    for start in &starts {
+        let outward = (start.position - center).normalize();
+        cluster_centers.push(start.position + outward * (BASE_RADIUS * 0.75));
+    }
+
+    // Expansions are placed within one slice and rotated into every other, keeping them fair
+    for _ in 0..EXPANSIONS_PER_PLAYER {
+        let angle = angle_offset + rng.gen_range(0. ..slice);
+        let radius = rng.gen_range(0.1..0.45) * extent;
+        for player in 0..params.players {
+            let angle = angle + slice * player as f32;
+            let position = center + direction(angle) * radius;
+            flatten(&mut terrain, position, BASE_RADIUS * 0.5);
+            cluster_centers.push(position);
+        }
+    }
+
+    let mut resources = Vec::new();
+    for &cluster in &cluster_centers {
+        // Same layout in every cluster so the mining distance is identical
+        let outward = (cluster - center).normalize();
+        for node in 0..NODES_PER_CLUSTER {
+            let (sin, cos) = sin_cos((node as f32 - NODES_PER_CLUSTER as f32 * 0.5) * 0.35);
+            let offset = Vector2::new(outward.x * cos - outward.y * sin, outward.x * sin + outward.y * cos);
+            resources.push(ResourceNode {
+                position: cluster + offset * 2.,
+                amount: RESOURCE_AMOUNT,
+            });
+        }
+    }
+
+    let cells = classify(&terrain);
+
+    Map {
+        terrain,
+        cells,
+        resources,
+        starts,
+        units: Vec::new(),
+    }
+}
+
+/// Unit vector at `angle` radians. Peers generate maps independently, so this
+/// has to give the same result everywhere.
+fn direction(angle: f32) -> Vector2<f32> {
+    let (sin, cos) = sin_cos(angle);
+    Vector2::new(cos, sin)
+}
+
+/// Level the ground in a circle to the height at its center, lifting it out of any water
+fn flatten(terrain: &mut Heightmap, center: Vector2<f32>, radius: f32) {
+    let level = terrain.height_at(center).max(MAX_HEIGHT * WATER_LEVEL + 0.5);
+    let (width, height, cell_size) = (terrain.width() as i32, terrain.height() as i32, terrain.cell_size());
+    let heights = terrain.heights_mut();
+
+    // Blend back into the surrounding ground over the outer rim
+    let rim = radius * 1.5;
+    for y in 0..height {
+        for x in 0..width {
+            let point = Vector2::new(x as f32, y as f32) * cell_size;
+            let distance = (point - center).magnitude();
+            if distance >= rim {
+                continue;
+            }
+            let t = ((distance - radius) / (rim - radius)).max(0.);
+            let h = &mut heights[(y * width + x) as usize];
+            *h = level + (*h - level) * t;
+        }
+    }
+}
+
+fn classify(terrain: &Heightmap) -> Vec<CellKind> {
+    let (width, height) = (terrain.width() as i32 - 1, terrain.height() as i32 - 1);
+    let water = MAX_HEIGHT * WATER_LEVEL;
+    let mut cells = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let corners = [
+                terrain.sample(x, y),
+                terrain.sample(x + 1, y),
+                terrain.sample(x, y + 1),
+                terrain.sample(x + 1, y + 1),
+            ];
+            let lowest = corners.iter().cloned().fold(f32::INFINITY, f32::min);
+            let highest = corners.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+            cells.push(if lowest < water {
+                CellKind::Water
+            } else if (highest - lowest) / terrain.cell_size() > CLIFF_SLOPE {
+                CellKind::Cliff
+            } else {
+                CellKind::Ground
+            });
+        }
+    }
+    cells
+}
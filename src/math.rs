@@ -0,0 +1,118 @@
+use std::ops::{Add, AddAssign, Div, Mul, Neg, Sub, SubAssign};
+
+use serde::{Deserialize, Serialize};
+
+/// Number type for simulation positions and velocities. `f32` is bit-for-bit
+/// reproducible for a given build; the `fixed-point` feature swaps in
+/// `Fixed` for results that also match across platforms and compilers.
+#[cfg(not(feature = "fixed-point"))]
+pub type Real = f32;
+#[cfg(feature = "fixed-point")]
+pub type Real = super::fixed::Fixed;
+
+pub trait Scalar: Copy + PartialOrd + Add<Output = Self> + Sub<Output = Self> + Mul<Output = Self> + Div<Output = Self> + Neg<Output = Self> + AddAssign + SubAssign {
+    const ZERO: Self;
+    fn from_f32(value: f32) -> Self;
+    fn to_f32(self) -> f32;
+    fn sqrt(self) -> Self;
+    /// Exact bit pattern, for state hashing
+    fn hash_bits(self) -> u64;
+}
+
+impl Scalar for f32 {
+    const ZERO: Self = 0.;
+
+    fn from_f32(value: f32) -> Self {
+        value
+    }
+
+    fn to_f32(self) -> f32 {
+        self
+    }
+
+    fn sqrt(self) -> Self {
+        f32::sqrt(self)
+    }
+
+    fn hash_bits(self) -> u64 {
+        self.to_bits() as u64
+    }
+}
+
+/// A point or offset on the ground plane in simulation space
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Vec2 {
+    pub x: Real,
+    pub y: Real,
+}
+
+impl Vec2 {
+    pub fn new(x: Real, y: Real) -> Self {
+        Self { x, y }
+    }
+
+    pub fn from_f32(v: cgmath::Vector2<f32>) -> Self {
+        Self::new(Real::from_f32(v.x), Real::from_f32(v.y))
+    }
+
+    /// For rendering and grid lookups, which don't feed back into the simulation
+    pub fn to_f32(self) -> cgmath::Vector2<f32> {
+        cgmath::Vector2::new(self.x.to_f32(), self.y.to_f32())
+    }
+
+    pub fn magnitude2(self) -> Real {
+        self.x * self.x + self.y * self.y
+    }
+
+    pub fn magnitude(self) -> Real {
+        self.magnitude2().sqrt()
+    }
+}
+
+impl Add for Vec2 {
+    type Output = Vec2;
+    fn add(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x + other.x, self.y + other.y)
+    }
+}
+
+impl Sub for Vec2 {
+    type Output = Vec2;
+    fn sub(self, other: Vec2) -> Vec2 {
+        Vec2::new(self.x - other.x, self.y - other.y)
+    }
+}
+
+impl Mul<Real> for Vec2 {
+    type Output = Vec2;
+    fn mul(self, scale: Real) -> Vec2 {
+        Vec2::new(self.x * scale, self.y * scale)
+    }
+}
+
+impl Div<Real> for Vec2 {
+    type Output = Vec2;
+    fn div(self, scale: Real) -> Vec2 {
+        Vec2::new(self.x / scale, self.y / scale)
+    }
+}
+
+/// Sine and cosine of `angle` in radians from arithmetic alone, so they come
+/// out the same on every platform, unlike `f32::sin_cos`. Good to about 1e-6.
+pub fn sin_cos(angle: f32) -> (f32, f32) {
+    use std::f32::consts::{FRAC_PI_2, PI};
+    // Into -π..π, then folded into -π/2..π/2 where the series converge quickly
+    let mut x = angle - (angle / (2. * PI)).round() * 2. * PI;
+    let mut cos_sign = 1.;
+    if x > FRAC_PI_2 {
+        x = PI - x;
+        cos_sign = -1.;
+    } else if x < -FRAC_PI_2 {
+        x = -PI - x;
+        cos_sign = -1.;
+    }
+    let x2 = x * x;
+    let sin = x * (1. - x2 / 6. * (1. - x2 / 20. * (1. - x2 / 42. * (1. - x2 / 72. * (1. - x2 / 110.)))));
+    let cos = 1. - x2 / 2. * (1. - x2 / 12. * (1. - x2 / 30. * (1. - x2 / 56. * (1. - x2 / 90. * (1. - x2 / 132.)))));
+    (sin, cos * cos_sign)
+}
@@ -1,3 +1,4 @@
+use cgmath::{InnerSpace, Vector3};
 use wgpu::{
     vertex_attr_array,
     util::DeviceExt,
@@ -10,13 +11,16 @@ pub trait VertexDesc {
 #[repr(C)]
 #[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct MeshVertex {
-    position: [f32; 3],
-    normal: [f32; 3],
+    pub position: [f32; 3],
+    pub normal: [f32; 3],
+    /// Where on the material's albedo texture this vertex samples
+    pub tex_coords: [f32; 2],
 }
 
-const MESH_VERTEX_ATTRS: [wgpu::VertexAttribute; 2] = vertex_attr_array![
+const MESH_VERTEX_ATTRS: [wgpu::VertexAttribute; 3] = vertex_attr_array![
     0 => Float32x3,
     1 => Float32x3,
+    2 => Float32x2,
 ];
 
 impl VertexDesc for MeshVertex {
@@ -34,6 +38,8 @@ pub struct Mesh {
     pub vertex_buffer: wgpu::Buffer,
     pub index_buffer: wgpu::Buffer,
     pub num_elements: u32,
+    /// Distance from the origin to the furthest vertex
+    pub bounding_radius: f32,
 }
 
 impl Mesh {
@@ -42,35 +48,35 @@ impl Mesh {
     ) -> Self {
         const VERTICES: &[MeshVertex] = &[
             // top (0, 0, 1)
-            MeshVertex { position: [-1., -1., 1.], normal: [0., 0., 1.] },
-            MeshVertex { position: [1., -1., 1.], normal: [0., 0., 1.] },
-            MeshVertex { position: [1., 1., 1.], normal: [0., 0., 1.] },
-            MeshVertex { position: [-1., 1., 1.], normal: [0., 0., 1.] },
+            MeshVertex { position: [-1., -1., 1.], normal: [0., 0., 1.], tex_coords: [0., 0.] },
+            MeshVertex { position: [1., -1., 1.], normal: [0., 0., 1.], tex_coords: [1., 0.] },
+            MeshVertex { position: [1., 1., 1.], normal: [0., 0., 1.], tex_coords: [1., 1.] },
+            MeshVertex { position: [-1., 1., 1.], normal: [0., 0., 1.], tex_coords: [0., 1.] },
             // bottom (0, 0, -1.)
-            MeshVertex { position: [-1., 1., -1.], normal: [0., 0., -1.] },
-            MeshVertex { position: [1., 1., -1.], normal: [0., 0., -1.] },
-            MeshVertex { position: [1., -1., -1.], normal: [0., 0., -1.] },
-            MeshVertex { position: [-1., -1., -1.], normal: [0., 0., -1.] },
+            MeshVertex { position: [-1., 1., -1.], normal: [0., 0., -1.], tex_coords: [0., 0.] },
+            MeshVertex { position: [1., 1., -1.], normal: [0., 0., -1.], tex_coords: [1., 0.] },
+            MeshVertex { position: [1., -1., -1.], normal: [0., 0., -1.], tex_coords: [1., 1.] },
+            MeshVertex { position: [-1., -1., -1.], normal: [0., 0., -1.], tex_coords: [0., 1.] },
             // right (1., 0, 0)
-            MeshVertex { position: [1., -1., -1.], normal: [1., 0., 0.] },
-            MeshVertex { position: [1., 1., -1.], normal: [1., 0., 0.] },
-            MeshVertex { position: [1., 1., 1.], normal: [1., 0., 0.] },
-            MeshVertex { position: [1., -1., 1.], normal: [1., 0., 0.] },
+            MeshVertex { position: [1., -1., -1.], normal: [1., 0., 0.], tex_coords: [0., 0.] },
+            MeshVertex { position: [1., 1., -1.], normal: [1., 0., 0.], tex_coords: [1., 0.] },
+            MeshVertex { position: [1., 1., 1.], normal: [1., 0., 0.], tex_coords: [1., 1.] },
+            MeshVertex { position: [1., -1., 1.], normal: [1., 0., 0.], tex_coords: [0., 1.] },
             // left (-1., 0, 0)
-            MeshVertex { position: [-1., -1., 1.], normal: [-1., 0., 0.] },
-            MeshVertex { position: [-1., 1., 1.], normal: [-1., 0., 0.] },
-            MeshVertex { position: [-1., 1., -1.], normal: [-1., 0., 0.] },
-            MeshVertex { position: [-1., -1., -1.], normal: [-1., 0., 0.] },
+            MeshVertex { position: [-1., -1., 1.], normal: [-1., 0., 0.], tex_coords: [0., 0.] },
+            MeshVertex { position: [-1., 1., 1.], normal: [-1., 0., 0.], tex_coords: [1., 0.] },
+            MeshVertex { position: [-1., 1., -1.], normal: [-1., 0., 0.], tex_coords: [1., 1.] },
+            MeshVertex { position: [-1., -1., -1.], normal: [-1., 0., 0.], tex_coords: [0., 1.] },
             // front (0, 1., 0)
-            MeshVertex { position: [1., 1., -1.], normal: [0., 1., 0.] },
-            MeshVertex { position: [-1., 1., -1.], normal: [0., 1., 0.] },
-            MeshVertex { position: [-1., 1., 1.], normal: [0., 1., 0.] },
-            MeshVertex { position: [1., 1., 1.], normal: [0., 1., 0.] },
+            MeshVertex { position: [1., 1., -1.], normal: [0., 1., 0.], tex_coords: [0., 0.] },
+            MeshVertex { position: [-1., 1., -1.], normal: [0., 1., 0.], tex_coords: [1., 0.] },
+            MeshVertex { position: [-1., 1., 1.], normal: [0., 1., 0.], tex_coords: [1., 1.] },
+            MeshVertex { position: [1., 1., 1.], normal: [0., 1., 0.], tex_coords: [0., 1.] },
             // back (0, -1., 0)
-            MeshVertex { position: [1., -1., 1.], normal: [0., -1., 0.] },
-            MeshVertex { position: [-1., -1., 1.], normal: [0., -1., 0.] },
-            MeshVertex { position: [-1., -1., -1.], normal: [0., -1., 0.] },
-            MeshVertex { position: [1., -1., -1.], normal: [0., -1., 0.] },
+            MeshVertex { position: [1., -1., 1.], normal: [0., -1., 0.], tex_coords: [0., 0.] },
+            MeshVertex { position: [-1., -1., 1.], normal: [0., -1., 0.], tex_coords: [1., 0.] },
+            MeshVertex { position: [-1., -1., -1.], normal: [0., -1., 0.], tex_coords: [1., 1.] },
+            MeshVertex { position: [1., -1., -1.], normal: [0., -1., 0.], tex_coords: [0., 1.] },
         ];
 
         const INDICES: &[u16] = &[
@@ -102,19 +108,29 @@ impl Mesh {
             vertex_buffer,
             index_buffer,
             num_elements: INDICES.len() as u32,
+            bounding_radius: bounding_radius(VERTICES),
         }
     }
 }
 
+fn bounding_radius(vertices: &[MeshVertex]) -> f32 {
+    vertices.iter()
+        .map(|vertex| Vector3::from(vertex.position).magnitude())
+        .fold(0., f32::max)
+}
+
 #[repr(C)]
 #[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct ModelInstance {
     pub model: [[f32; 4]; 4],
     pub normal: [[f32; 3]; 3],
+    /// Team color, tinting where the skin's mask allows
     pub color: [f32; 3],
+    /// Layer of the skin array to texture this instance with. 0 is untextured.
+    pub layer: u32,
 }
 
-const INSTANCE_RAW_ATTRS: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
+const INSTANCE_RAW_ATTRS: [wgpu::VertexAttribute; 9] = wgpu::vertex_attr_array![
     5 => Float32x4,
     6 => Float32x4,
     7 => Float32x4,
@@ -123,8 +139,21 @@ const INSTANCE_RAW_ATTRS: [wgpu::VertexAttribute; 8] = wgpu::vertex_attr_array![
     10 => Float32x3,
     11 => Float32x3,
     12 => Float32x3,
+    13 => Uint32,
 ];
 
+impl ModelInstance {
+    /// Center and radius of a sphere around this instance of a mesh that
+    /// fits within `mesh_radius` of its origin
+    pub fn bounding_sphere(&self, mesh_radius: f32) -> (Vector3<f32>, f32) {
+        let [x, y, z, translation] = self.model;
+        let scale = [x, y, z].iter()
+            .map(|axis| Vector3::new(axis[0], axis[1], axis[2]).magnitude())
+            .fold(0., f32::max);
+        (Vector3::new(translation[0], translation[1], translation[2]), mesh_radius * scale)
+    }
+}
+
 impl VertexDesc for ModelInstance {
     fn desc() -> wgpu::VertexBufferLayout<'static> {
         use std::mem;
@@ -0,0 +1,402 @@
+//! Particle effects: muzzle flashes, explosions, dust and smoke
+//!
+//! How each effect looks is data. An effects file is JSON with an
+//! `EmitterDef` per effect, like `{ "explosion": { "burst": 60, "speed":
+//! [2, 6], "color": [1, 0.6, 0.2, 1], "blend": "additive" } }`, and anything
+//! left out keeps its default.
+//!
+//! Emitters are either placed in the world or attached to an entity, which
+//! they follow until it's gone. Particles are simulated here and drawn as
+//! camera-facing quads, one instance each, in a single draw per blend mode
+//! after everything opaque.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufReader};
+use std::path::Path;
+
+use cgmath::{Matrix4, Vector3, Vector4, VectorSpace};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+use wgpu::vertex_attr_array;
+
+use super::ecs::{Entities, Entity, Storage};
+use super::model::VertexDesc;
+
+/// Particles alive at once, beyond which emitters hold off
+const MAX_PARTICLES: usize = 20_000;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Blend {
+    /// Drawn over what's behind by their alpha, furthest first
+    Alpha,
+    /// Added to what's behind, so they glow and order doesn't matter
+    Additive,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EmitterDef {
+    /// Particles released as soon as the emitter starts
+    pub burst: u32,
+    /// Particles a second after that
+    pub rate: f32,
+    /// Seconds the emitter runs for. `None` runs until it's stopped or what
+    /// it's attached to is gone.
+    pub duration: Option<f32>,
+    /// Seconds each particle lives, between the two at random
+    pub lifetime: [f32; 2],
+    /// Starting speed, between the two at random
+    pub speed: [f32; 2],
+    /// Most degrees from straight up particles set off at. 180 is any direction.
+    pub spread: f32,
+    /// Downwards acceleration. Negative rises, like smoke.
+    pub gravity: f32,
+    /// Fraction of its speed a particle loses each second
+    pub drag: f32,
+    /// Width at birth and at death
+    pub size: [f32; 2],
+    /// Color and opacity at birth
+    pub color: [f32; 4],
+    /// Color and opacity at death
+    pub end_color: [f32; 4],
+    pub blend: Blend,
+    /// Where particles start relative to the emitter
+    pub offset: [f32; 3],
+}
+
+impl Default for EmitterDef {
+    fn default() -> Self {
+        Self {
+            burst: 0,
+            rate: 0.,
+            duration: Some(0.),
+            lifetime: [1., 1.],
+            speed: [1., 1.],
+            spread: 180.,
+            gravity: 0.,
+            drag: 0.,
+            size: [0.5, 0.5],
+            color: [1., 1., 1., 1.],
+            end_color: [1., 1., 1., 0.],
+            blend: Blend::Alpha,
+            offset: [0., 0., 0.],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Effect {
+    MuzzleFlash,
+    Explosion,
+    /// Kicked up by units on the move
+    Dust,
+    /// Rising from damaged buildings
+    Smoke,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EffectTable {
+    pub muzzle_flash: EmitterDef,
+    pub explosion: EmitterDef,
+    pub dust: EmitterDef,
+    pub smoke: EmitterDef,
+}
+
+impl Default for EffectTable {
+    fn default() -> Self {
+        Self {
+            muzzle_flash: EmitterDef {
+                burst: 8,
+                lifetime: [0.05, 0.12],
+                speed: [1., 3.],
+                spread: 60.,
+                size: [0.4, 0.1],
+                color: [1., 0.9, 0.5, 1.],
+                end_color: [1., 0.4, 0.1, 0.],
+                blend: Blend::Additive,
+                offset: [0., 0.5, 0.],
+                ..EmitterDef::default()
+            },
+            explosion: EmitterDef {
+                burst: 60,
+                lifetime: [0.4, 1.],
+                speed: [2., 6.],
+                spread: 100.,
+                gravity: 4.,
+                drag: 1.5,
+                size: [0.8, 1.6],
+                color: [1., 0.7, 0.3, 1.],
+                end_color: [0.4, 0.1, 0.05, 0.],
+                blend: Blend::Additive,
+                offset: [0., 0.5, 0.],
+                ..EmitterDef::default()
+            },
+            dust: EmitterDef {
+                rate: 6.,
+                duration: None,
+                lifetime: [0.6, 1.2],
+                speed: [0.2, 0.6],
+                spread: 70.,
+                gravity: 0.3,
+                drag: 1.,
+                size: [0.3, 0.9],
+                color: [0.6, 0.5, 0.4, 0.5],
+                end_color: [0.6, 0.5, 0.4, 0.],
+                ..EmitterDef::default()
+            },
+            smoke: EmitterDef {
+                rate: 4.,
+                duration: None,
+                lifetime: [2., 3.5],
+                speed: [0.3, 0.6],
+                spread: 15.,
+                gravity: -0.4,
+                drag: 0.5,
+                size: [0.8, 2.5],
+                color: [0.2, 0.2, 0.2, 0.7],
+                end_color: [0.35, 0.35, 0.35, 0.],
+                offset: [0., 1.5, 0.],
+                ..EmitterDef::default()
+            },
+        }
+    }
+}
+
+impl EffectTable {
+    pub fn get(&self, effect: Effect) -> &EmitterDef {
+        match effect {
+            Effect::MuzzleFlash => &self.muzzle_flash,
+            Effect::Explosion => &self.explosion,
+            Effect::Dust => &self.dust,
+            Effect::Smoke => &self.smoke,
+        }
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, EffectFileError> {
+        let reader = BufReader::new(File::open(path)?);
+        Ok(serde_json::from_reader(reader)?)
+    }
+}
+
+#[derive(Debug)]
+pub enum EffectFileError {
+    Io(io::Error),
+    Parse(serde_json::Error),
+}
+
+impl fmt::Display for EffectFileError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EffectFileError::Io(err) => write!(f, "{}", err),
+            EffectFileError::Parse(err) => write!(f, "invalid effects file: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for EffectFileError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            EffectFileError::Io(err) => Some(err),
+            EffectFileError::Parse(err) => Some(err),
+        }
+    }
+}
+
+impl From<io::Error> for EffectFileError {
+    fn from(err: io::Error) -> Self {
+        EffectFileError::Io(err)
+    }
+}
+
+impl From<serde_json::Error> for EffectFileError {
+    fn from(err: serde_json::Error) -> Self {
+        EffectFileError::Parse(err)
+    }
+}
+
+/// One particle as the shader draws it
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct ParticleInstance {
+    pub position: [f32; 3],
+    pub size: f32,
+    pub color: [f32; 4],
+}
+
+const PARTICLE_INSTANCE_ATTRS: [wgpu::VertexAttribute; 3] = vertex_attr_array![
+    0 => Float32x3,
+    1 => Float32,
+    2 => Float32x4,
+];
+
+impl VertexDesc for ParticleInstance {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<ParticleInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &PARTICLE_INSTANCE_ATTRS,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct EmitterHandle(Entity);
+
+#[derive(Copy, Clone, Debug)]
+enum Anchor {
+    Fixed(Vector3<f32>),
+    Attached(Entity),
+}
+
+struct Emitter {
+    effect: Effect,
+    anchor: Anchor,
+    /// Seconds since it started. Negative until its first update.
+    age: f32,
+    /// Particles owed by `rate` but not yet released
+    owed: f32,
+}
+
+struct Particle {
+    effect: Effect,
+    position: Vector3<f32>,
+    velocity: Vector3<f32>,
+    age: f32,
+    lifetime: f32,
+}
+
+pub struct ParticleSystem {
+    effects: EffectTable,
+    keys: Entities,
+    emitters: Storage<Emitter>,
+    particles: Vec<Particle>,
+    /// Only for looks, so it doesn't need to match between players
+    rng: ChaCha8Rng,
+}
+
+impl ParticleSystem {
+    pub fn new(effects: EffectTable) -> Self {
+        Self {
+            effects,
+            keys: Entities::default(),
+            emitters: Storage::new(),
+            particles: Vec::new(),
+            rng: ChaCha8Rng::seed_from_u64(0),
+        }
+    }
+
+    /// Start `effect` at a fixed point
+    pub fn spawn(&mut self, effect: Effect, position: Vector3<f32>) -> EmitterHandle {
+        self.add(effect, Anchor::Fixed(position))
+    }
+
+    /// Start `effect` on `entity`, following wherever `update` finds it
+    pub fn attach(&mut self, effect: Effect, entity: Entity) -> EmitterHandle {
+        self.add(effect, Anchor::Attached(entity))
+    }
+
+    /// End an emitter early. Its particles live out their lives.
+    pub fn stop(&mut self, handle: EmitterHandle) {
+        self.emitters.remove(handle.0);
+    }
+
+    fn add(&mut self, effect: Effect, anchor: Anchor) -> EmitterHandle {
+        let key = self.keys.create();
+        self.emitters.insert(key, Emitter { effect, anchor, age: -1., owed: 0. });
+        EmitterHandle(key)
+    }
+
+    /// Move particles on by `dt` seconds and have emitters release new
+    /// ones. `position_of` says where an entity is now, or `None` once it's
+    /// gone, which ends the emitters attached to it.
+    pub fn update(&mut self, dt: f32, position_of: impl Fn(Entity) -> Option<Vector3<f32>>) {
+        let effects = &self.effects;
+        self.particles.retain(|particle| particle.age + dt < particle.lifetime);
+        for particle in &mut self.particles {
+            let def = effects.get(particle.effect);
+            particle.velocity.y -= def.gravity * dt;
+            particle.velocity *= (1. - def.drag * dt).max(0.);
+            particle.position += particle.velocity * dt;
+            particle.age += dt;
+        }
+
+        let (particles, rng) = (&mut self.particles, &mut self.rng);
+        self.emitters.retain(|_, emitter| {
+            let position = match emitter.anchor {
+                Anchor::Fixed(position) => position,
+                Anchor::Attached(entity) => match position_of(entity) {
+                    Some(position) => position,
+                    None => return false,
+                },
+            };
+            let def = effects.get(emitter.effect);
+            let mut count = 0;
+            if emitter.age < 0. {
+                emitter.age = 0.;
+                count += def.burst as usize;
+            } else {
+                emitter.age += dt;
+                emitter.owed += def.rate * dt;
+                count += emitter.owed as usize;
+                emitter.owed = emitter.owed.fract();
+            }
+            for _ in 0..count.min(MAX_PARTICLES.saturating_sub(particles.len())) {
+                particles.push(emit(emitter.effect, def, position, rng));
+            }
+            def.duration.is_none_or(|duration| emitter.age < duration)
+        });
+    }
+
+    /// Every particle as drawn from the camera at `view_proj`, split by
+    /// blend mode. Alpha blended ones are sorted furthest first.
+    pub fn instances(&self, view_proj: Matrix4<f32>) -> (Vec<ParticleInstance>, Vec<ParticleInstance>) {
+        let mut alpha = Vec::new();
+        let mut additive = Vec::new();
+        for particle in &self.particles {
+            let def = self.effects.get(particle.effect);
+            let t = particle.age / particle.lifetime;
+            let instance = ParticleInstance {
+                position: particle.position.into(),
+                size: def.size[0] + (def.size[1] - def.size[0]) * t,
+                color: Vector4::from(def.color).lerp(Vector4::from(def.end_color), t).into(),
+            };
+            match def.blend {
+                Blend::Alpha => alpha.push(instance),
+                Blend::Additive => additive.push(instance),
+            }
+        }
+
+        let depth = |instance: &ParticleInstance| {
+            let clip = view_proj * Vector3::from(instance.position).extend(1.);
+            clip.z / clip.w
+        };
+        alpha.sort_by(|a, b| depth(b).partial_cmp(&depth(a)).unwrap_or(std::cmp::Ordering::Equal));
+        (alpha, additive)
+    }
+}
+
+fn emit(effect: Effect, def: &EmitterDef, position: Vector3<f32>, rng: &mut ChaCha8Rng) -> Particle {
+    let tilt = rng.gen_range(0.0..=def.spread.max(0.)).to_radians();
+    let heading = rng.gen_range(0.0..std::f32::consts::PI * 2.);
+    let direction = Vector3::new(tilt.sin() * heading.cos(), tilt.cos(), tilt.sin() * heading.sin());
+    Particle {
+        effect,
+        position: position + Vector3::from(def.offset),
+        velocity: direction * between(def.speed, rng),
+        age: 0.,
+        lifetime: between(def.lifetime, rng).max(0.001),
+    }
+}
+
+/// A value from `min` to `max` at random
+fn between([min, max]: [f32; 2], rng: &mut ChaCha8Rng) -> f32 {
+    if max > min {
+        rng.gen_range(min..max)
+    } else {
+        min
+    }
+}
@@ -0,0 +1,63 @@
+// Camera-facing particle quads, one instance per particle, drawn as a
+// four vertex triangle strip with no vertex buffer.
+
+[[block]]
+struct Uniforms {
+    view_proj: mat4x4<f32>;
+    // (scale x, scale z, offset x, offset z) from world to fog texture coordinates
+    fog_transform: vec4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+struct InstanceInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] size: f32;
+    [[location(2)]] color: vec4<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] color: vec4<f32>;
+    [[location(1)]] fog_coords: vec2<f32>;
+    // From -1 to 1 across the quad
+    [[location(2)]] corner: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main(
+    instance: InstanceInput,
+    [[builtin(vertex_index)]] vertex_index: u32,
+) -> VertexOutput {
+    // The first two rows of view_proj point along the screen's x and y in
+    // the world, for the orthographic camera and a symmetric perspective one
+    let m = uniforms.view_proj;
+    let right = normalize(vec3<f32>(m[0].x, m[1].x, m[2].x));
+    let up = normalize(vec3<f32>(m[0].y, m[1].y, m[2].y));
+
+    let corner = vec2<f32>(f32(vertex_index % 2u), f32(vertex_index / 2u)) * 2.0 - vec2<f32>(1.0, 1.0);
+    let offset = (right * corner.x + up * corner.y) * instance.size * 0.5;
+    let world_position = vec4<f32>(instance.position + offset, 1.0);
+
+    var out: VertexOutput;
+    out.clip_position = uniforms.view_proj * world_position;
+    out.color = instance.color;
+    out.fog_coords = world_position.xz * uniforms.fog_transform.xy + uniforms.fog_transform.zw;
+    out.corner = corner;
+    return out;
+}
+
+// Fragment shader
+
+[[group(1), binding(0)]]
+var t_fog: texture_2d<f32>;
+[[group(1), binding(1)]]
+var s_fog: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    // Round and soft edged, and unseen under fog
+    let fade = 1.0 - smoothstep(0.5, 1.0, length(in.corner));
+    let fog = textureSample(t_fog, s_fog, in.fog_coords).r;
+    return vec4<f32>(in.color.rgb, in.color.a * fade * fog);
+}
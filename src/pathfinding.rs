@@ -0,0 +1,91 @@
+//! A* over the map's cells, for getting round what a straight line can't
+//!
+//! Costs are whole numbers and ties are broken by cell index, so the same
+//! search finds the same path on every machine.
+
+use std::cmp::Reverse;
+use std::collections::BinaryHeap;
+
+use cgmath::{InnerSpace, Vector2};
+
+use super::map::Map;
+
+/// Cost of a step to a side neighbour, and to a corner one
+const STRAIGHT: u32 = 10;
+const DIAGONAL: u32 = 14;
+
+const NEIGHBOURS: [(i32, i32); 8] = [(1, 0), (0, 1), (-1, 0), (0, -1), (1, 1), (-1, 1), (-1, -1), (1, -1)];
+
+/// Waypoints from `from` to within `range` of `to`, the first being `to`
+/// itself and the rest cell centers, the next one to head for last. Corners
+/// are only cut between two passable cells. `None` if there's no way there.
+pub(crate) fn find_path(map: &Map, from: Vector2<f32>, to: Vector2<f32>, range: f32) -> Option<Vec<Vector2<f32>>> {
+    let (width, height) = map.cells_size();
+    let start = map.cell_coords(from)?;
+    let target = map.cell_coords(to)?;
+    let index = |(x, y): (u32, u32)| (y * width + x) as usize;
+    let passable = |x: i32, y: i32| {
+        x >= 0 && y >= 0 && x < width as i32 && y < height as i32 && map.cells[index((x as u32, y as u32))].is_passable()
+    };
+    // Stopping on a cell's center must count as having arrived, so one the
+    // target isn't in has to be well within range
+    let is_goal = |cell: (u32, u32)| {
+        cell == target || (map.cell_center(cell.0, cell.1) - to).magnitude() <= range * 0.5
+    };
+    let estimate = |(x, y): (u32, u32)| {
+        let dx = (x as i32 - target.0 as i32).unsigned_abs();
+        let dy = (y as i32 - target.1 as i32).unsigned_abs();
+        STRAIGHT * dx.max(dy) + (DIAGONAL - STRAIGHT) * dx.min(dy)
+    };
+
+    let mut cost = vec![u32::MAX; (width * height) as usize];
+    let mut came_from = vec![u32::MAX; (width * height) as usize];
+    let mut open = BinaryHeap::new();
+    cost[index(start)] = 0;
+    open.push(Reverse((estimate(start), index(start) as u32)));
+
+    let goal = loop {
+        let Reverse((priority, current)) = open.pop()?;
+        let cell = (current % width, current / width);
+        let current_cost = cost[current as usize];
+        // Already reached more cheaply
+        if priority > current_cost + estimate(cell) {
+            continue;
+        }
+        if is_goal(cell) {
+            break cell;
+        }
+        for &(dx, dy) in NEIGHBOURS.iter() {
+            let (x, y) = (cell.0 as i32 + dx, cell.1 as i32 + dy);
+            if !passable(x, y) {
+                continue;
+            }
+            let step = if dx != 0 && dy != 0 {
+                if !passable(cell.0 as i32 + dx, cell.1 as i32) || !passable(cell.0 as i32, cell.1 as i32 + dy) {
+                    continue;
+                }
+                DIAGONAL
+            } else {
+                STRAIGHT
+            };
+            let next = (x as u32, y as u32);
+            let next_cost = current_cost + step;
+            if next_cost < cost[index(next)] {
+                cost[index(next)] = next_cost;
+                came_from[index(next)] = current;
+                open.push(Reverse((next_cost + estimate(next), index(next) as u32)));
+            }
+        }
+    };
+
+    let mut path = vec![to, map.cell_center(goal.0, goal.1)];
+    let mut cell = goal;
+    while came_from[index(cell)] != u32::MAX {
+        let previous = came_from[index(cell)];
+        cell = (previous % width, previous / width);
+        if cell != start {
+            path.push(map.cell_center(cell.0, cell.1));
+        }
+    }
+    Some(path)
+}
@@ -1,19 +1,50 @@
+use std::ops::Range;
+
+use rayon::prelude::*;
 use wgpu::util::DeviceExt;
-use slab::Slab;
 
 use super::{model, texture};
-use super::model::VertexDesc;
+use super::particles::ParticleInstance;
+use super::animation::{AnimationKind, AnimationState, Rig, SkinnedMesh, SkinnedVertex};
+use super::ecs::{Entities, Entity, Storage};
+use super::frustum::Frustum;
+use super::model::{MeshVertex, ModelInstance, VertexDesc};
+use super::texture::{AnimationTextures, Skins};
+use super::vat::{VatInstance, VatVertex, VertexAnimation};
+
+/// Initial room in each model's instance buffer
+const INSTANCE_CAPACITY: usize = 256;
+/// Initial room in the particle buffer
+const PARTICLE_CAPACITY: usize = 4096;
+/// Camera zoom until told otherwise, as in the demo's camera
+const DEFAULT_ZOOM: f32 = 0.0625;
 
-#[derive(Copy, Clone)]
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub struct InstanceHandle {
     model: u16,
-    index: u16,
+    key: Entity,
+}
+
+/// An instance of a skinned model, which has its own animation
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct SkinnedHandle {
+    model: u16,
+    key: Entity,
+}
+
+/// An instance of a baked crowd model
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct CrowdHandle {
+    model: u16,
+    key: Entity,
 }
 
 #[repr(C)]
 #[derive(Debug, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
 pub struct Uniforms {
     pub view_proj: [[f32; 4]; 4],
+    /// Maps world xz to fog texture coordinates: (scale x, scale z, offset x, offset z)
+    pub fog_transform: [f32; 4],
 }
 
 impl Uniforms {
@@ -21,26 +52,61 @@ impl Uniforms {
         use cgmath::SquareMatrix;
         Self {
             view_proj: cgmath::Matrix4::identity().into(),
+            fog_transform: [0., 0., 0., 0.],
         }
     }
+
+    /// Cover a fog grid of `width` by `height` cells of `cell_size` starting at the world origin
+    pub fn with_fog(mut self, width: u32, height: u32, cell_size: f32) -> Self {
+        self.fog_transform = [1. / (width as f32 * cell_size), 1. / (height as f32 * cell_size), 0., 0.];
+        self
+    }
 }
 
 pub struct Renderer {
     models: Vec<ModelBuffers>,
+    skinned_models: Vec<SkinnedModelBuffers>,
+    crowds: Vec<CrowdBuffers>,
     device: wgpu::Device,
     size: winit::dpi::PhysicalSize<u32>,
     surface: wgpu::Surface,
     queue: wgpu::Queue,
     sc_desc: wgpu::SwapChainDescriptor,
     swap_chain: wgpu::SwapChain,
-    render_pipeline: wgpu::RenderPipeline,
+    /// As applied, which may not be quite what was asked for
+    settings: RenderSettings,
+    backend: wgpu::Backend,
+    /// Kept to rebuild the pipelines when the sample count changes
+    pipeline_sources: PipelineSources,
+    pipelines: Pipelines,
+    /// Drawn into and resolved to the swap chain frame when multisampling
+    msaa_target: Option<wgpu::TextureView>,
+    joints_bind_group_layout: wgpu::BindGroupLayout,
+    animation_textures_bind_group_layout: wgpu::BindGroupLayout,
+    particle_buffer: wgpu::Buffer,
+    particle_capacity: usize,
+    /// Where each blend mode's particles are in the particle buffer
+    alpha_particles: Range<u32>,
+    additive_particles: Range<u32>,
     depth_texture: texture::Texture,
     uniform_buffer: wgpu::Buffer,
     uniform_bind_group: wgpu::BindGroup,
+    fog_texture: texture::Texture,
+    fog_size: (u32, u32),
+    fog_bind_group_layout: wgpu::BindGroupLayout,
+    fog_bind_group: wgpu::BindGroup,
+    skins_bind_group_layout: wgpu::BindGroupLayout,
+    skins: Skins,
+    /// Screen pixels per world unit, which picks the level of detail
+    pixels_per_unit: f32,
+    /// The camera's, to cull instances against. Nothing is culled until it's set.
+    view_proj: Option<cgmath::Matrix4<f32>>,
 }
 
 impl Renderer {
-    pub async fn new(window: &winit::window::Window) -> Self {
+    /// A renderer drawing to `window`, with `settings` or the nearest the
+    /// adapter can do, as `settings` then reports
+    pub async fn new(window: &winit::window::Window, settings: RenderSettings) -> Self {
         let size = window.inner_size();
 
         let gpu_instance = wgpu::Instance::new(wgpu::BackendBit::PRIMARY);
@@ -52,6 +118,8 @@ impl Renderer {
             })
             .await
             .unwrap();
+        let backend = adapter.get_info().backend;
+        let settings = settings.supported(backend);
 
         let (device, queue) = adapter
             .request_device(
@@ -70,7 +138,7 @@ impl Renderer {
             format: adapter.get_swap_chain_preferred_format(&surface).unwrap(),
             width: size.width,
             height: size.height,
-            present_mode: wgpu::PresentMode::Fifo,
+            present_mode: settings.present_mode.to_wgpu(),
         };
         let swap_chain = device.create_swap_chain(&surface, &sc_desc);
 
@@ -117,120 +185,923 @@ impl Renderer {
             label: Some("uniform_bind_group"),
         });
 
-        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, "depth_texture");
+        let fog_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("fog_bind_group_layout"),
+        });
+
+        // Until a fog grid is uploaded everything is fully lit
+        let fog_texture = texture::Texture::create_fog_texture(&device, 1, 1, "fog_texture");
+        fog_texture.write_r8(&queue, 1, 1, &[0xff]);
+        let fog_bind_group = create_fog_bind_group(&device, &fog_bind_group_layout, &fog_texture);
 
-        let instance_buffer = device.create_buffer_init(
-            &wgpu::util::BufferInitDescriptor {
-                label: Some("Instance Buffer"),
-                contents: &[0u8; 4096],
-                usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
-            }
-        );
+        let skins_bind_group_layout = Skins::bind_group_layout(&device);
+        let skins = Skins::load(&device, &queue, &skins_bind_group_layout, &[] as &[&str]).expect("No skins to load");
+
+        let depth_texture = texture::Texture::create_depth_texture(&device, &sc_desc, settings.msaa.samples(), "depth_texture");
+        let msaa_target = create_msaa_target(&device, &sc_desc, settings.msaa.samples());
 
         let render_pipeline_layout = device.create_pipeline_layout(
             &wgpu::PipelineLayoutDescriptor {
                 label: Some("Render Pipeline Layout"),
                 bind_group_layouts: &[
                     &uniform_bind_group_layout,
+                    &fog_bind_group_layout,
+                    &skins_bind_group_layout,
                 ],
                 push_constant_ranges: &[],
             }
         );
 
-        let render_pipeline = device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
-            label: Some("Render Pipeline"),
-            layout: Some(&render_pipeline_layout),
-            vertex: wgpu::VertexState {
-                module: &shader,
-                entry_point: "main",
-                buffers: &[model::MeshVertex::desc(), model::ModelInstance::desc()],
-            },
-            fragment: Some(wgpu::FragmentState {
-                module: &shader,
-                entry_point: "main",
-                targets: &[wgpu::ColorTargetState {
-                    format: sc_desc.format,
-                    blend: Some(wgpu::BlendState::REPLACE),
-                    write_mask: wgpu::ColorWrite::ALL,
-                }],
-            }),
-            primitive: wgpu::PrimitiveState {
-                topology: wgpu::PrimitiveTopology::TriangleList,
-                strip_index_format: None,
-                front_face: wgpu::FrontFace::Ccw,
-                cull_mode: Some(wgpu::Face::Back),
-                clamp_depth: false,
-                conservative: false,
-                polygon_mode: wgpu::PolygonMode::Fill,
-            },
-            depth_stencil: Some(wgpu::DepthStencilState {
-                format: texture::Texture::DEPTH_FORMAT,
-                depth_write_enabled: true,
-                depth_compare: wgpu::CompareFunction::Less,
-                stencil: wgpu::StencilState::default(),
-                bias: wgpu::DepthBiasState::default(),
-            }),
-            multisample: wgpu::MultisampleState {
-                count: 1,
-                mask: !0,
-                alpha_to_coverage_enabled: false,
-            },
+        let skinned_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Skinned Shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(include_str!("skinned.wgsl").into()),
+        });
+
+        let joints_bind_group_layout = device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                }
+            ],
+            label: Some("joints_bind_group_layout"),
+        });
+
+        let skinned_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Skinned Pipeline Layout"),
+                bind_group_layouts: &[
+                    &uniform_bind_group_layout,
+                    &fog_bind_group_layout,
+                    &skins_bind_group_layout,
+                    &joints_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let crowd_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Crowd Shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(include_str!("vat.wgsl").into()),
+        });
+
+        let animation_textures_bind_group_layout = AnimationTextures::bind_group_layout(&device);
+
+        let crowd_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Crowd Pipeline Layout"),
+                bind_group_layouts: &[
+                    &uniform_bind_group_layout,
+                    &fog_bind_group_layout,
+                    &skins_bind_group_layout,
+                    &animation_textures_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let particle_shader = device.create_shader_module(&wgpu::ShaderModuleDescriptor {
+            label: Some("Particle Shader"),
+            flags: wgpu::ShaderFlags::all(),
+            source: wgpu::ShaderSource::Wgsl(include_str!("particles.wgsl").into()),
         });
 
+        let particle_pipeline_layout = device.create_pipeline_layout(
+            &wgpu::PipelineLayoutDescriptor {
+                label: Some("Particle Pipeline Layout"),
+                bind_group_layouts: &[
+                    &uniform_bind_group_layout,
+                    &fog_bind_group_layout,
+                ],
+                push_constant_ranges: &[],
+            }
+        );
+
+        let pipeline_sources = PipelineSources {
+            shader,
+            render_pipeline_layout,
+            skinned_shader,
+            skinned_pipeline_layout,
+            crowd_shader,
+            crowd_pipeline_layout,
+            particle_shader,
+            particle_pipeline_layout,
+        };
+        let pipelines = pipeline_sources.build(&device, sc_desc.format, settings.msaa.samples());
+        let particle_buffer = create_particle_buffer(&device, PARTICLE_CAPACITY);
         let models = Vec::new();
 
         Self {
             models,
+            skinned_models: Vec::new(),
+            crowds: Vec::new(),
             device,
             surface,
             queue,
             sc_desc,
             swap_chain,
             size,
-            render_pipeline,
+            settings,
+            backend,
+            pipeline_sources,
+            pipelines,
+            msaa_target,
+            joints_bind_group_layout,
+            animation_textures_bind_group_layout,
+            particle_buffer,
+            particle_capacity: PARTICLE_CAPACITY,
+            alpha_particles: 0..0,
+            additive_particles: 0..0,
             depth_texture,
             uniform_buffer,
             uniform_bind_group,
+            fog_texture,
+            fog_size: (1, 1),
+            fog_bind_group_layout,
+            fog_bind_group,
+            skins_bind_group_layout,
+            skins,
+            pixels_per_unit: pixels_per_unit(size.height, DEFAULT_ZOOM),
+            view_proj: None,
         }
     }
 
     pub fn add_model(&mut self, model: Model) -> u16 {
         let index = self.models.len() as u16;
 
-        let num_triangles = (model.indices.len() / 3) as u16;
-        let vertex_buffer = create_buffer_init(&mut self.device, "vertex", &model.vertices, wgpu::BufferUsage::VERTEX);
-        let index_buffer = create_buffer_init(&mut self.device, "index", &model.indices, wgpu::BufferUsage::INDEX);
-        let instance_buffer = create_buffer_init(&mut self.device, "instance", &[0u8; 2usize.pow(14)], wgpu::BufferUsage::VERTEX);
-        let instances = DenseMap::new();
+        // Every level shares one vertex and one index buffer
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        let lods = model.lods.iter().map(|lod| {
+            let base_vertex = vertices.len() as i32;
+            let first_index = indices.len() as u32;
+            vertices.extend_from_slice(&lod.vertices);
+            indices.extend_from_slice(&lod.indices);
+            LodBuffers {
+                indices: first_index..indices.len() as u32,
+                base_vertex,
+                min_size: lod.min_size,
+            }
+        }).collect::<Vec<_>>();
+        let vertex_buffer = create_buffer_init(&mut self.device, "vertex", &vertices, wgpu::BufferUsage::VERTEX);
+        let index_buffer = create_buffer_init(&mut self.device, "index", &indices, wgpu::BufferUsage::INDEX);
+        let instance_buffer = create_instance_buffer(&self.device, INSTANCE_CAPACITY);
+        let indirect_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("indirect"),
+            size: (lods.len() * std::mem::size_of::<DrawIndexedIndirect>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::INDIRECT | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
 
         self.models.push(ModelBuffers {
             vertex_buffer,
             index_buffer,
-            num_triangles,
+            radius: model.bounding_radius(),
+            instance_ranges: vec![0..0; lods.len()],
+            lods,
             instance_buffer,
+            indirect_buffer,
+            instance_capacity: INSTANCE_CAPACITY,
             instance_buffer_dirty: false,
-            instances,
+            keys: Entities::default(),
+            instances: Storage::new(),
         });
 
         index
     }
 
-    pub fn add_instance(&mut self, model: u16, instance: Instance) -> InstanceHandle {
-        assert!(model as usize <= self.models.len());
+    pub fn add_instance(&mut self, model: u16, instance: ModelInstance) -> InstanceHandle {
+        assert!((model as usize) < self.models.len());
         let model_buffers = &mut self.models[model as usize];
-        let index = model_buffers.instances.insert(instance) as u16;
+        let key = model_buffers.keys.create();
+        model_buffers.instances.insert(key, instance);
         model_buffers.instance_buffer_dirty = true;
         InstanceHandle {
             model,
-            index,
+            key,
+        }
+    }
+
+    pub fn update_instance(&mut self, handle: InstanceHandle, instance: ModelInstance) {
+        let model_buffers = &mut self.models[handle.model as usize];
+        model_buffers.instances[handle.key] = instance;
+        model_buffers.instance_buffer_dirty = true;
+    }
+
+    pub fn remove_instance(&mut self, handle: InstanceHandle) {
+        let model_buffers = &mut self.models[handle.model as usize];
+        model_buffers.instances.remove(handle.key);
+        model_buffers.instance_buffer_dirty = true;
+    }
+
+    pub fn add_skinned_model(&mut self, mesh: SkinnedMesh) -> u16 {
+        let index = self.skinned_models.len() as u16;
+        let joint_count = mesh.rig.skeleton.joints.len().max(1);
+        let (instance_buffer, joint_buffer, joints_bind_group) = self.create_skinned_buffers(INSTANCE_CAPACITY, joint_count);
+        self.skinned_models.push(SkinnedModelBuffers {
+            vertex_buffer: create_buffer_init(&mut self.device, "skinned vertex", &mesh.vertices, wgpu::BufferUsage::VERTEX),
+            index_buffer: create_buffer_init(&mut self.device, "skinned index", &mesh.indices, wgpu::BufferUsage::INDEX),
+            num_indices: mesh.indices.len() as u32,
+            rig: mesh.rig,
+            joint_count,
+            keys: Entities::default(),
+            instances: Storage::new(),
+            instance_buffer,
+            joint_buffer,
+            joints_bind_group,
+            capacity: INSTANCE_CAPACITY,
+            uploaded: 0,
+        });
+        index
+    }
+
+    pub fn add_skinned_instance(&mut self, model: u16, instance: ModelInstance, animation: AnimationKind, time: f32) -> SkinnedHandle {
+        assert!((model as usize) < self.skinned_models.len());
+        let model_buffers = &mut self.skinned_models[model as usize];
+        let key = model_buffers.keys.create();
+        model_buffers.instances.insert(key, (instance, AnimationState::new(animation, time)));
+        SkinnedHandle {
+            model,
+            key,
+        }
+    }
+
+    pub fn update_skinned_instance(&mut self, handle: SkinnedHandle, instance: ModelInstance) {
+        self.skinned_models[handle.model as usize].instances[handle.key].0 = instance;
+    }
+
+    /// Switch an instance to `animation` from `time` on, blending out of
+    /// whatever it was playing
+    pub fn play_animation(&mut self, handle: SkinnedHandle, animation: AnimationKind, time: f32) {
+        self.skinned_models[handle.model as usize].instances[handle.key].1.play(animation, time);
+    }
+
+    pub fn remove_skinned_instance(&mut self, handle: SkinnedHandle) {
+        self.skinned_models[handle.model as usize].instances.remove(handle.key);
+    }
+
+    /// Pose every skinned instance as it is at `time` and upload the joint
+    /// matrices, one storage buffer per model. Poses are sampled in parallel.
+    /// Crowds only need to be told the time. Call once a frame, before `render`.
+    pub fn animate(&mut self, time: f32) {
+        for crowd in &self.crowds {
+            crowd.textures.set_time(&self.queue, time);
+        }
+        for index in 0..self.skinned_models.len() {
+            let count = self.skinned_models[index].instances.len();
+            if count > self.skinned_models[index].capacity {
+                let capacity = count.next_power_of_two();
+                let (instance_buffer, joint_buffer, joints_bind_group) = self.create_skinned_buffers(capacity, self.skinned_models[index].joint_count);
+                let model = &mut self.skinned_models[index];
+                model.instance_buffer = instance_buffer;
+                model.joint_buffer = joint_buffer;
+                model.joints_bind_group = joints_bind_group;
+                model.capacity = capacity;
+            }
+
+            let model = &mut self.skinned_models[index];
+            let rig = &model.rig;
+            let joint_count = model.joint_count;
+            let matrices: Vec<Vec<[[f32; 4]; 4]>> = model.instances.values().par_iter()
+                .map(|(_, state)| {
+                    let mut matrices = Vec::with_capacity(joint_count);
+                    rig.skeleton.joint_matrices(&rig.pose(state, time), &mut matrices);
+                    matrices.resize(joint_count, [[0.; 4]; 4]);
+                    matrices
+                })
+                .collect();
+            let instances: Vec<ModelInstance> = model.instances.values().iter().map(|(instance, _)| *instance).collect();
+
+            self.queue.write_buffer(&model.instance_buffer, 0, bytemuck::cast_slice(&instances));
+            self.queue.write_buffer(&model.joint_buffer, JOINTS_HEADER_SIZE, bytemuck::cast_slice(&matrices.concat()));
+            model.uploaded = count as u32;
+        }
+    }
+
+    /// Add a model animated by baked vertex animation textures, for drawing
+    /// many instances at once
+    pub fn add_crowd_model(&mut self, animation: &VertexAnimation) -> u16 {
+        let index = self.crowds.len() as u16;
+        let textures = AnimationTextures::new(&self.device, &self.queue, &self.animation_textures_bind_group_layout, animation);
+        self.crowds.push(CrowdBuffers {
+            vertex_buffer: create_buffer_init(&mut self.device, "crowd vertex", &animation.vertices(), wgpu::BufferUsage::VERTEX),
+            index_buffer: create_buffer_init(&mut self.device, "crowd index", &animation.indices, wgpu::BufferUsage::INDEX),
+            num_indices: animation.indices.len() as u32,
+            textures,
+            keys: Entities::default(),
+            instances: Storage::new(),
+            instance_buffer: create_crowd_instance_buffer(&self.device, INSTANCE_CAPACITY),
+            instance_capacity: INSTANCE_CAPACITY,
+            instance_buffer_dirty: false,
+            uploaded: 0,
+        });
+        index
+    }
+
+    pub fn add_crowd_instance(&mut self, model: u16, instance: VatInstance) -> CrowdHandle {
+        assert!((model as usize) < self.crowds.len());
+        let crowd = &mut self.crowds[model as usize];
+        let key = crowd.keys.create();
+        crowd.instances.insert(key, instance);
+        crowd.instance_buffer_dirty = true;
+        CrowdHandle {
+            model,
+            key,
+        }
+    }
+
+    pub fn update_crowd_instance(&mut self, handle: CrowdHandle, instance: VatInstance) {
+        let crowd = &mut self.crowds[handle.model as usize];
+        crowd.instances[handle.key] = instance;
+        crowd.instance_buffer_dirty = true;
+    }
+
+    pub fn remove_crowd_instance(&mut self, handle: CrowdHandle) {
+        let crowd = &mut self.crowds[handle.model as usize];
+        crowd.instances.remove(handle.key);
+        crowd.instance_buffer_dirty = true;
+    }
+
+    /// Instance and joint buffers for `capacity` instances of a model with
+    /// `joint_count` joints, with the bind group for the joints
+    fn create_skinned_buffers(&self, capacity: usize, joint_count: usize) -> (wgpu::Buffer, wgpu::Buffer, wgpu::BindGroup) {
+        let instance_buffer = create_instance_buffer(&self.device, capacity);
+        let joint_buffer = self.device.create_buffer(&wgpu::BufferDescriptor {
+            label: Some("joints"),
+            size: JOINTS_HEADER_SIZE + (capacity * joint_count * std::mem::size_of::<[[f32; 4]; 4]>()) as wgpu::BufferAddress,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+            mapped_at_creation: false,
+        });
+        self.queue.write_buffer(&joint_buffer, 0, bytemuck::cast_slice(&[joint_count as u32, 0, 0, 0]));
+        let joints_bind_group = self.device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout: &self.joints_bind_group_layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: joint_buffer.as_entire_binding(),
+                }
+            ],
+            label: Some("joints_bind_group"),
+        });
+        (instance_buffer, joint_buffer, joints_bind_group)
+    }
+
+    /// Replace the unit skins with the images at `paths`, which become
+    /// layers 1 onwards for `ModelInstance::layer` to pick
+    pub fn load_skins(&mut self, paths: &[impl AsRef<std::path::Path>]) -> image::ImageResult<()> {
+        self.skins = Skins::load(&self.device, &self.queue, &self.skins_bind_group_layout, paths)?;
+        Ok(())
+    }
+
+    /// Upload the fog overlay, one brightness byte per cell (see `FogOfWar::texture_data`)
+    pub fn update_fog(&mut self, width: u32, height: u32, data: &[u8]) {
+        if self.fog_size != (width, height) {
+            self.fog_texture = texture::Texture::create_fog_texture(&self.device, width, height, "fog_texture");
+            self.fog_bind_group = create_fog_bind_group(&self.device, &self.fog_bind_group_layout, &self.fog_texture);
+            self.fog_size = (width, height);
         }
+        self.fog_texture.write_r8(&self.queue, width, height, data);
     }
 
-    pub fn remove_instance(&mut self, instance: InstanceHandle) {
-        self.models[instance.model as usize].instances.remove(instance.index as usize);
+    pub fn resize(&mut self, new_size: winit::dpi::PhysicalSize<u32>) {
+        self.size = new_size;
+        self.sc_desc.width = new_size.width;
+        self.sc_desc.height = new_size.height;
+        self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        self.create_targets();
+    }
+
+    /// The settings in effect, after any fallback
+    pub fn settings(&self) -> RenderSettings {
+        self.settings
+    }
+
+    /// Switch anti-aliasing or present mode while running, rebuilding only
+    /// what the change affects. Settings the adapter can't do fall back to
+    /// the nearest it can, as `settings` then reports.
+    pub fn set_settings(&mut self, settings: RenderSettings) {
+        let settings = settings.supported(self.backend);
+        let old = std::mem::replace(&mut self.settings, settings);
+        if settings.present_mode != old.present_mode {
+            self.sc_desc.present_mode = settings.present_mode.to_wgpu();
+            self.swap_chain = self.device.create_swap_chain(&self.surface, &self.sc_desc);
+        }
+        if settings.msaa != old.msaa {
+            self.pipelines = self.pipeline_sources.build(&self.device, self.sc_desc.format, settings.msaa.samples());
+            self.create_targets();
+        }
+    }
+
+    /// The depth texture and multisampled color target, at the swap chain's size
+    fn create_targets(&mut self) {
+        let sample_count = self.settings.msaa.samples();
+        self.depth_texture = texture::Texture::create_depth_texture(&self.device, &self.sc_desc, sample_count, "depth_texture");
+        self.msaa_target = create_msaa_target(&self.device, &self.sc_desc, sample_count);
+    }
+
+    /// Follow the camera's zoom, which changes how big everything is on
+    /// screen and so which level of detail it's drawn at
+    pub fn set_zoom(&mut self, zoom: f32) {
+        let pixels_per_unit = pixels_per_unit(self.size.height, zoom);
+        if pixels_per_unit != self.pixels_per_unit {
+            self.pixels_per_unit = pixels_per_unit;
+            for model in &mut self.models {
+                model.instance_buffer_dirty = true;
+            }
+        }
+    }
+
+    /// Follow the camera's view, which changes which instances are on screen
+    pub fn set_view(&mut self, view_proj: cgmath::Matrix4<f32>) {
+        if self.view_proj != Some(view_proj) {
+            self.view_proj = Some(view_proj);
+            for model in &mut self.models {
+                model.instance_buffer_dirty = true;
+            }
+        }
+    }
+
+    /// Copy instances changed since the last upload to the GPU, growing
+    /// buffers that ran out of room. Instances out of view are left out, and
+    /// the rest grouped by level of detail, so every level is drawn with one
+    /// indirect draw of a range of the buffer.
+    pub fn upload_instances(&mut self) {
+        let pixels_per_unit = self.pixels_per_unit;
+        let frustum = self.view_proj.map(Frustum::from_view_proj);
+        for model in self.models.iter_mut().filter(|model| model.instance_buffer_dirty) {
+            let mut buckets = vec![Vec::new(); model.lods.len()];
+            let visible = model.instances.values().iter()
+                .filter(|instance| frustum.as_ref().is_none_or(|frustum| frustum.intersects_instance(instance, model.radius)));
+            for instance in visible {
+                let (_, radius) = instance.bounding_sphere(model.radius);
+                let size = radius * 2. * pixels_per_unit;
+                let lod = model.lods.iter().position(|lod| size >= lod.min_size).unwrap_or(model.lods.len() - 1);
+                buckets[lod].push(*instance);
+            }
+
+            let mut packed = Vec::with_capacity(model.instances.len());
+            for (bucket, range) in buckets.iter().zip(&mut model.instance_ranges) {
+                let start = packed.len() as u32;
+                packed.extend_from_slice(bucket);
+                *range = start..packed.len() as u32;
+            }
+
+            if packed.len() > model.instance_capacity {
+                model.instance_capacity = packed.len().next_power_of_two();
+                model.instance_buffer = create_instance_buffer(&self.device, model.instance_capacity);
+            }
+            self.queue.write_buffer(&model.instance_buffer, 0, bytemuck::cast_slice(&packed));
+
+            // Each level's range is bound at the start of the buffer, so the
+            // draws all start from instance 0
+            let draws: Vec<_> = model.lods.iter().zip(&model.instance_ranges)
+                .map(|(lod, instances)| DrawIndexedIndirect {
+                    index_count: lod.indices.len() as u32,
+                    instance_count: instances.len() as u32,
+                    first_index: lod.indices.start,
+                    base_vertex: lod.base_vertex,
+                    first_instance: 0,
+                })
+                .collect();
+            self.queue.write_buffer(&model.indirect_buffer, 0, bytemuck::cast_slice(&draws));
+            model.instance_buffer_dirty = false;
+        }
+
+        for crowd in self.crowds.iter_mut().filter(|crowd| crowd.instance_buffer_dirty) {
+            if crowd.instances.len() > crowd.instance_capacity {
+                crowd.instance_capacity = crowd.instances.len().next_power_of_two();
+                crowd.instance_buffer = create_crowd_instance_buffer(&self.device, crowd.instance_capacity);
+            }
+            self.queue.write_buffer(&crowd.instance_buffer, 0, bytemuck::cast_slice(crowd.instances.values()));
+            crowd.uploaded = crowd.instances.len() as u32;
+            crowd.instance_buffer_dirty = false;
+        }
+    }
+
+    /// Replace the particles drawn each frame, as given by `ParticleSystem::instances`
+    pub fn upload_particles(&mut self, alpha: &[ParticleInstance], additive: &[ParticleInstance]) {
+        let count = alpha.len() + additive.len();
+        if count > self.particle_capacity {
+            self.particle_capacity = count.next_power_of_two();
+            self.particle_buffer = create_particle_buffer(&self.device, self.particle_capacity);
+        }
+        self.queue.write_buffer(&self.particle_buffer, 0, bytemuck::cast_slice(alpha));
+        self.queue.write_buffer(&self.particle_buffer, std::mem::size_of_val(alpha) as wgpu::BufferAddress, bytemuck::cast_slice(additive));
+        self.alpha_particles = 0..alpha.len() as u32;
+        self.additive_particles = alpha.len() as u32..count as u32;
+    }
+
+    pub fn render(&mut self, uniforms: &Uniforms) -> Result<(), wgpu::SwapChainError> {
+        let frame = self.swap_chain.get_current_frame()?.output;
+
+        let mut encoder = self.device.create_command_encoder(&wgpu::CommandEncoderDescriptor {
+            label: Some("Render Encoder"),
+        });
+
+        {
+            let mut render_pass = encoder.begin_render_pass(&wgpu::RenderPassDescriptor {
+                label: Some("Render Pass"),
+                color_attachments: &[wgpu::RenderPassColorAttachment {
+                    view: self.msaa_target.as_ref().unwrap_or(&frame.view),
+                    resolve_target: self.msaa_target.as_ref().map(|_| &frame.view),
+                    ops: wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(wgpu::Color { r: 0.1, g: 0.2, b: 0.3, a: 1.0 }),
+                        store: true,
+                    },
+                }],
+                depth_stencil_attachment: Some(wgpu::RenderPassDepthStencilAttachment {
+                    view: &self.depth_texture.view,
+                    depth_ops: Some(wgpu::Operations {
+                        load: wgpu::LoadOp::Clear(1.0),
+                        store: true,
+                    }),
+                    stencil_ops: None,
+                }),
+            });
+
+            render_pass.set_pipeline(&self.pipelines.render);
+            render_pass.set_bind_group(0, &self.uniform_bind_group, &[]);
+            render_pass.set_bind_group(1, &self.fog_bind_group, &[]);
+            render_pass.set_bind_group(2, &self.skins.bind_group, &[]);
+            for model in &self.models {
+                render_pass.set_vertex_buffer(0, model.vertex_buffer.slice(..));
+                render_pass.set_index_buffer(model.index_buffer.slice(..), wgpu::IndexFormat::Uint16);
+                for (lod, instances) in model.instance_ranges.iter().enumerate().filter(|(_, instances)| !instances.is_empty()) {
+                    let start = instances.start as wgpu::BufferAddress * std::mem::size_of::<ModelInstance>() as wgpu::BufferAddress;
+                    render_pass.set_vertex_buffer(1, model.instance_buffer.slice(start..));
+                    render_pass.draw_indexed_indirect(&model.indirect_buffer, (lod * std::mem::size_of::<DrawIndexedIndirect>()) as wgpu::BufferAddress);
+                }
+            }
+
+            render_pass.set_pipeline(&self.pipelines.skinned);
+            for model in self.skinned_models.iter().filter(|model| model.uploaded > 0) {
+                render_pass.set_bind_group(3, &model.joints_bind_group, &[]);
+                render_pass.set_vertex_buffer(0, model.vertex_buffer.slice(..));
+                render_pass.set_vertex_buffer(1, model.instance_buffer.slice(..));
+                render_pass.set_index_buffer(model.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..model.num_indices, 0, 0..model.uploaded);
+            }
+
+            render_pass.set_pipeline(&self.pipelines.crowd);
+            for crowd in self.crowds.iter().filter(|crowd| crowd.uploaded > 0) {
+                render_pass.set_bind_group(3, &crowd.textures.bind_group, &[]);
+                render_pass.set_vertex_buffer(0, crowd.vertex_buffer.slice(..));
+                render_pass.set_vertex_buffer(1, crowd.instance_buffer.slice(..));
+                render_pass.set_index_buffer(crowd.index_buffer.slice(..), wgpu::IndexFormat::Uint32);
+                render_pass.draw_indexed(0..crowd.num_indices, 0, 0..crowd.uploaded);
+            }
+
+            // Particles go over everything opaque, tested against its depth
+            // but not hiding each other
+            render_pass.set_vertex_buffer(0, self.particle_buffer.slice(..));
+            if !self.alpha_particles.is_empty() {
+                render_pass.set_pipeline(&self.pipelines.alpha_particles);
+                render_pass.draw(0..4, self.alpha_particles.clone());
+            }
+            if !self.additive_particles.is_empty() {
+                render_pass.set_pipeline(&self.pipelines.additive_particles);
+                render_pass.draw(0..4, self.additive_particles.clone());
+            }
+        }
+
+        self.queue.write_buffer(&self.uniform_buffer, 0, bytemuck::cast_slice(&[*uniforms]));
+        self.queue.submit(std::iter::once(encoder.finish()));
+
+        Ok(())
     }
 }
 
+/// Samples per pixel for anti-aliasing. More smooths edges better at the cost of fill rate.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Msaa {
+    Off,
+    X2,
+    X4,
+    X8,
+}
+
+impl Msaa {
+    pub const ALL: [Msaa; 4] = [Msaa::Off, Msaa::X2, Msaa::X4, Msaa::X8];
+
+    /// Whether every adapter can take this many samples. wgpu can't be asked
+    /// which counts an adapter supports, and WebGPU only guarantees 1 and 4.
+    pub fn is_supported(self) -> bool {
+        matches!(self, Msaa::Off | Msaa::X4)
+    }
+
+    pub fn samples(self) -> u32 {
+        match self {
+            Msaa::Off => 1,
+            Msaa::X2 => 2,
+            Msaa::X4 => 4,
+            Msaa::X8 => 8,
+        }
+    }
+}
+
+/// When finished frames reach the screen
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum PresentMode {
+    /// In step with the display's refresh. No tearing, but frames can wait.
+    Vsync,
+    /// The newest frame at each refresh, dropping older ones. No tearing and
+    /// little waiting, where supported.
+    Mailbox,
+    /// Straight away, tearing and all
+    Immediate,
+}
+
+impl PresentMode {
+    pub const ALL: [PresentMode; 3] = [PresentMode::Vsync, PresentMode::Mailbox, PresentMode::Immediate];
+
+    /// Whether surfaces on `backend` can present this way. wgpu can't ask the
+    /// surface itself, so this goes by what each backend is able to offer.
+    /// Vsync works everywhere. Vulkan leaves the rest to the driver, which
+    /// can still turn them down, in which case wgpu falls back to vsync too.
+    pub fn is_supported(self, backend: wgpu::Backend) -> bool {
+        matches!(
+            (self, backend),
+            (PresentMode::Vsync, _)
+                | (_, wgpu::Backend::Vulkan)
+                | (PresentMode::Immediate, wgpu::Backend::Metal | wgpu::Backend::Dx12 | wgpu::Backend::Dx11)
+        )
+    }
+
+    fn to_wgpu(self) -> wgpu::PresentMode {
+        match self {
+            PresentMode::Vsync => wgpu::PresentMode::Fifo,
+            PresentMode::Mailbox => wgpu::PresentMode::Mailbox,
+            PresentMode::Immediate => wgpu::PresentMode::Immediate,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RenderSettings {
+    pub msaa: Msaa,
+    pub present_mode: PresentMode,
+}
+
+impl RenderSettings {
+    /// These settings with anything `backend` can't do swapped for the
+    /// nearest it can: 4x anti-aliasing, or vsync
+    pub fn supported(self, backend: wgpu::Backend) -> Self {
+        Self {
+            msaa: if self.msaa.is_supported() { self.msaa } else { Msaa::X4 },
+            present_mode: if self.present_mode.is_supported(backend) { self.present_mode } else { PresentMode::Vsync },
+        }
+    }
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            msaa: Msaa::X4,
+            present_mode: PresentMode::Vsync,
+        }
+    }
+}
+
+/// The shaders and layouts the pipelines are built from
+struct PipelineSources {
+    shader: wgpu::ShaderModule,
+    render_pipeline_layout: wgpu::PipelineLayout,
+    skinned_shader: wgpu::ShaderModule,
+    skinned_pipeline_layout: wgpu::PipelineLayout,
+    crowd_shader: wgpu::ShaderModule,
+    crowd_pipeline_layout: wgpu::PipelineLayout,
+    particle_shader: wgpu::ShaderModule,
+    particle_pipeline_layout: wgpu::PipelineLayout,
+}
+
+struct Pipelines {
+    render: wgpu::RenderPipeline,
+    skinned: wgpu::RenderPipeline,
+    crowd: wgpu::RenderPipeline,
+    alpha_particles: wgpu::RenderPipeline,
+    additive_particles: wgpu::RenderPipeline,
+}
+
+impl PipelineSources {
+    /// Every pipeline, drawing to `format` with `sample_count` samples per pixel
+    fn build(&self, device: &wgpu::Device, format: wgpu::TextureFormat, sample_count: u32) -> Pipelines {
+        Pipelines {
+            render: create_pipeline(device, &self.render_pipeline_layout, &self.shader, &[model::MeshVertex::desc(), model::ModelInstance::desc()], format, sample_count, "Render Pipeline"),
+            skinned: create_pipeline(device, &self.skinned_pipeline_layout, &self.skinned_shader, &[SkinnedVertex::desc(), model::ModelInstance::desc()], format, sample_count, "Skinned Pipeline"),
+            crowd: create_pipeline(device, &self.crowd_pipeline_layout, &self.crowd_shader, &[VatVertex::desc(), VatInstance::desc()], format, sample_count, "Crowd Pipeline"),
+            alpha_particles: create_particle_pipeline(device, &self.particle_pipeline_layout, &self.particle_shader, format, sample_count, wgpu::BlendFactor::OneMinusSrcAlpha, "Alpha Particle Pipeline"),
+            additive_particles: create_particle_pipeline(device, &self.particle_pipeline_layout, &self.particle_shader, format, sample_count, wgpu::BlendFactor::One, "Additive Particle Pipeline"),
+        }
+    }
+}
+
+/// Screen pixels per world unit for the orthographic camera, which shows
+/// `2 / zoom` world units from the bottom of the screen to the top
+pub fn pixels_per_unit(screen_height: u32, zoom: f32) -> f32 {
+    screen_height as f32 * zoom / 2.
+}
+
+fn create_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("instance"),
+        size: (capacity * std::mem::size_of::<ModelInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_crowd_instance_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("crowd instance"),
+        size: (capacity * std::mem::size_of::<VatInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+fn create_particle_buffer(device: &wgpu::Device, capacity: usize) -> wgpu::Buffer {
+    device.create_buffer(&wgpu::BufferDescriptor {
+        label: Some("particles"),
+        size: (capacity * std::mem::size_of::<ParticleInstance>()) as wgpu::BufferAddress,
+        usage: wgpu::BufferUsage::VERTEX | wgpu::BufferUsage::COPY_DST,
+        mapped_at_creation: false,
+    })
+}
+
+/// An opaque pipeline, culling back faces and writing depth
+fn create_pipeline(
+    device: &wgpu::Device,
+    layout: &wgpu::PipelineLayout,
+    shader: &wgpu::ShaderModule,
+    buffers: &[wgpu::VertexBufferLayout],
+    format: wgpu::TextureFormat,
+    sample_count: u32,
+    label: &str,
+) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "main",
+            buffers,
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState::REPLACE),
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleList,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: Some(wgpu::Face::Back),
+            clamp_depth: false,
+            conservative: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: true,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+    })
+}
+
+/// The color target to draw into before resolving to the frame, or `None`
+/// for a single sample, which draws straight to the frame
+fn create_msaa_target(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor, sample_count: u32) -> Option<wgpu::TextureView> {
+    if sample_count == 1 {
+        return None;
+    }
+    let texture = device.create_texture(&wgpu::TextureDescriptor {
+        label: Some("msaa_target"),
+        size: wgpu::Extent3d {
+            width: sc_desc.width,
+            height: sc_desc.height,
+            depth_or_array_layers: 1,
+        },
+        mip_level_count: 1,
+        sample_count,
+        dimension: wgpu::TextureDimension::D2,
+        format: sc_desc.format,
+        usage: wgpu::TextureUsage::RENDER_ATTACHMENT,
+    });
+    Some(texture.create_view(&wgpu::TextureViewDescriptor::default()))
+}
+
+/// Particles blend their color in by their alpha, over what's behind scaled
+/// by `dst_factor`: `OneMinusSrcAlpha` for ordinary blending, `One` to add
+fn create_particle_pipeline(device: &wgpu::Device, layout: &wgpu::PipelineLayout, shader: &wgpu::ShaderModule, format: wgpu::TextureFormat, sample_count: u32, dst_factor: wgpu::BlendFactor, label: &str) -> wgpu::RenderPipeline {
+    device.create_render_pipeline(&wgpu::RenderPipelineDescriptor {
+        label: Some(label),
+        layout: Some(layout),
+        vertex: wgpu::VertexState {
+            module: shader,
+            entry_point: "main",
+            buffers: &[ParticleInstance::desc()],
+        },
+        fragment: Some(wgpu::FragmentState {
+            module: shader,
+            entry_point: "main",
+            targets: &[wgpu::ColorTargetState {
+                format,
+                blend: Some(wgpu::BlendState {
+                    color: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::SrcAlpha,
+                        dst_factor,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                    alpha: wgpu::BlendComponent {
+                        src_factor: wgpu::BlendFactor::Zero,
+                        dst_factor: wgpu::BlendFactor::One,
+                        operation: wgpu::BlendOperation::Add,
+                    },
+                }),
+                write_mask: wgpu::ColorWrite::ALL,
+            }],
+        }),
+        primitive: wgpu::PrimitiveState {
+            topology: wgpu::PrimitiveTopology::TriangleStrip,
+            strip_index_format: None,
+            front_face: wgpu::FrontFace::Ccw,
+            cull_mode: None,
+            clamp_depth: false,
+            conservative: false,
+            polygon_mode: wgpu::PolygonMode::Fill,
+        },
+        depth_stencil: Some(wgpu::DepthStencilState {
+            format: texture::Texture::DEPTH_FORMAT,
+            depth_write_enabled: false,
+            depth_compare: wgpu::CompareFunction::Less,
+            stencil: wgpu::StencilState::default(),
+            bias: wgpu::DepthBiasState::default(),
+        }),
+        multisample: wgpu::MultisampleState {
+            count: sample_count,
+            mask: !0,
+            alpha_to_coverage_enabled: false,
+        },
+    })
+}
+
+fn create_fog_bind_group(device: &wgpu::Device, layout: &wgpu::BindGroupLayout, fog_texture: &texture::Texture) -> wgpu::BindGroup {
+    device.create_bind_group(&wgpu::BindGroupDescriptor {
+        layout,
+        entries: &[
+            wgpu::BindGroupEntry {
+                binding: 0,
+                resource: wgpu::BindingResource::TextureView(&fog_texture.view),
+            },
+            wgpu::BindGroupEntry {
+                binding: 1,
+                resource: wgpu::BindingResource::Sampler(&fog_texture.sampler),
+            },
+        ],
+        label: Some("fog_bind_group"),
+    })
+}
+
 fn create_buffer_init(device: &mut wgpu::Device, label: &str, contents: &[impl bytemuck::Pod], usage: wgpu::BufferUsage) -> wgpu::Buffer {
     device.create_buffer_init(
         &wgpu::util::BufferInitDescriptor {
@@ -244,72 +1115,162 @@ fn create_buffer_init(device: &mut wgpu::Device, label: &str, contents: &[impl b
 struct ModelBuffers {
     vertex_buffer: wgpu::Buffer,
     index_buffer: wgpu::Buffer,
-    num_triangles: u16,
+    /// Most detailed first
+    lods: Vec<LodBuffers>,
+    /// Bounding radius of the most detailed level
+    radius: f32,
 
+    /// The instances in view, as of the last upload
     instance_buffer: wgpu::Buffer,
+    /// Where each level's instances are in the instance buffer
+    instance_ranges: Vec<Range<u32>>,
+    /// One draw per level, of that level's instances
+    indirect_buffer: wgpu::Buffer,
+    /// Instances the buffer has room for
+    instance_capacity: usize,
     instance_buffer_dirty: bool,
-    instances: DenseMap<Instance>,
+    /// Hands out the keys in instance handles
+    keys: Entities,
+    instances: Storage<ModelInstance>,
 }
 
-pub struct Model {
-    vertices: Vec<Vertex>,
-    indices: Vec<u16>,
+/// Bytes before the joint matrices in a joint buffer, holding the joint count
+const JOINTS_HEADER_SIZE: wgpu::BufferAddress = 16;
+
+struct SkinnedModelBuffers {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    num_indices: u32,
+    rig: Rig,
+    /// Matrices per instance in the joint buffer
+    joint_count: usize,
+
+    keys: Entities,
+    instances: Storage<(ModelInstance, AnimationState)>,
+    instance_buffer: wgpu::Buffer,
+    /// The joint count, then every instance's joint matrices in instance order
+    joint_buffer: wgpu::Buffer,
+    joints_bind_group: wgpu::BindGroup,
+    /// Instances the buffers have room for
+    capacity: usize,
+    /// Instances in the buffers as of the last `animate`
+    uploaded: u32,
 }
 
-/// Element in the vertex buffer
-#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
-#[repr(C)]
-struct Vertex {
-    position: [f32; 3],
-    normal: [f32; 3],
+/// A baked model, whose instances all draw in one call
+struct CrowdBuffers {
+    vertex_buffer: wgpu::Buffer,
+    index_buffer: wgpu::Buffer,
+    num_indices: u32,
+    textures: AnimationTextures,
+
+    keys: Entities,
+    instances: Storage<VatInstance>,
+    instance_buffer: wgpu::Buffer,
+    instance_capacity: usize,
+    instance_buffer_dirty: bool,
+    /// Instances in the buffer as of the last upload
+    uploaded: u32,
 }
 
-/// Element in the instance buffer
-#[derive(Clone, Copy, bytemuck::Zeroable, bytemuck::Pod)]
+/// Layout `draw_indexed_indirect` reads its arguments in
 #[repr(C)]
-struct Instance {
-    position: [f32; 3],
-    color: [f32; 3],
+#[derive(Debug, Default, Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct DrawIndexedIndirect {
+    index_count: u32,
+    instance_count: u32,
+    first_index: u32,
+    base_vertex: i32,
+    first_instance: u32,
+}
+
+/// Where one level of detail is in its model's buffers
+struct LodBuffers {
+    indices: Range<u32>,
+    base_vertex: i32,
+    min_size: f32,
 }
 
-struct DenseMap<T> {
-    // The dense block of stored values
-    values: Vec<T>,
-    // Same size as values, each key indexes into indices
-    keys: Vec<usize>,
-    // Maps keys to indexes in values
-    indices: Slab<usize>,
+/// A mesh, with simpler versions of it to draw when it's small on screen
+pub struct Model {
+    /// Most detailed first
+    lods: Vec<Lod>,
+}
+
+struct Lod {
+    vertices: Vec<MeshVertex>,
+    indices: Vec<u16>,
+    /// Drawn at this many pixels across and up, until a more detailed level takes over
+    min_size: f32,
 }
 
-impl<T> DenseMap<T> {
-    fn new() -> Self {
+impl Model {
+    fn from_mesh(vertices: Vec<MeshVertex>, indices: Vec<u16>) -> Self {
         Self {
-            values: Vec::new(),
-            keys: Vec::new(),
-            indices: Slab::new(),
+            lods: vec![Lod { vertices, indices, min_size: 0. }],
         }
     }
 
-    fn values(&self) -> &[T] {
-        return &self.values
+    /// Draw `simpler` instead once this model is under `size` pixels across
+    /// on screen. Levels are added from the most detailed down, so `size`
+    /// has to be smaller each time.
+    pub fn with_lod(mut self, size: f32, simpler: Model) -> Self {
+        let levels = self.lods.len();
+        assert!(levels < 2 || size < self.lods[levels - 2].min_size, "Levels of detail have to get smaller");
+        self.lods[levels - 1].min_size = size;
+        self.lods.extend(simpler.lods);
+        self
     }
 
-    fn insert(&mut self, value: T) -> usize {
-        let index = self.values.len();
-        let key = self.indices.insert(index);
-        self.values.push(value);
-        self.keys.push(key);
-        key
+    fn bounding_radius(&self) -> f32 {
+        use cgmath::InnerSpace;
+        self.lods[0].vertices.iter()
+            .map(|vertex| cgmath::Vector3::from(vertex.position).magnitude())
+            .fold(0., f32::max)
     }
 
-    fn remove(&mut self, key: usize) {
-        let index = *self.indices.get(key).expect("The key does not exist");
+    /// A box around the origin, `half_extents` from the center along each axis
+    pub fn cuboid(half_extents: [f32; 3]) -> Self {
+        // Normal, then the two axes spanning the face, so the corners wind counter-clockwise
+        let faces: [([f32; 3], [f32; 3], [f32; 3]); 6] = [
+            ([1., 0., 0.], [0., 1., 0.], [0., 0., 1.]),
+            ([-1., 0., 0.], [0., 0., 1.], [0., 1., 0.]),
+            ([0., 1., 0.], [0., 0., 1.], [1., 0., 0.]),
+            ([0., -1., 0.], [1., 0., 0.], [0., 0., 1.]),
+            ([0., 0., 1.], [1., 0., 0.], [0., 1., 0.]),
+            ([0., 0., -1.], [0., 1., 0.], [1., 0., 0.]),
+        ];
 
-        let swap_key = *self.keys.last().unwrap();
-        self.indices[swap_key] = index;
+        let mut vertices = Vec::new();
+        let mut indices = Vec::new();
+        for (normal, u, v) in faces.iter() {
+            let base = vertices.len() as u16;
+            for (su, sv) in [(-1., -1.), (1., -1.), (1., 1.), (-1., 1.)].iter() {
+                let corner = |axis: usize| (normal[axis] + u[axis] * su + v[axis] * sv) * half_extents[axis];
+                vertices.push(MeshVertex {
+                    position: [corner(0), corner(1), corner(2)],
+                    normal: *normal,
+                    tex_coords: [(su + 1.) / 2., (sv + 1.) / 2.],
+                });
+            }
+            indices.extend_from_slice(&[base, base + 1, base + 2, base + 2, base + 3, base]);
+        }
+
+        Self::from_mesh(vertices, indices)
+    }
 
-        self.values.swap_remove(index);
-        self.keys.swap_remove(index);
-        self.indices.remove(key);
+    /// This model with `other` moved by `offset` added to it, for building
+    /// shapes out of boxes. Neither can have levels of detail yet.
+    pub fn merge(mut self, other: Model, offset: [f32; 3]) -> Self {
+        assert!(self.lods.len() == 1 && other.lods.len() == 1, "Merge models before adding levels of detail");
+        let lod = &mut self.lods[0];
+        let other = other.lods.into_iter().next().unwrap();
+        let base = lod.vertices.len() as u16;
+        lod.vertices.extend(other.vertices.into_iter().map(|vertex| MeshVertex {
+            position: [vertex.position[0] + offset[0], vertex.position[1] + offset[1], vertex.position[2] + offset[2]],
+            ..vertex
+        }));
+        lod.indices.extend(other.indices.into_iter().map(|index| base + index));
+        self
     }
 }
@@ -0,0 +1,178 @@
+//! Recording matches as their command stream, and playing them back
+//!
+//! A replay is the map, the seed, the match rules, the mission script if there
+//! was one, and every command, stored in a `container` with magic `RTSR`. Since the
+//! simulation is deterministic that's enough to rebuild every tick of the match.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::command::Command;
+use super::container::{self, ContainerError};
+use super::lifecycle::MatchRules;
+use super::map::Map;
+use super::script::{Mission, MissionScript, ScriptError, ScriptEvent};
+use super::sim::World;
+
+const MAGIC: [u8; 4] = *b"RTSR";
+pub const VERSION: u32 = 1;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Replay {
+    pub map: Map,
+    pub seed: u64,
+    pub rules: MatchRules,
+    pub script: Option<MissionScript>,
+    /// Sorted by tick
+    pub commands: Vec<Command>,
+    /// Number of ticks the match ran for
+    pub length: u64,
+}
+
+impl Replay {
+    pub fn new(map: Map, seed: u64, rules: MatchRules) -> Self {
+        Self {
+            map,
+            seed,
+            rules,
+            script: None,
+            commands: Vec::new(),
+            length: 0,
+        }
+    }
+
+    pub fn record(&mut self, command: Command) {
+        assert!(self.commands.last().is_none_or(|last| last.tick <= command.tick), "Commands must be recorded in tick order");
+        self.length = self.length.max(command.tick);
+        self.commands.push(command);
+    }
+
+    /// Note that the match has run through `tick`
+    pub fn extend_to(&mut self, tick: u64) {
+        self.length = self.length.max(tick);
+    }
+
+    pub fn save(&self, path: impl AsRef<Path>) -> Result<(), ContainerError> {
+        let mut writer = BufWriter::new(File::create(path)?);
+        self.write(&mut writer)?;
+        writer.flush()?;
+        Ok(())
+    }
+
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ContainerError> {
+        Self::read(&mut BufReader::new(File::open(path)?))
+    }
+
+    pub fn write(&self, writer: &mut impl Write) -> Result<(), ContainerError> {
+        let payload = bincode::serialize(self)?;
+        container::write(writer, MAGIC, VERSION, &payload)?;
+        Ok(())
+    }
+
+    pub fn read(reader: &mut impl Read) -> Result<Self, ContainerError> {
+        let (_version, payload) = container::read(reader, MAGIC, VERSION)?;
+        Ok(bincode::deserialize(&payload)?)
+    }
+}
+
+/// Re-runs a replay one tick at a time
+pub struct Playback {
+    replay: Replay,
+    world: World,
+    mission: Option<Mission>,
+    /// Mission output not yet collected
+    events: Vec<ScriptEvent>,
+    next_command: usize,
+}
+
+impl Playback {
+    pub fn new(replay: Replay) -> Self {
+        let (world, mission) = Self::start(&replay);
+        let mut playback = Self {
+            replay,
+            world,
+            mission: None,
+            events: Vec::new(),
+            next_command: 0,
+        };
+        playback.set_mission(mission);
+        playback
+    }
+
+    fn start(replay: &Replay) -> (World, Option<Result<Mission, ScriptError>>) {
+        let mut world = World::with_rules(replay.map.clone(), replay.seed, replay.rules);
+        let mission = replay.script.as_ref().map(|script| Mission::start(script, &mut world));
+        (world, mission)
+    }
+
+    fn set_mission(&mut self, mission: Option<Result<Mission, ScriptError>>) {
+        self.mission = match mission {
+            Some(Ok(mission)) => Some(mission),
+            Some(Err(err)) => {
+                self.events.push(ScriptEvent::Error(err));
+                None
+            }
+            None => None,
+        };
+    }
+
+    /// Mission messages, results and errors since the last call
+    pub fn take_events(&mut self) -> Vec<ScriptEvent> {
+        let mut events = std::mem::take(&mut self.events);
+        if let Some(mission) = &mut self.mission {
+            events.extend(mission.take_events());
+        }
+        events
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn length(&self) -> u64 {
+        self.replay.length
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.world.tick >= self.replay.length
+    }
+
+    pub fn step(&mut self) {
+        if self.is_finished() {
+            return;
+        }
+        while let Some(command) = self.replay.commands.get(self.next_command) {
+            if command.tick > self.world.tick {
+                break;
+            }
+            self.world.apply(command);
+            self.next_command += 1;
+        }
+        self.world.step();
+        if let Some(mission) = &mut self.mission {
+            mission.update(&mut self.world);
+        }
+    }
+
+    /// Jump to `tick`. There's no way to run the simulation backwards, so
+    /// seeking back starts over from the beginning and simulates forwards.
+    pub fn seek(&mut self, tick: u64) {
+        let tick = tick.min(self.replay.length);
+        if tick < self.world.tick {
+            let (world, mission) = Self::start(&self.replay);
+            self.world = world;
+            self.set_mission(mission);
+            self.next_command = 0;
+        }
+        while self.world.tick < tick {
+            self.step();
+        }
+        // Messages from the ticks skipped over are stale
+        if let Some(mission) = &mut self.mission {
+            mission.take_events();
+        }
+    }
+}
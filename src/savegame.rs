@@ -0,0 +1,68 @@
+//! Mid-match saves
+//!
+//! A save is the whole `World` in a `container` with magic `RTSS`, including
+//! the RNG state, followed by the state of any computer players, so a loaded
+//! game plays out exactly as the original would have.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use super::ai::Ai;
+use super::container::{self, ContainerError};
+use super::sim::World;
+
+const MAGIC: [u8; 4] = *b"RTSS";
+pub const VERSION: u32 = 3;
+/// Older saves are from before units kept their paths (version 1) or the
+/// computer players were saved (version 2), and aren't migrated: a match is
+/// short enough to start again
+const OLDEST_VERSION: u32 = 3;
+
+pub fn save(world: &World, ais: &[Ai], path: impl AsRef<Path>) -> Result<(), ContainerError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(world, ais, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<(World, Vec<Ai>), ContainerError> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn write(world: &World, ais: &[Ai], writer: &mut impl Write) -> Result<(), ContainerError> {
+    let payload = bincode::serialize(&(world, ais))?;
+    container::write(writer, MAGIC, VERSION, &payload)?;
+    Ok(())
+}
+
+pub fn read(reader: &mut impl Read) -> Result<(World, Vec<Ai>), ContainerError> {
+    let (version, payload) = container::read(reader, MAGIC, VERSION)?;
+    if version < OLDEST_VERSION {
+        return Err(ContainerError::UnsupportedVersion(version));
+    }
+    Ok(bincode::deserialize(&payload)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lifecycle::MatchRules;
+    use crate::mapgen::{self, MapGenParams};
+
+    #[test]
+    fn saves_from_older_versions_are_rejected() {
+        let map = mapgen::generate(&MapGenParams { seed: 1, size: 64, players: 2 });
+        let world = World::with_rules(map, 1, MatchRules::default());
+        let mut bytes = Vec::new();
+        for version in 1..OLDEST_VERSION {
+            bytes.clear();
+            container::write(&mut bytes, MAGIC, version, &bincode::serialize(&world).unwrap()).unwrap();
+            assert!(matches!(read(&mut bytes.as_slice()), Err(ContainerError::UnsupportedVersion(v)) if v == version));
+        }
+
+        bytes.clear();
+        write(&world, &[], &mut bytes).unwrap();
+        assert_eq!(read(&mut bytes.as_slice()).unwrap().0.tick, world.tick);
+    }
+}
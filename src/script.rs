@@ -0,0 +1,447 @@
+//! Mission scripting in Rhai
+//!
+//! A map can come with a script next to it, with the same name and a `.rhai`
+//! extension. The top level of the script runs once when the mission starts,
+//! and mostly registers triggers. Triggers are checked at the end of every
+//! tick, so a mission is as deterministic as the rest of the simulation and
+//! replays run it again rather than recording what it did.
+//!
+//! Functions available to scripts:
+//!
+//! | function                             | does                                            |
+//! |--------------------------------------|-------------------------------------------------|
+//! | `tick()`                             | current tick                                    |
+//! | `resources(player)`                  | a player's resources                            |
+//! | `unit_count(player)`                 | units a player owns                             |
+//! | `unit_exists(unit)`                  | whether a unit is alive                         |
+//! | `unit_owner(unit)`                   | owning player, -1 if it's gone                  |
+//! | `unit_x(unit)`, `unit_y(unit)`       | position                                        |
+//! | `spawn(kind, player, x, y)`          | create a unit, returning its id                 |
+//! | `give_resources(player, amount)`     |                                                 |
+//! | `order_move(player, units, x, y)`    | give orders as `player`                         |
+//! | `order_attack(player, units, target)`|                                                 |
+//! | `order_stop(player, units)`          |                                                 |
+//! | `message(text)`                      | show a message to everyone                      |
+//! | `victory(player)`, `defeat(player)`  | end the match for a player                      |
+//! | `on_enter(x0, y0, x1, y1, callback)` | `callback(unit)` when a unit enters a rectangle |
+//! | `after(ticks, callback)`             | `callback()` once, `ticks` from now             |
+//! | `every(ticks, callback)`             | `callback()` every `ticks`                      |
+//! | `on_killed(callback)`                | `callback(unit, player)` when a unit dies       |
+//!
+//! Unit kinds are `"worker"`, `"soldier"`, `"headquarters"` and `"barracks"`.
+//! A trigger whose callback fails is reported and removed; the match goes on.
+//!
+//! The script's own variables and triggers aren't part of a savegame, so the
+//! game doesn't quicksave or quickload while a mission is running.
+
+use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+use cgmath::Vector2;
+use rhai::{Array, Dynamic, Engine, EvalAltResult, FnPtr, Scope, AST};
+use serde::{Deserialize, Serialize};
+
+use super::command::{Command, CommandKind};
+use super::lifecycle;
+use super::math::Vec2;
+use super::sim::{PlayerId, UnitId, UnitKind, World};
+
+/// Rhai operations one call may take before it's assumed to be stuck in a loop
+const MAX_OPERATIONS: u64 = 1_000_000;
+
+/// The script that goes with the map at `map_path`
+pub fn script_path(map_path: impl AsRef<Path>) -> PathBuf {
+    map_path.as_ref().with_extension("rhai")
+}
+
+/// A mission script's source. Stored in replays so they can run it again.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MissionScript {
+    /// File name, for error messages
+    pub name: String,
+    pub source: String,
+}
+
+impl MissionScript {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self, ScriptError> {
+        let path = path.as_ref();
+        let name = path.display().to_string();
+        match std::fs::read_to_string(path) {
+            Ok(source) => Ok(Self { name, source }),
+            Err(err) => Err(ScriptError { file: name, line: None, message: err.to_string() }),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct ScriptError {
+    pub file: String,
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+impl ScriptError {
+    fn eval(file: &str, mut err: Box<EvalAltResult>) -> Self {
+        let position = err.take_position();
+        Self { file: file.to_string(), line: position.line(), message: err.to_string() }
+    }
+}
+
+impl fmt::Display for ScriptError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ScriptError { file, line: Some(line), message } => write!(f, "{}:{}: {}", file, line, message),
+            ScriptError { file, line: None, message } => write!(f, "{}: {}", file, message),
+        }
+    }
+}
+
+impl std::error::Error for ScriptError {}
+
+/// Something a mission did that the game should show or act on
+#[derive(Clone, Debug)]
+pub enum ScriptEvent {
+    Message(String),
+    Victory(PlayerId),
+    Defeat(PlayerId),
+    Error(ScriptError),
+}
+
+enum Action {
+    Spawn { kind: UnitKind, owner: PlayerId, position: Vector2<f32> },
+    GiveResources { player: PlayerId, amount: u32 },
+    Command { player: PlayerId, kind: CommandKind },
+    Declare { player: PlayerId, won: bool },
+}
+
+enum Condition {
+    /// Units inside the rectangle as of the last check
+    Enter { min: Vector2<f32>, max: Vector2<f32>, inside: BTreeSet<UnitId> },
+    Timer { at: u64, repeat: Option<u64> },
+    Killed,
+}
+
+struct Trigger {
+    condition: Condition,
+    callback: FnPtr,
+}
+
+struct UnitInfo {
+    owner: PlayerId,
+    position: Vector2<f32>,
+}
+
+/// Shared between the mission and the functions registered with the engine.
+/// Scripts see the world as of the last `observe` and change it through
+/// `actions`, which are applied after each callback.
+#[derive(Default)]
+struct State {
+    tick: u64,
+    resources: Vec<u32>,
+    units: BTreeMap<UnitId, UnitInfo>,
+    /// Id the next spawned unit will get, counting spawns not yet applied
+    next_unit: UnitId,
+    actions: Vec<Action>,
+    events: Vec<ScriptEvent>,
+    /// Triggers registered since the mission last collected them
+    new_triggers: Vec<Trigger>,
+}
+
+impl State {
+    fn observe(&mut self, world: &World) {
+        self.tick = world.tick;
+        self.resources = world.players.iter().map(|player| player.resources).collect();
+        self.units = world.units.iter()
+            .map(|(id, unit)| (id, UnitInfo { owner: unit.owner, position: unit.position.to_f32() }))
+            .collect();
+        self.next_unit = world.next_unit_id();
+    }
+
+    fn player(&self, player: i64) -> Result<PlayerId, Box<EvalAltResult>> {
+        if player >= 0 && (player as usize) < self.resources.len() {
+            Ok(player as PlayerId)
+        } else {
+            Err(format!("there is no player {}", player).into())
+        }
+    }
+}
+
+pub struct Mission {
+    name: String,
+    engine: Engine,
+    ast: AST,
+    state: Rc<RefCell<State>>,
+    triggers: Vec<Trigger>,
+    /// Owner of every unit alive at the last update, to notice deaths
+    alive: BTreeMap<UnitId, PlayerId>,
+}
+
+impl Mission {
+    /// Compile `script` and run its top level against `world`
+    pub fn start(script: &MissionScript, world: &mut World) -> Result<Self, ScriptError> {
+        let state = Rc::new(RefCell::new(State::default()));
+        let mut engine = Engine::new();
+        engine.set_max_operations(MAX_OPERATIONS);
+        register_api(&mut engine, &state);
+
+        let ast = engine.compile(&script.source).map_err(|err| ScriptError {
+            file: script.name.clone(),
+            line: err.1.line(),
+            message: err.0.to_string(),
+        })?;
+
+        state.borrow_mut().observe(world);
+        engine.run_ast_with_scope(&mut Scope::new(), &ast).map_err(|err| ScriptError::eval(&script.name, err))?;
+
+        let mut mission = Self {
+            name: script.name.clone(),
+            engine,
+            ast,
+            state,
+            triggers: Vec::new(),
+            alive: BTreeMap::new(),
+        };
+        mission.apply(world);
+        mission.alive = world.units.iter().map(|(id, unit)| (id, unit.owner)).collect();
+        Ok(mission)
+    }
+
+    /// Check every trigger against the tick that just ran. Call after `World::step`.
+    pub fn update(&mut self, world: &mut World) {
+        let mut fired: Vec<(usize, Vec<Dynamic>)> = Vec::new();
+
+        let killed: Vec<(UnitId, PlayerId)> = self.alive.iter()
+            .filter(|(id, _)| !world.units.contains(**id))
+            .map(|(&id, &owner)| (id, owner))
+            .collect();
+
+        for (index, trigger) in self.triggers.iter_mut().enumerate() {
+            match &mut trigger.condition {
+                Condition::Enter { min, max, inside } => {
+                    let now_inside: BTreeSet<UnitId> = world.units.iter()
+                        .filter(|(_, unit)| {
+                            let position = unit.position.to_f32();
+                            position.x >= min.x && position.y >= min.y && position.x <= max.x && position.y <= max.y
+                        })
+                        .map(|(id, _)| id)
+                        .collect();
+                    for id in now_inside.difference(inside) {
+                        fired.push((index, vec![Dynamic::from(*id as i64)]));
+                    }
+                    *inside = now_inside;
+                }
+                Condition::Timer { at, repeat } => {
+                    if world.tick >= *at {
+                        fired.push((index, Vec::new()));
+                        if let Some(repeat) = repeat {
+                            *at += *repeat;
+                        }
+                    }
+                }
+                Condition::Killed => {
+                    for &(id, owner) in &killed {
+                        fired.push((index, vec![Dynamic::from(id as i64), Dynamic::from(owner as i64)]));
+                    }
+                }
+            }
+        }
+
+        let mut failed = BTreeSet::new();
+        for (index, args) in fired {
+            if failed.contains(&index) {
+                continue;
+            }
+            self.state.borrow_mut().observe(world);
+            let result = self.triggers[index].callback.call::<Dynamic>(&self.engine, &self.ast, args);
+            if let Err(err) = result {
+                self.state.borrow_mut().events.push(ScriptEvent::Error(ScriptError::eval(&self.name, err)));
+                failed.insert(index);
+            }
+            self.apply(world);
+        }
+
+        // Timers that have gone off for good, and triggers that failed
+        let mut index = 0;
+        let tick = world.tick;
+        self.triggers.retain(|trigger| {
+            let keep = !failed.contains(&index) && !matches!(trigger.condition, Condition::Timer { at, repeat: None } if tick >= at);
+            index += 1;
+            keep
+        });
+        self.alive = world.units.iter().map(|(id, unit)| (id, unit.owner)).collect();
+    }
+
+    /// Messages, results and errors since the last call
+    pub fn take_events(&mut self) -> Vec<ScriptEvent> {
+        std::mem::take(&mut self.state.borrow_mut().events)
+    }
+
+    /// Carry out what the script asked for, and pick up any triggers it registered
+    fn apply(&mut self, world: &mut World) {
+        let actions = std::mem::take(&mut self.state.borrow_mut().actions);
+        for action in actions {
+            match action {
+                Action::Spawn { kind, owner, position } => {
+                    world.spawn(kind, owner, Vec2::from_f32(position), 0.);
+                }
+                Action::GiveResources { player, amount } => {
+                    let resources = &mut world.players[player as usize].resources;
+                    *resources = resources.saturating_add(amount);
+                }
+                Action::Command { player, kind } => world.apply(&Command { tick: world.tick, player, kind }),
+                Action::Declare { player, won } => lifecycle::declare(world, player, won),
+            }
+        }
+        let new_triggers = std::mem::take(&mut self.state.borrow_mut().new_triggers);
+        self.triggers.extend(new_triggers);
+    }
+}
+
+fn parse_kind(name: &str) -> Result<UnitKind, Box<EvalAltResult>> {
+    match name {
+        "worker" => Ok(UnitKind::Worker),
+        "soldier" => Ok(UnitKind::Soldier),
+        "headquarters" => Ok(UnitKind::Headquarters),
+        "barracks" => Ok(UnitKind::Barracks),
+        _ => Err(format!("unknown unit kind {}", name).into()),
+    }
+}
+
+fn unit_ids(units: Array) -> Result<Vec<UnitId>, Box<EvalAltResult>> {
+    units.into_iter()
+        .map(|unit| match unit.as_int() {
+            Ok(id) if id >= 0 => Ok(id as UnitId),
+            _ => Err("units must be a list of unit ids".into()),
+        })
+        .collect()
+}
+
+fn register_api(engine: &mut Engine, state: &Rc<RefCell<State>>) {
+    let s = state.clone();
+    engine.register_fn("tick", move || s.borrow().tick as i64);
+    let s = state.clone();
+    engine.register_fn("resources", move |player: i64| -> Result<i64, Box<EvalAltResult>> {
+        let state = s.borrow();
+        Ok(state.resources[state.player(player)? as usize] as i64)
+    });
+    let s = state.clone();
+    engine.register_fn("unit_count", move |player: i64| {
+        s.borrow().units.values().filter(|unit| unit.owner as i64 == player).count() as i64
+    });
+    let s = state.clone();
+    engine.register_fn("unit_exists", move |unit: i64| unit >= 0 && s.borrow().units.contains_key(&(unit as UnitId)));
+    let s = state.clone();
+    engine.register_fn("unit_owner", move |unit: i64| {
+        s.borrow().units.get(&(unit as UnitId)).map_or(-1, |unit| unit.owner as i64)
+    });
+    let s = state.clone();
+    engine.register_fn("unit_x", move |unit: i64| -> Result<f64, Box<EvalAltResult>> {
+        match s.borrow().units.get(&(unit as UnitId)) {
+            Some(unit) => Ok(unit.position.x as f64),
+            None => Err(format!("there is no unit {}", unit).into()),
+        }
+    });
+    let s = state.clone();
+    engine.register_fn("unit_y", move |unit: i64| -> Result<f64, Box<EvalAltResult>> {
+        match s.borrow().units.get(&(unit as UnitId)) {
+            Some(unit) => Ok(unit.position.y as f64),
+            None => Err(format!("there is no unit {}", unit).into()),
+        }
+    });
+
+    let s = state.clone();
+    engine.register_fn("spawn", move |kind: &str, player: i64, x: f64, y: f64| -> Result<i64, Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let kind = parse_kind(kind)?;
+        let owner = state.player(player)?;
+        state.actions.push(Action::Spawn { kind, owner, position: Vector2::new(x as f32, y as f32) });
+        let id = state.next_unit;
+        state.next_unit += 1;
+        Ok(id as i64)
+    });
+    let s = state.clone();
+    engine.register_fn("give_resources", move |player: i64, amount: i64| -> Result<(), Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let player = state.player(player)?;
+        state.actions.push(Action::GiveResources { player, amount: amount.max(0).min(u32::MAX as i64) as u32 });
+        Ok(())
+    });
+
+    let s = state.clone();
+    engine.register_fn("order_move", move |player: i64, units: Array, x: f64, y: f64| -> Result<(), Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let player = state.player(player)?;
+        let kind = CommandKind::Move { units: unit_ids(units)?, target: Vec2::from_f32(Vector2::new(x as f32, y as f32)) };
+        state.actions.push(Action::Command { player, kind });
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("order_attack", move |player: i64, units: Array, target: i64| -> Result<(), Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let player = state.player(player)?;
+        let kind = CommandKind::Attack { units: unit_ids(units)?, target: target.max(0) as UnitId };
+        state.actions.push(Action::Command { player, kind });
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("order_stop", move |player: i64, units: Array| -> Result<(), Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let player = state.player(player)?;
+        let kind = CommandKind::Stop { units: unit_ids(units)? };
+        state.actions.push(Action::Command { player, kind });
+        Ok(())
+    });
+
+    let s = state.clone();
+    engine.register_fn("message", move |text: &str| s.borrow_mut().events.push(ScriptEvent::Message(text.to_string())));
+    let s = state.clone();
+    engine.register_fn("victory", move |player: i64| -> Result<(), Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let player = state.player(player)?;
+        state.actions.push(Action::Declare { player, won: true });
+        state.events.push(ScriptEvent::Victory(player));
+        Ok(())
+    });
+    let s = state.clone();
+    engine.register_fn("defeat", move |player: i64| -> Result<(), Box<EvalAltResult>> {
+        let mut state = s.borrow_mut();
+        let player = state.player(player)?;
+        state.actions.push(Action::Declare { player, won: false });
+        state.events.push(ScriptEvent::Defeat(player));
+        Ok(())
+    });
+
+    let s = state.clone();
+    engine.register_fn("on_enter", move |x0: f64, y0: f64, x1: f64, y1: f64, callback: FnPtr| {
+        let min = Vector2::new(x0.min(x1) as f32, y0.min(y1) as f32);
+        let max = Vector2::new(x0.max(x1) as f32, y0.max(y1) as f32);
+        let mut state = s.borrow_mut();
+        // Units already there haven't entered
+        let inside = state.units.iter()
+            .filter(|(_, unit)| unit.position.x >= min.x && unit.position.y >= min.y && unit.position.x <= max.x && unit.position.y <= max.y)
+            .map(|(&id, _)| id)
+            .collect();
+        let condition = Condition::Enter { min, max, inside };
+        state.new_triggers.push(Trigger { condition, callback });
+    });
+    let s = state.clone();
+    engine.register_fn("after", move |ticks: i64, callback: FnPtr| {
+        let mut state = s.borrow_mut();
+        let condition = Condition::Timer { at: state.tick + ticks.max(1) as u64, repeat: None };
+        state.new_triggers.push(Trigger { condition, callback });
+    });
+    let s = state.clone();
+    engine.register_fn("every", move |ticks: i64, callback: FnPtr| {
+        let mut state = s.borrow_mut();
+        let ticks = ticks.max(1) as u64;
+        let condition = Condition::Timer { at: state.tick + ticks, repeat: Some(ticks) };
+        state.new_triggers.push(Trigger { condition, callback });
+    });
+    let s = state.clone();
+    engine.register_fn("on_killed", move |callback: FnPtr| {
+        s.borrow_mut().new_triggers.push(Trigger { condition: Condition::Killed, callback });
+    });
+}
@@ -0,0 +1,328 @@
+//! Dedicated server
+//!
+//! The server owns the match. Clients send it their commands, it schedules
+//! each one for the next tick it runs, and sends every tick's commands back
+//! out. Clients rebuild the match from those the same way a replay is played
+//! back, so the server's world is the one that counts and nobody waits on the
+//! slowest connection.
+//!
+//! Everything is sent over UDP and resent until acknowledged.
+
+use std::collections::BTreeMap;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use super::command::{Command, CommandKind};
+use super::hash::fnv1a;
+use super::lifecycle::MatchRules;
+use super::map::Map;
+use super::replay::Replay;
+use super::sim::{PlayerId, World};
+use super::transport::MAX_DATAGRAM;
+
+pub const DEFAULT_PORT: u16 = 7777;
+pub(crate) const RESEND_INTERVAL: Duration = Duration::from_millis(50);
+const DISCONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+/// Most ticks or commands packed in one message
+pub(crate) const MAX_BATCH: usize = 32;
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum ClientMessage {
+    /// Ask for a slot in the match. `map_hash` has to match the server's map.
+    Join { map_hash: u64 },
+    /// The client's commands, numbered from `first`
+    Commands { first: u64, commands: Vec<CommandKind> },
+    /// Every tick up to and including `through` has arrived
+    Ack { through: u64 },
+    /// Nothing else to send, but still here
+    Ping,
+    Leave,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) enum ServerMessage {
+    Welcome { player: PlayerId, seed: u64, rules: MatchRules },
+    Rejected { reason: String },
+    /// The commands for consecutive ticks starting at `first_tick`
+    Ticks { first_tick: u64, ticks: Vec<Vec<Command>> },
+    /// Every command numbered below `next` has arrived
+    CommandAck { next: u64 },
+}
+
+/// Identifies a map, so clients can check they loaded the same one as the server
+pub fn map_hash(map: &Map) -> u64 {
+    fnv1a(&bincode::serialize(map).expect("Map serialization can't fail"))
+}
+
+struct Client {
+    addr: SocketAddr,
+    player: PlayerId,
+    /// Number of the next command expected from this client
+    next_command: u64,
+    /// Highest tick this client has acknowledged
+    acked: Option<u64>,
+    last_heard: Duration,
+    gone: bool,
+}
+
+pub struct Server {
+    socket: UdpSocket,
+    world: World,
+    seed: u64,
+    map_hash: u64,
+    replay: Replay,
+    /// How many clients the match waits for before starting
+    slots: usize,
+    clients: Vec<Client>,
+    /// Commands to apply at the next tick
+    pending: Vec<Command>,
+    /// Commands of past ticks that some client hasn't acknowledged
+    history: BTreeMap<u64, Vec<Command>>,
+    now: Duration,
+    last_resend: Duration,
+}
+
+impl Server {
+    /// Host a match on `map` for `slots` clients, who take players 0 through
+    /// `slots - 1`. Any other players on the map sit idle.
+    pub fn bind(addr: SocketAddr, map: Map, seed: u64, rules: MatchRules, slots: usize) -> io::Result<Self> {
+        let socket = UdpSocket::bind(addr)?;
+        socket.set_nonblocking(true)?;
+        Ok(Self {
+            socket,
+            world: World::with_rules(map.clone(), seed, rules),
+            seed,
+            map_hash: map_hash(&map),
+            replay: Replay::new(map, seed, rules),
+            slots: slots.min(u8::MAX as usize),
+            clients: Vec::new(),
+            pending: Vec::new(),
+            history: BTreeMap::new(),
+            now: Duration::from_millis(0),
+            last_resend: Duration::from_millis(0),
+        })
+    }
+
+    pub fn world(&self) -> &World {
+        &self.world
+    }
+
+    pub fn replay(&self) -> &Replay {
+        &self.replay
+    }
+
+    /// Queue a command that didn't come from a client, like a bot's
+    pub fn issue(&mut self, player: PlayerId, kind: CommandKind) {
+        self.pending.push(Command { tick: 0, player, kind });
+    }
+
+    /// Whether every slot has been filled and the match is running
+    pub fn is_started(&self) -> bool {
+        self.clients.len() >= self.slots
+    }
+
+    /// Whether the match had clients and they have all left
+    pub fn is_abandoned(&self) -> bool {
+        !self.clients.is_empty() && self.clients.iter().all(|client| client.gone)
+    }
+
+    /// Send and receive. Call often, whether or not the match has started.
+    pub fn update(&mut self, now: Duration) {
+        self.now = now;
+
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            let (len, from) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(_) => continue,
+            };
+            if let Ok(message) = bincode::deserialize(&buffer[..len]) {
+                self.handle(from, message);
+            }
+        }
+
+        // A slot filled in the lobby is kept until the match starts
+        if self.is_started() {
+            for client in &mut self.clients {
+                if !client.gone && now > client.last_heard + DISCONNECT_TIMEOUT {
+                    client.gone = true;
+                }
+            }
+        }
+
+        if now >= self.last_resend + RESEND_INTERVAL {
+            self.last_resend = now;
+            self.send_ticks();
+        }
+    }
+
+    /// Run one tick with whatever commands have arrived since the last
+    pub fn step(&mut self) {
+        let tick = self.world.tick;
+        let mut commands = std::mem::take(&mut self.pending);
+        for command in &mut commands {
+            command.tick = tick;
+            self.world.apply(command);
+            self.replay.record(command.clone());
+        }
+        self.world.step();
+        self.replay.extend_to(self.world.tick);
+        self.history.insert(tick, commands);
+
+        // Forget ticks every remaining client has
+        let oldest_needed = self.clients.iter()
+            .filter(|client| !client.gone)
+            .map(|client| client.acked.map_or(0, |acked| acked + 1))
+            .min()
+            .unwrap_or(self.world.tick);
+        self.history = self.history.split_off(&oldest_needed);
+
+        self.send_ticks();
+    }
+
+    fn handle(&mut self, from: SocketAddr, message: ClientMessage) {
+        let index = match self.clients.iter().position(|client| client.addr == from) {
+            Some(index) => index,
+            None => {
+                if let ClientMessage::Join { map_hash } = message {
+                    self.join(from, map_hash);
+                }
+                return;
+            }
+        };
+
+        let (seed, rules) = (self.seed, self.world.rules);
+        let client = &mut self.clients[index];
+        if client.gone {
+            return;
+        }
+        client.last_heard = self.now;
+        match message {
+            // Our welcome was lost
+            ClientMessage::Join { .. } => {
+                let player = client.player;
+                self.send(from, &ServerMessage::Welcome { player, seed, rules });
+            }
+            ClientMessage::Commands { first, commands } => {
+                for (number, kind) in (first..).zip(commands) {
+                    if number == client.next_command {
+                        self.pending.push(Command { tick: 0, player: client.player, kind });
+                        client.next_command += 1;
+                    }
+                }
+                let next = client.next_command;
+                self.send(from, &ServerMessage::CommandAck { next });
+            }
+            ClientMessage::Ack { through } => {
+                client.acked = Some(client.acked.map_or(through, |acked| acked.max(through)));
+            }
+            ClientMessage::Ping => (),
+            ClientMessage::Leave => client.gone = true,
+        }
+    }
+
+    /// Give a new client the next slot, if the map matches and there's one left
+    fn join(&mut self, from: SocketAddr, map_hash: u64) {
+        let reply = if map_hash != self.map_hash {
+            ServerMessage::Rejected { reason: "server is running a different map".to_string() }
+        } else if self.is_started() {
+            ServerMessage::Rejected { reason: "match is full".to_string() }
+        } else {
+            let player = self.clients.len() as PlayerId;
+            self.clients.push(Client {
+                addr: from,
+                player,
+                next_command: 0,
+                acked: None,
+                last_heard: self.now,
+                gone: false,
+            });
+            ServerMessage::Welcome { player, seed: self.seed, rules: self.world.rules }
+        };
+        self.send(from, &reply);
+    }
+
+    /// Send each client the ticks it hasn't acknowledged yet
+    fn send_ticks(&self) {
+        for client in self.clients.iter().filter(|client| !client.gone) {
+            let first_tick = client.acked.map_or(0, |acked| acked + 1);
+            let ticks: Vec<_> = self.history.range(first_tick..)
+                .take(MAX_BATCH)
+                .map(|(_, commands)| commands.clone())
+                .collect();
+            if !ticks.is_empty() {
+                self.send(client.addr, &ServerMessage::Ticks { first_tick, ticks });
+            }
+        }
+    }
+
+    fn send(&self, to: SocketAddr, message: &ServerMessage) {
+        let bytes = bincode::serialize(message).expect("Message serialization can't fail");
+        // Losing a packet is expected, so failures are treated the same way
+        let _ = self.socket.send_to(&bytes, to);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::client::Client;
+    use crate::mapgen::{self, MapGenParams};
+    use std::thread;
+
+    /// Connect a client, running the server at `now` until it's in
+    fn join(server: &mut Server, now: Duration) -> Client {
+        let addr = server.socket.local_addr().unwrap();
+        let map_hash = server.map_hash;
+        let joining = thread::spawn(move || Client::connect(addr, map_hash));
+        while !joining.is_finished() {
+            server.update(now);
+            thread::sleep(Duration::from_millis(1));
+        }
+        joining.join().unwrap().expect("Failed to join")
+    }
+
+    #[test]
+    fn late_join_doesnt_drop_the_first_client() {
+        let map = mapgen::generate(&MapGenParams { seed: 3, size: 64, players: 2 });
+        let addr = SocketAddr::from(([127, 0, 0, 1], 0));
+        let mut server = Server::bind(addr, map, 3, MatchRules::default(), 2).unwrap();
+
+        let mut now = Duration::from_millis(0);
+        let mut first = join(&mut server, now);
+        // Wait in the lobby for longer than the disconnect timeout
+        while now < DISCONNECT_TIMEOUT + Duration::from_secs(1) {
+            now += RESEND_INTERVAL;
+            first.update(now);
+            thread::sleep(Duration::from_millis(1));
+            server.update(now);
+        }
+        let mut second = join(&mut server, now);
+        assert!(server.is_started());
+
+        const TICKS: u64 = 20;
+        for _ in 0..TICKS {
+            server.step();
+        }
+        let mut received = [0; 2];
+        for _ in 0..200 {
+            now += RESEND_INTERVAL;
+            server.update(now);
+            thread::sleep(Duration::from_millis(1));
+            for (client, received) in [&mut first, &mut second].iter_mut().zip(&mut received) {
+                client.update(now);
+                while client.next_tick().is_some() {
+                    *received += 1;
+                }
+            }
+            if received == [TICKS; 2] {
+                break;
+            }
+        }
+        assert_eq!(received, [TICKS; 2]);
+    }
+}
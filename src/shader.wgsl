@@ -3,6 +3,8 @@
 [[block]]
 struct Uniforms {
     view_proj: mat4x4<f32>;
+    // (scale x, scale z, offset x, offset z) from world to fog texture coordinates
+    fog_transform: vec4<f32>;
 };
 [[group(0), binding(0)]]
 var<uniform> uniforms: Uniforms;
@@ -10,6 +12,7 @@ var<uniform> uniforms: Uniforms;
 struct VertexInput {
     [[location(0)]] position: vec3<f32>;
     [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] tex_coords: vec2<f32>;
 };
 
 struct InstanceInput {
@@ -21,11 +24,16 @@ struct InstanceInput {
     [[location(10)]] normal_matrix_1: vec3<f32>;
     [[location(11)]] normal_matrix_2: vec3<f32>;
 	[[location(12)]] color: vec3<f32>;
+    [[location(13)]] layer: u32;
 };
 
 struct VertexOutput {
     [[builtin(position)]] clip_position: vec4<f32>;
-    [[location(0)]] color: vec3<f32>;
+    [[location(0)]] light: vec3<f32>;
+    [[location(1)]] fog_coords: vec2<f32>;
+    [[location(2)]] tex_coords: vec2<f32>;
+    [[location(3)]] team_color: vec3<f32>;
+    [[location(4), interpolate(flat)]] layer: u32;
 };
 
 [[stage(vertex)]]
@@ -46,21 +54,41 @@ fn main(
     );
 
     var out: VertexOutput;
-    out.clip_position = uniforms.view_proj * model_matrix * vec4<f32>(model.position, 1.0);
+    let world_position = model_matrix * vec4<f32>(model.position, 1.0);
+    out.clip_position = uniforms.view_proj * world_position;
+    out.fog_coords = world_position.xz * uniforms.fog_transform.xy + uniforms.fog_transform.zw;
+    out.tex_coords = model.tex_coords;
+    out.team_color = instance.color;
+    out.layer = instance.layer;
 	
 	let world_normal = normal_matrix * model.normal;
 	let light_dir = normalize(vec3<f32>(1., 3., 0.5));
 
 	let ambient = 0.1;
 	let diffuse = max(dot(world_normal, light_dir), 0.);
-	out.color = (ambient + diffuse) * instance.color;
+	out.light = vec3<f32>(ambient + diffuse);
 
     return out;
 }
 
 // Fragment shader
 
+[[group(1), binding(0)]]
+var t_fog: texture_2d<f32>;
+[[group(1), binding(1)]]
+var s_fog: sampler;
+
+// Every unit skin, one per layer. Alpha marks where team color goes.
+// Layer 0 is plain white and all team color.
+[[group(2), binding(0)]]
+var t_skins: texture_2d_array<f32>;
+[[group(2), binding(1)]]
+var s_skins: sampler;
+
 [[stage(fragment)]]
 fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
-    return vec4<f32>(in.color, 1.0);
+    let fog = textureSample(t_fog, s_fog, in.fog_coords).r;
+    let skin = textureSample(t_skins, s_skins, in.tex_coords, i32(in.layer));
+    let albedo = mix(skin.rgb, skin.rgb * in.team_color, skin.a);
+    return vec4<f32>(in.light * albedo * fog, 1.0);
 }
@@ -0,0 +1,443 @@
+use std::f32::consts::FRAC_1_SQRT_2;
+use std::hash::Hasher;
+
+use cgmath::{InnerSpace, Vector2, Vector3};
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use serde::{Deserialize, Serialize};
+
+use super::balance::UnitStats;
+use super::command::{Command, CommandKind};
+use super::ecs::{Entities, Entity, Storage};
+use super::fog::FogOfWar;
+use super::hash::{self, Fnv1a};
+use super::lifecycle::{MatchRules, Standing, Stats};
+use super::map::Map;
+use super::math::{Real, Scalar, Vec2};
+use super::systems;
+
+pub type PlayerId = u8;
+pub type UnitId = Entity;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub enum UnitKind {
+    Worker,
+    Soldier,
+    Headquarters,
+    Barracks,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Weapon {
+    pub damage: u32,
+    pub range: f32,
+    /// Ticks between shots
+    pub cooldown: u32,
+}
+
+/// Numbers tuned for balance, like cost and health, are in the match's
+/// `StatTable` rather than here
+impl UnitKind {
+    pub const ALL: [UnitKind; 4] = [UnitKind::Worker, UnitKind::Soldier, UnitKind::Headquarters, UnitKind::Barracks];
+
+    pub fn is_building(self) -> bool {
+        matches!(self, UnitKind::Headquarters | UnitKind::Barracks)
+    }
+
+    /// Units this building can train
+    pub fn trains(self) -> &'static [UnitKind] {
+        match self {
+            UnitKind::Headquarters => &[UnitKind::Worker],
+            UnitKind::Barracks => &[UnitKind::Soldier],
+            UnitKind::Worker | UnitKind::Soldier => &[],
+        }
+    }
+
+    /// Buildings this unit can construct
+    pub fn builds(self) -> &'static [UnitKind] {
+        match self {
+            UnitKind::Worker => &[UnitKind::Headquarters, UnitKind::Barracks],
+            _ => &[],
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Tech {
+    /// +2 damage for every weapon
+    Weapons,
+    /// -1 damage from every hit taken
+    Armor,
+}
+
+impl Tech {
+    pub fn cost(self) -> u32 {
+        match self {
+            Tech::Weapons => 150,
+            Tech::Armor => 150,
+        }
+    }
+
+    pub fn research_time(self) -> u32 {
+        match self {
+            Tech::Weapons => 2400,
+            Tech::Armor => 2400,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub enum Order {
+    Idle,
+    Move { target: Vec2 },
+    Attack { target: UnitId },
+    /// Shuttle between a resource node and the nearest headquarters
+    Gather { node: usize, returning: bool },
+    Build { kind: UnitKind, position: Vec2 },
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Unit {
+    pub kind: UnitKind,
+    pub owner: PlayerId,
+    pub position: Vec2,
+    /// Facing in degrees. Purely cosmetic, so it's left out of the state hash.
+    pub rotation: f32,
+    pub sight_radius: f32,
+    pub health: u32,
+    pub order: Order,
+    /// Ticks until the weapon can fire again
+    pub cooldown: u32,
+    /// Resources carried by a worker
+    pub cargo: u32,
+    /// Units a building is waiting to train, the front one in progress
+    pub queue: Vec<UnitKind>,
+    /// Ticks spent on the front of `queue`
+    pub progress: u32,
+    /// Waypoints round whatever is in the way of where the order leads, the
+    /// next one last. Empty while the way is straight.
+    pub path: Vec<Vec2>,
+}
+
+/// A shot on its way to its target, which it follows until it hits
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Projectile {
+    pub owner: PlayerId,
+    pub target: UnitId,
+    pub position: Vec2,
+    pub damage: u32,
+}
+
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Research {
+    pub completed: Vec<Tech>,
+    pub current: Option<Tech>,
+    pub progress: u32,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Player {
+    pub resources: u32,
+    pub research: Research,
+    pub standing: Standing,
+    pub stats: Stats,
+}
+
+impl Player {
+    pub fn has(&self, tech: Tech) -> bool {
+        self.research.completed.contains(&tech)
+    }
+}
+
+const STARTING_RESOURCES: u32 = 100;
+const STARTING_WORKERS: u32 = 4;
+const SPAWN_DISTANCE: f32 = 3.;
+const MAX_QUEUE: usize = 5;
+
+/// The eight compass directions as unit vectors. A table rather than sin/cos,
+/// whose results vary between platforms.
+pub(crate) const DIRECTIONS: [(f32, f32); 8] = [
+    (1., 0.), (FRAC_1_SQRT_2, FRAC_1_SQRT_2), (0., 1.), (-FRAC_1_SQRT_2, FRAC_1_SQRT_2),
+    (-1., 0.), (-FRAC_1_SQRT_2, -FRAC_1_SQRT_2), (0., -1.), (FRAC_1_SQRT_2, -FRAC_1_SQRT_2),
+];
+
+/// The simulation state, advanced once per fixed time step.
+///
+/// Units and projectiles are entities, each a component in its own `Storage`,
+/// and the `systems` advance them a tick at a time.
+///
+/// Stepping is deterministic: all randomness comes from `rng`, entities are
+/// visited in id order, and players in index order. Two worlds built from the
+/// same map and seed and given the same inputs have the same `state_hash`
+/// after every tick.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct World {
+    pub map: Map,
+    pub units: Storage<Unit>,
+    pub projectiles: Storage<Projectile>,
+    pub players: Vec<Player>,
+    pub fog: FogOfWar,
+    pub rng: ChaCha8Rng,
+    pub tick: u64,
+    pub rules: MatchRules,
+    pub(crate) entities: Entities,
+}
+
+impl World {
+    /// Set up a match on `map` with the default rules
+    pub fn from_map(map: Map, seed: u64) -> Self {
+        Self::with_rules(map, seed, MatchRules::default())
+    }
+
+    /// Set up a match on `map`. Players without pre-placed units get a
+    /// headquarters and a few workers at their start location.
+    pub fn with_rules(map: Map, seed: u64, rules: MatchRules) -> Self {
+        let terrain = &map.terrain;
+        let num_players = map.starts.len();
+        // One fog cell per terrain cell
+        let fog = FogOfWar::new(terrain.width() - 1, terrain.height() - 1, terrain.cell_size(), num_players);
+        let player = Player {
+            resources: STARTING_RESOURCES,
+            research: Research::default(),
+            standing: Standing::Playing,
+            stats: Stats::default(),
+        };
+
+        let mut world = Self {
+            map,
+            units: Storage::new(),
+            projectiles: Storage::new(),
+            players: vec![player; num_players],
+            fog,
+            rng: ChaCha8Rng::seed_from_u64(seed),
+            tick: 0,
+            rules,
+            entities: Entities::default(),
+        };
+
+        for placed in world.map.units.clone() {
+            world.spawn(placed.kind, placed.owner, Vec2::from_f32(placed.position), placed.rotation);
+        }
+        for start in world.map.starts.clone() {
+            if world.units.values().iter().any(|unit| unit.owner == start.player) {
+                continue;
+            }
+            let origin = Vec2::from_f32(start.position);
+            world.spawn(UnitKind::Headquarters, start.player, origin, 0.);
+            for _ in 0..STARTING_WORKERS {
+                let position = world.spawn_point(origin);
+                world.spawn(UnitKind::Worker, start.player, position, 0.);
+            }
+        }
+
+        world
+    }
+
+    pub fn stats(&self, kind: UnitKind) -> &UnitStats {
+        self.rules.units.get(kind)
+    }
+
+    pub fn spawn(&mut self, kind: UnitKind, owner: PlayerId, position: Vec2, rotation: f32) -> UnitId {
+        let id = self.entities.create();
+        self.units.insert(id, Unit {
+            kind,
+            owner,
+            position,
+            rotation,
+            sight_radius: self.stats(kind).sight_radius,
+            health: self.stats(kind).max_health,
+            order: Order::Idle,
+            cooldown: 0,
+            cargo: 0,
+            queue: Vec::new(),
+            progress: 0,
+            path: Vec::new(),
+        });
+        id
+    }
+
+    /// The id the next unit to spawn will get
+    pub fn next_unit_id(&self) -> UnitId {
+        self.entities.peek()
+    }
+
+    /// Where a unit stands in 3D, resting on the terrain
+    pub fn ground_position(&self, unit: &Unit) -> Vector3<f32> {
+        let position = unit.position.to_f32();
+        let height = self.map.terrain.height_at(position);
+        Vector3::new(position.x, height, position.y)
+    }
+
+    /// Carry out a player's command. Commands the player isn't allowed to give,
+    /// like ordering someone else's units or training without the resources,
+    /// are ignored.
+    pub fn apply(&mut self, command: &Command) {
+        let player = command.player;
+        if player as usize >= self.players.len() {
+            return;
+        }
+
+        match &command.kind {
+            CommandKind::Move { units, target } => {
+                for unit in self.owned_mut(player, units) {
+                    if !unit.kind.is_building() {
+                        unit.order = Order::Move { target: *target };
+                    }
+                }
+            }
+            CommandKind::Attack { units, target } => {
+                if !self.units.contains(*target) {
+                    return;
+                }
+                let table = self.rules.units;
+                for unit in self.owned_mut(player, units) {
+                    if table.get(unit.kind).weapon.is_some() {
+                        unit.order = Order::Attack { target: *target };
+                    }
+                }
+            }
+            CommandKind::Gather { units, node } => {
+                if *node >= self.map.resources.len() {
+                    return;
+                }
+                for unit in self.owned_mut(player, units) {
+                    if unit.kind == UnitKind::Worker {
+                        unit.order = Order::Gather { node: *node, returning: false };
+                    }
+                }
+            }
+            CommandKind::Build { worker, kind, position } => {
+                match self.units.get_mut(*worker) {
+                    Some(unit) if unit.owner == player && unit.kind.builds().contains(kind) => {
+                        unit.order = Order::Build { kind: *kind, position: *position };
+                    }
+                    _ => (),
+                }
+            }
+            CommandKind::Train { building, kind } => {
+                let resources = self.players[player as usize].resources;
+                let cost = self.stats(*kind).cost;
+                let unit = match self.units.get_mut(*building) {
+                    Some(unit) if unit.owner == player => unit,
+                    _ => return,
+                };
+                if unit.kind.trains().contains(kind) && unit.queue.len() < MAX_QUEUE && resources >= cost {
+                    unit.queue.push(*kind);
+                    let owner = &mut self.players[player as usize];
+                    owner.resources -= cost;
+                    owner.stats.resources_spent += cost;
+                }
+            }
+            CommandKind::Research { tech } => {
+                let has_headquarters = self.units.values().iter().any(|unit| unit.owner == player && unit.kind == UnitKind::Headquarters);
+                let owner = &mut self.players[player as usize];
+                if has_headquarters && owner.research.current.is_none() && !owner.has(*tech) && owner.resources >= tech.cost() {
+                    owner.resources -= tech.cost();
+                    owner.stats.resources_spent += tech.cost();
+                    owner.research.current = Some(*tech);
+                }
+            }
+            CommandKind::Stop { units } => {
+                for unit in self.owned_mut(player, units) {
+                    unit.order = Order::Idle;
+                }
+            }
+        }
+    }
+
+    /// The units in `ids` that exist and belong to `player`
+    fn owned_mut<'a>(&'a mut self, player: PlayerId, ids: &'a [UnitId]) -> impl Iterator<Item = &'a mut Unit> {
+        self.units.iter_mut()
+            .filter(move |(id, unit)| unit.owner == player && ids.contains(id))
+            .map(|(_, unit)| unit)
+    }
+
+    pub fn step(&mut self) {
+        for system in &systems::SCHEDULE {
+            system(self);
+        }
+        self.tick += 1;
+    }
+
+    /// Somewhere near `origin` to place a new unit
+    pub(crate) fn spawn_point(&mut self, origin: Vec2) -> Vec2 {
+        let (x, y) = DIRECTIONS[self.rng.gen_range(0..DIRECTIONS.len())];
+        origin + Vec2::new(Real::from_f32(x), Real::from_f32(y)) * Real::from_f32(SPAWN_DISTANCE)
+    }
+
+    /// Hash of the state that decides how the match plays out. Cosmetic fields
+    /// and the fog, which is rebuilt from unit positions every tick, are left out.
+    ///
+    /// Players and units are hashed one at a time and the results summed, so
+    /// the hash doesn't depend on the order they happen to be stored in.
+    pub fn state_hash(&self) -> u64 {
+        let mut hasher = Fnv1a::new();
+        hasher.write_u64(self.tick);
+        hasher.write_u64(self.rng.get_word_pos() as u64);
+        hasher.write_u32(self.entities.peek());
+        for resource in &self.map.resources {
+            hasher.write_u32(resource.amount);
+        }
+
+        let players = self.players.iter()
+            .enumerate()
+            .map(|(id, player)| hash::mix(player_hash(id as PlayerId, player)));
+        let units = self.units.iter().map(|(id, unit)| hash::mix(unit_hash(id, unit)));
+        let projectiles = self.projectiles.iter().map(|(id, projectile)| hash::mix(projectile_hash(id, projectile)));
+        let entities = players.chain(units).chain(projectiles).fold(0u64, u64::wrapping_add);
+        hasher.write_u64(entities);
+        hasher.finish()
+    }
+
+    /// Units `player` is allowed to see: their own, and anyone else's standing in visible cells
+    pub fn visible_units(&self, player: PlayerId) -> impl Iterator<Item = (UnitId, &Unit)> {
+        let fog = &self.fog;
+        self.units.iter().filter(move |(_, unit)| {
+            unit.owner == player || fog.is_visible(player, unit.position.to_f32())
+        })
+    }
+
+    /// The closest unit to `point` within `radius` that `player` can see
+    pub fn pick(&self, player: PlayerId, point: Vector2<f32>, radius: f32) -> Option<UnitId> {
+        self.visible_units(player)
+            .map(|(id, unit)| (id, (unit.position.to_f32() - point).magnitude2()))
+            .filter(|&(_, distance2)| distance2 <= radius * radius)
+            .min_by(|a, b| a.1.partial_cmp(&b.1).unwrap())
+            .map(|(id, _)| id)
+    }
+}
+
+/// The part of `World::state_hash` contributed by one player
+pub fn player_hash(id: PlayerId, player: &Player) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_u8(id);
+    hasher.write_u32(player.resources);
+    hasher.write(&bincode::serialize(&(&player.research, player.standing, player.stats)).unwrap());
+    hasher.finish()
+}
+
+/// The part of `World::state_hash` contributed by one projectile
+pub fn projectile_hash(id: Entity, projectile: &Projectile) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_u32(id);
+    hasher.write(&bincode::serialize(&(projectile.owner, projectile.target, projectile.damage)).unwrap());
+    hasher.write_u64(projectile.position.x.hash_bits());
+    hasher.write_u64(projectile.position.y.hash_bits());
+    hasher.finish()
+}
+
+/// The part of `World::state_hash` contributed by one unit
+pub fn unit_hash(id: UnitId, unit: &Unit) -> u64 {
+    let mut hasher = Fnv1a::new();
+    hasher.write_u32(id);
+    hasher.write(&bincode::serialize(&(unit.kind, unit.owner, &unit.order, unit.health, unit.cooldown, unit.cargo, &unit.queue, unit.progress)).unwrap());
+    hasher.write_u64(unit.position.x.hash_bits());
+    hasher.write_u64(unit.position.y.hash_bits());
+    // The path steers later ticks, so a different one is a desync already
+    hasher.write_u64(unit.path.len() as u64);
+    for waypoint in &unit.path {
+        hasher.write_u64(waypoint.x.hash_bits());
+        hasher.write_u64(waypoint.y.hash_bits());
+    }
+    hasher.finish()
+}
@@ -0,0 +1,123 @@
+// Vertex shader for skinned meshes. Same as shader.wgsl, except each
+// vertex is first moved by the joints it follows.
+
+[[block]]
+struct Uniforms {
+    view_proj: mat4x4<f32>;
+    // (scale x, scale z, offset x, offset z) from world to fog texture coordinates
+    fog_transform: vec4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+[[block]]
+struct Joints {
+    // Joints per instance. Each instance's matrices follow the last one's.
+    count: u32;
+    matrices: array<mat4x4<f32>>;
+};
+[[group(3), binding(0)]]
+var<storage> joints: [[access(read)]] Joints;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] normal: vec3<f32>;
+    [[location(2)]] tex_coords: vec2<f32>;
+    [[location(3)]] joints: vec4<u32>;
+    [[location(4)]] weights: vec4<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+    [[location(9)]] normal_matrix_0: vec3<f32>;
+    [[location(10)]] normal_matrix_1: vec3<f32>;
+    [[location(11)]] normal_matrix_2: vec3<f32>;
+    [[location(12)]] color: vec3<f32>;
+    [[location(13)]] layer: u32;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] light: vec3<f32>;
+    [[location(1)]] fog_coords: vec2<f32>;
+    [[location(2)]] tex_coords: vec2<f32>;
+    [[location(3)]] team_color: vec3<f32>;
+    [[location(4), interpolate(flat)]] layer: u32;
+};
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+    [[builtin(instance_index)]] instance_index: u32,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    let first = instance_index * joints.count;
+    let m0 = joints.matrices[first + model.joints.x];
+    let m1 = joints.matrices[first + model.joints.y];
+    let m2 = joints.matrices[first + model.joints.z];
+    let m3 = joints.matrices[first + model.joints.w];
+    let position = vec4<f32>(model.position, 1.0);
+    let skinned_position = (m0 * position) * model.weights.x
+        + (m1 * position) * model.weights.y
+        + (m2 * position) * model.weights.z
+        + (m3 * position) * model.weights.w;
+    let normal = vec4<f32>(model.normal, 0.0);
+    let skinned_normal = (m0 * normal) * model.weights.x
+        + (m1 * normal) * model.weights.y
+        + (m2 * normal) * model.weights.z
+        + (m3 * normal) * model.weights.w;
+
+    var out: VertexOutput;
+    let world_position = model_matrix * skinned_position;
+    out.clip_position = uniforms.view_proj * world_position;
+    out.fog_coords = world_position.xz * uniforms.fog_transform.xy + uniforms.fog_transform.zw;
+    out.tex_coords = model.tex_coords;
+    out.team_color = instance.color;
+    out.layer = instance.layer;
+
+    let world_normal = normalize(normal_matrix * skinned_normal.xyz);
+    let light_dir = normalize(vec3<f32>(1., 3., 0.5));
+
+    let ambient = 0.1;
+    let diffuse = max(dot(world_normal, light_dir), 0.);
+    out.light = vec3<f32>(ambient + diffuse);
+
+    return out;
+}
+
+// Fragment shader
+
+[[group(1), binding(0)]]
+var t_fog: texture_2d<f32>;
+[[group(1), binding(1)]]
+var s_fog: sampler;
+
+// Every unit skin, one per layer. Alpha marks where team color goes.
+// Layer 0 is plain white and all team color.
+[[group(2), binding(0)]]
+var t_skins: texture_2d_array<f32>;
+[[group(2), binding(1)]]
+var s_skins: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let fog = textureSample(t_fog, s_fog, in.fog_coords).r;
+    let skin = textureSample(t_skins, s_skins, in.tex_coords, i32(in.layer));
+    let albedo = mix(skin.rgb, skin.rgb * in.team_color, skin.a);
+    return vec4<f32>(in.light * albedo * fog, 1.0);
+}
@@ -0,0 +1,52 @@
+//! A grid of buckets over the map for finding the units near a point
+//! without looking at every unit
+
+use cgmath::Vector2;
+
+use super::sim::{UnitId, World};
+
+pub(crate) struct Grid {
+    cell_size: f32,
+    width: i32,
+    height: i32,
+    /// Row-major, each bucket in unit id order
+    cells: Vec<Vec<UnitId>>,
+}
+
+impl Grid {
+    /// Bucket every living unit by where it stands now
+    pub fn new(world: &World, cell_size: f32) -> Self {
+        let extent = world.map.terrain.extent();
+        let width = (extent.x / cell_size).ceil().max(1.) as i32;
+        let height = (extent.y / cell_size).ceil().max(1.) as i32;
+        let mut grid = Self {
+            cell_size,
+            width,
+            height,
+            cells: vec![Vec::new(); (width * height) as usize],
+        };
+        for (id, unit) in world.units.iter().filter(|(_, unit)| unit.health > 0) {
+            let (x, y) = grid.cell(unit.position.to_f32());
+            grid.cells[(y * width + x) as usize].push(id);
+        }
+        grid
+    }
+
+    /// Units in the cells within `radius` of `center`. Further ones may be
+    /// included, so callers check the distance themselves. The order only
+    /// depends on where units stand, never on which thread is asking.
+    pub fn near(&self, center: Vector2<f32>, radius: f32) -> impl Iterator<Item = UnitId> + '_ {
+        let (min_x, min_y) = self.cell(center - Vector2::new(radius, radius));
+        let (max_x, max_y) = self.cell(center + Vector2::new(radius, radius));
+        (min_y..=max_y)
+            .flat_map(move |y| (min_x..=max_x).map(move |x| (y * self.width + x) as usize))
+            .flat_map(move |index| self.cells[index].iter().cloned())
+    }
+
+    /// The cell containing `position`, with anything off the map going to the nearest edge cell
+    fn cell(&self, position: Vector2<f32>) -> (i32, i32) {
+        let x = (position.x / self.cell_size).floor() as i32;
+        let y = (position.y / self.cell_size).floor() as i32;
+        (x.max(0).min(self.width - 1), y.max(0).min(self.height - 1))
+    }
+}
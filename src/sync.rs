@@ -0,0 +1,349 @@
+//! Mirrors the parts of the world that can be seen into renderer instances
+//!
+//! Every entity on screen holds an instance handle, kept in a `Storage` by
+//! the same id as its components. Each frame the handles are brought in line
+//! with the world: new entities get an instance, ones that moved have theirs
+//! updated, and ones that died or went out of sight lose theirs.
+//!
+//! Workers and soldiers can be drawn with an animated model instead of a box,
+//! either skinned or baked for crowds, playing whatever fits what they're doing.
+//!
+//! The same changes set off particle effects: a muzzle flash for every new
+//! shot, an explosion where a unit died, dust behind units on the move and
+//! smoke over badly damaged buildings.
+
+use cgmath::{Deg, Matrix3, Matrix4, Vector3};
+
+use super::animation::{AnimationKind, SkinnedMesh};
+use super::ecs::{Entity, Storage};
+use super::model::ModelInstance;
+use super::particles::{Effect, EmitterHandle, ParticleSystem};
+use super::renderer::{CrowdHandle, InstanceHandle, Model, Renderer, SkinnedHandle};
+use super::sim::{Order, PlayerId, Unit, UnitKind, World};
+use super::vat::{VatInstance, VertexAnimation};
+
+/// Team colors, by player
+const PLAYER_COLORS: [[f32; 3]; 8] = [
+    [0.2, 0.4, 1.],
+    [1., 0.25, 0.2],
+    [0.2, 0.8, 0.3],
+    [1., 0.85, 0.2],
+    [0.7, 0.3, 0.9],
+    [1., 0.55, 0.1],
+    [0.2, 0.9, 0.9],
+    [0.9, 0.9, 0.9],
+];
+const RESOURCE_COLOR: [f32; 3] = [0.3, 0.9, 0.8];
+/// Height projectiles fly at above the terrain
+const PROJECTILE_HEIGHT: f32 = 1.;
+/// Buildings smoke below this fraction of their health
+const SMOKE_HEALTH: f32 = 0.5;
+/// Buildings are drawn as a plain box below this many pixels across
+const BUILDING_DETAIL_SIZE: f32 = 24.;
+
+/// What workers and soldiers are drawn with
+pub enum UnitModel {
+    Boxes,
+    Skinned(SkinnedMesh),
+    Crowd(VertexAnimation),
+}
+
+/// `UnitModel` once it's been handed to the renderer
+enum AnimatedModel {
+    None,
+    Skinned(u16),
+    Crowd(u16),
+}
+
+pub struct RenderSync {
+    /// By `UnitKind`
+    unit_models: [u16; 4],
+    /// Skin layer for each unit kind, 0 if it has none
+    unit_skins: [u32; 4],
+    animated_model: AnimatedModel,
+    projectile_model: u16,
+    resource_model: u16,
+    /// A box two units across, for the tiles of the influence map overlay
+    overlay_model: u16,
+    overlay: Vec<InstanceHandle>,
+    units: Storage<InstanceHandle>,
+    projectiles: Storage<InstanceHandle>,
+    /// By resource node index
+    resources: Storage<InstanceHandle>,
+    /// Projectiles from this id on weren't there last update
+    next_projectile: Entity,
+    /// Units drawn with the animated model, and what each is playing
+    skinned: Storage<(SkinnedHandle, AnimationKind)>,
+    /// As `skinned`, with when each started playing
+    crowd: Storage<(CrowdHandle, AnimationKind, f32)>,
+    /// Where each unit on screen stood last update
+    positions: Storage<Vector3<f32>>,
+    dust: Storage<EmitterHandle>,
+    smoke: Storage<EmitterHandle>,
+}
+
+impl RenderSync {
+    pub fn new(renderer: &mut Renderer, unit_model: UnitModel) -> Self {
+        let mut unit_models = [0; 4];
+        for &kind in UnitKind::ALL.iter() {
+            let half_extents = match kind {
+                UnitKind::Worker => [0.3, 0.5, 0.3],
+                UnitKind::Soldier => [0.4, 0.7, 0.4],
+                UnitKind::Headquarters => [2., 1.5, 2.],
+                UnitKind::Barracks => [1.5, 1., 1.5],
+            };
+            let model = if kind.is_building() {
+                // A smaller block on the roof, left off once it'd be too small to see
+                let [x, y, z] = half_extents;
+                Model::cuboid(half_extents)
+                    .merge(Model::cuboid([x * 0.5, y * 0.25, z * 0.5]), [0., y * 1.25, 0.])
+                    .with_lod(BUILDING_DETAIL_SIZE, Model::cuboid(half_extents))
+            } else {
+                Model::cuboid(half_extents)
+            };
+            unit_models[kind as usize] = renderer.add_model(model);
+        }
+        let animated_model = match unit_model {
+            UnitModel::Boxes => AnimatedModel::None,
+            UnitModel::Skinned(mesh) => AnimatedModel::Skinned(renderer.add_skinned_model(mesh)),
+            UnitModel::Crowd(animation) => AnimatedModel::Crowd(renderer.add_crowd_model(&animation)),
+        };
+        Self {
+            unit_models,
+            unit_skins: [0; 4],
+            animated_model,
+            projectile_model: renderer.add_model(Model::cuboid([0.1, 0.1, 0.1])),
+            resource_model: renderer.add_model(Model::cuboid([0.6, 0.4, 0.6])),
+            overlay_model: renderer.add_model(Model::cuboid([1., 1., 1.])),
+            overlay: Vec::new(),
+            units: Storage::new(),
+            projectiles: Storage::new(),
+            resources: Storage::new(),
+            next_projectile: 0,
+            skinned: Storage::new(),
+            crowd: Storage::new(),
+            positions: Storage::new(),
+            dust: Storage::new(),
+            smoke: Storage::new(),
+        }
+    }
+
+    /// Texture unit kinds with the images at `paths`, one per kind in the
+    /// order of `UnitKind::ALL`. Kinds past the last path stay untextured.
+    pub fn load_skins(&mut self, renderer: &mut Renderer, paths: &[impl AsRef<std::path::Path>]) -> image::ImageResult<()> {
+        renderer.load_skins(paths)?;
+        for (layer, &kind) in UnitKind::ALL.iter().enumerate() {
+            self.unit_skins[kind as usize] = if layer < paths.len() { layer as u32 + 1 } else { 0 };
+        }
+        Ok(())
+    }
+
+    /// Where a unit on screen is, for particles attached to it
+    pub fn position(&self, unit: Entity) -> Option<Vector3<f32>> {
+        self.positions.get(unit).cloned()
+    }
+
+    /// Bring the instances in line with what `player` can see of `world`,
+    /// starting effects for what changed since last time. `time` is the
+    /// clock animations are played by, as later passed to `Renderer::animate`.
+    pub fn update(&mut self, world: &World, renderer: &mut Renderer, particles: &mut ParticleSystem, player: PlayerId, time: f32) {
+        // Has to be worked out before the effects move the positions on
+        let mut animations = Storage::new();
+        for (id, unit) in world.visible_units(player).filter(|(_, unit)| !unit.kind.is_building()) {
+            let moving = self.positions.get(id).is_some_and(|&last| last != world.ground_position(unit));
+            animations.insert(id, animation(unit, moving));
+        }
+        self.update_effects(world, particles, player);
+
+        let animated = !matches!(self.animated_model, AnimatedModel::None);
+        let (unit_models, unit_skins) = (self.unit_models, self.unit_skins);
+        let units = world.visible_units(player)
+            .filter(|(_, unit)| !animated || unit.kind.is_building())
+            .map(|(id, unit)| (id, unit_models[unit.kind as usize], unit_instance(world, unit, unit_skins)));
+        sync(&mut self.units, units, renderer);
+
+        let animated_units = world.visible_units(player)
+            .filter_map(|(id, unit)| Some((id, unit_instance(world, unit, unit_skins), *animations.get(id)?)));
+        match self.animated_model {
+            AnimatedModel::None => {}
+            AnimatedModel::Skinned(model) => sync_skinned(&mut self.skinned, model, animated_units, renderer, time),
+            AnimatedModel::Crowd(model) => sync_crowd(&mut self.crowd, model, animated_units, renderer, time),
+        }
+
+        let projectile_model = self.projectile_model;
+        let projectiles = world.projectiles.iter()
+            .filter(|(_, projectile)| world.fog.is_visible(player, projectile.position.to_f32()))
+            .map(|(id, projectile)| {
+                let position = projectile.position.to_f32();
+                let height = world.map.terrain.height_at(position) + PROJECTILE_HEIGHT;
+                let color = PLAYER_COLORS[projectile.owner as usize % PLAYER_COLORS.len()];
+                (id, projectile_model, instance(Vector3::new(position.x, height, position.y), 0., color))
+            });
+        sync(&mut self.projectiles, projectiles, renderer);
+
+        let resource_model = self.resource_model;
+        let resources = world.map.resources.iter()
+            .enumerate()
+            .filter(|(_, resource)| resource.amount > 0)
+            .map(|(index, resource)| {
+                let position = resource.position;
+                let height = world.map.terrain.height_at(position);
+                (index as Entity, resource_model, instance(Vector3::new(position.x, height, position.y), 0., RESOURCE_COLOR))
+            });
+        sync(&mut self.resources, resources, renderer);
+    }
+
+    /// Replace the debug overlay, as made by `InfluenceMap::debug_instances`.
+    /// Empty to hide it.
+    pub fn set_overlay(&mut self, instances: Vec<ModelInstance>, renderer: &mut Renderer) {
+        for handle in self.overlay.drain(instances.len().min(self.overlay.len())..) {
+            renderer.remove_instance(handle);
+        }
+        for (index, instance) in instances.into_iter().enumerate() {
+            match self.overlay.get(index) {
+                Some(&handle) => renderer.update_instance(handle, instance),
+                None => self.overlay.push(renderer.add_instance(self.overlay_model, instance)),
+            }
+        }
+    }
+
+    fn update_effects(&mut self, world: &World, particles: &mut ParticleSystem, player: PlayerId) {
+        // Ids only go up, so new projectiles are still where they were fired from
+        let next_projectile = self.next_projectile;
+        let fired = world.projectiles.iter()
+            .filter(|&(id, _)| id >= next_projectile)
+            .map(|(_, projectile)| projectile.position.to_f32())
+            .filter(|&position| world.fog.is_visible(player, position));
+        for position in fired {
+            particles.spawn(Effect::MuzzleFlash, Vector3::new(position.x, world.map.terrain.height_at(position), position.y));
+        }
+        if let Some(&last) = world.projectiles.entities().last() {
+            self.next_projectile = self.next_projectile.max(last + 1);
+        }
+
+        let mut positions = Storage::new();
+        for (id, unit) in world.visible_units(player) {
+            let position = world.ground_position(unit);
+            let moving = !unit.kind.is_building() && self.positions.get(id).is_some_and(|&last| last != position);
+            toggle(&mut self.dust, particles, id, Effect::Dust, moving);
+            let damaged = unit.kind.is_building() && (unit.health as f32) < world.stats(unit.kind).max_health as f32 * SMOKE_HEALTH;
+            toggle(&mut self.smoke, particles, id, Effect::Smoke, damaged);
+            positions.insert(id, position);
+        }
+
+        // Units gone from the world died. Ones that only went out of sight are still in it.
+        for (id, &position) in self.positions.iter() {
+            if !positions.contains(id) && world.units.get(id).is_none_or(|unit| unit.health == 0) {
+                particles.spawn(Effect::Explosion, position);
+            }
+        }
+        // The particle system ends emitters on units it can no longer find
+        self.dust.retain(|id, _| positions.contains(id));
+        self.smoke.retain(|id, _| positions.contains(id));
+        self.positions = positions;
+    }
+}
+
+/// Have `effect` attached to `entity` when `on`, and not otherwise
+fn toggle(emitters: &mut Storage<EmitterHandle>, particles: &mut ParticleSystem, entity: Entity, effect: Effect, on: bool) {
+    match (emitters.get(entity), on) {
+        (None, true) => {
+            emitters.insert(entity, particles.attach(effect, entity));
+        }
+        (Some(&handle), false) => {
+            particles.stop(handle);
+            emitters.remove(entity);
+        }
+        _ => (),
+    }
+}
+
+/// Give every entity in `visible` an up to date instance, and drop the
+/// instances of those no longer in it. `visible` has to be in entity order.
+fn sync(handles: &mut Storage<InstanceHandle>, visible: impl Iterator<Item = (Entity, u16, ModelInstance)>, renderer: &mut Renderer) {
+    let mut kept = Storage::new();
+    for (entity, model, instance) in visible {
+        let handle = match handles.get(entity) {
+            Some(&handle) => {
+                renderer.update_instance(handle, instance);
+                handle
+            }
+            None => renderer.add_instance(model, instance),
+        };
+        kept.insert(entity, handle);
+    }
+    for (_, &handle) in handles.iter().filter(|(entity, _)| !kept.contains(*entity)) {
+        renderer.remove_instance(handle);
+    }
+    *handles = kept;
+}
+
+/// As `sync`, for units drawn with a skinned model. Units switch animation
+/// when they start doing something else.
+fn sync_skinned(handles: &mut Storage<(SkinnedHandle, AnimationKind)>, model: u16, visible: impl Iterator<Item = (Entity, ModelInstance, AnimationKind)>, renderer: &mut Renderer, time: f32) {
+    let mut kept = Storage::new();
+    for (entity, instance, kind) in visible {
+        let handle = match handles.get(entity) {
+            Some(&(handle, playing)) => {
+                renderer.update_skinned_instance(handle, instance);
+                if playing != kind {
+                    renderer.play_animation(handle, kind, time);
+                }
+                handle
+            }
+            None => renderer.add_skinned_instance(model, instance, kind, time),
+        };
+        kept.insert(entity, (handle, kind));
+    }
+    for (_, &(handle, _)) in handles.iter().filter(|(entity, _)| !kept.contains(*entity)) {
+        renderer.remove_skinned_instance(handle);
+    }
+    *handles = kept;
+}
+
+/// As `sync`, for units drawn from baked crowd animation
+fn sync_crowd(handles: &mut Storage<(CrowdHandle, AnimationKind, f32)>, model: u16, visible: impl Iterator<Item = (Entity, ModelInstance, AnimationKind)>, renderer: &mut Renderer, time: f32) {
+    let mut kept = Storage::new();
+    for (entity, instance, kind) in visible {
+        let (handle, started) = match handles.get(entity) {
+            Some(&(handle, playing, started)) => {
+                // Carry on from where the animation is unless it changed
+                let started = if playing == kind { started } else { time };
+                renderer.update_crowd_instance(handle, VatInstance::new(instance, kind, started));
+                (handle, started)
+            }
+            None => (renderer.add_crowd_instance(model, VatInstance::new(instance, kind, time)), time),
+        };
+        kept.insert(entity, (handle, kind, started));
+    }
+    for (_, &(handle, _, _)) in handles.iter().filter(|(entity, _)| !kept.contains(*entity)) {
+        renderer.remove_crowd_instance(handle);
+    }
+    *handles = kept;
+}
+
+/// What a unit's animated model plays
+fn animation(unit: &Unit, moving: bool) -> AnimationKind {
+    match unit.order {
+        _ if moving => AnimationKind::Walk,
+        Order::Attack { .. } | Order::Gather { returning: false, .. } | Order::Build { .. } => AnimationKind::Attack,
+        _ => AnimationKind::Idle,
+    }
+}
+
+fn unit_instance(world: &World, unit: &Unit, skins: [u32; 4]) -> ModelInstance {
+    let color = PLAYER_COLORS[unit.owner as usize % PLAYER_COLORS.len()];
+    ModelInstance {
+        layer: skins[unit.kind as usize],
+        ..instance(world.ground_position(unit), unit.rotation, color)
+    }
+}
+
+fn instance(position: Vector3<f32>, rotation: f32, color: [f32; 3]) -> ModelInstance {
+    ModelInstance {
+        model: (Matrix4::from_translation(position) * Matrix4::from_angle_y(Deg(rotation))).into(),
+        normal: Matrix3::from_angle_y(Deg(rotation)).into(),
+        color,
+        layer: 0,
+    }
+}
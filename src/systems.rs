@@ -0,0 +1,438 @@
+//! The systems that advance the world by one tick
+//!
+//! Each system is a function over the parts of the world it needs, and
+//! `World::step` runs them in `SCHEDULE` order. Systems that go through
+//! entities visit them in id order, so a tick plays out the same everywhere.
+//!
+//! The heavy systems split the work across threads. Each unit's part is
+//! worked out in parallel from a world nothing is writing to, and only the
+//! results are written back, in id order, so the outcome never depends on how
+//! many threads there are or how they were scheduled.
+
+use rayon::prelude::*;
+
+use super::lifecycle;
+use super::math::{Real, Scalar, Vec2};
+use super::pathfinding;
+use super::sim::{Order, PlayerId, Projectile, Tech, Unit, UnitId, UnitKind, World};
+use super::spatial::Grid;
+
+/// Weapons that reach no further than this strike directly. Longer ranged
+/// ones fire a projectile.
+const MELEE_RANGE: f32 = 2.;
+/// Distance a projectile covers per tick
+const PROJECTILE_SPEED: f32 = 0.4;
+const CARRY_CAPACITY: u32 = 5;
+/// Ticks to mine one unit of resource
+const GATHER_TIME: u64 = 30;
+const INTERACT_RANGE: f32 = 1.5;
+/// Moving units closer than this push each other apart
+const UNIT_SPACING: f32 = 0.8;
+/// Share of the overlap undone each tick
+const AVOIDANCE_STRENGTH: f32 = 0.1;
+/// Cell size of the grids used to find nearby units
+const GRID_CELL_SIZE: f32 = 8.;
+
+pub(crate) const SCHEDULE: [fn(&mut World); 10] = [
+    targeting,
+    movement,
+    avoidance,
+    combat,
+    projectiles,
+    production,
+    research,
+    cleanup,
+    lifecycle::judge,
+    vision,
+];
+
+/// What a unit's order has it do this tick
+enum Plan {
+    Wait,
+    /// Drop the current order for this one
+    Reorder(Order),
+    Step { position: Vec2, rotation: f32, path: Vec<Vec2> },
+    /// Within reach of where the order leads
+    Arrived,
+}
+
+/// Carry out orders that involve going somewhere: moving, gathering,
+/// building, and closing in on an attack target. Every unit plans its move
+/// in parallel from the world as it stood at the start of the tick, then the
+/// plans are carried out one unit at a time in id order.
+fn movement(world: &mut World) {
+    let plans: Vec<(UnitId, Plan)> = {
+        let world = &*world;
+        world.units.par_iter().map(|(id, unit)| (id, plan(world, unit))).collect()
+    };
+    for (id, plan) in plans {
+        match plan {
+            Plan::Wait => {}
+            Plan::Reorder(order) => set_order(world, id, order),
+            Plan::Step { position, rotation, path } => {
+                let unit = &mut world.units[id];
+                unit.position = position;
+                unit.rotation = rotation;
+                unit.path = path;
+            }
+            Plan::Arrived => arrive(world, id),
+        }
+    }
+}
+
+fn plan(world: &World, unit: &Unit) -> Plan {
+    if unit.health == 0 {
+        return Plan::Wait;
+    }
+    let (target, range) = match unit.order {
+        Order::Idle => return Plan::Wait,
+        Order::Move { target } => (target, Real::from_f32(0.1)),
+        Order::Attack { target } => {
+            // Get within weapon range, giving up once the target is gone
+            let target = match world.units.get(target) {
+                Some(target) if target.health > 0 => target.position,
+                _ => return Plan::Reorder(Order::Idle),
+            };
+            match world.stats(unit.kind).weapon {
+                Some(weapon) => (target, Real::from_f32(weapon.range)),
+                None => return Plan::Reorder(Order::Idle),
+            }
+        }
+        Order::Gather { returning: true, .. } => {
+            let depot = world.units.values().iter()
+                .filter(|depot| depot.owner == unit.owner && depot.kind == UnitKind::Headquarters)
+                .map(|depot| depot.position)
+                .min_by(|&a, &b| (a - unit.position).magnitude2().partial_cmp(&(b - unit.position).magnitude2()).unwrap());
+            match depot {
+                Some(depot) => (depot, Real::from_f32(INTERACT_RANGE * 2.)),
+                None => return Plan::Reorder(Order::Idle),
+            }
+        }
+        Order::Gather { node, returning: false } => match world.map.resources.get(node) {
+            Some(resource) if resource.amount > 0 => (Vec2::from_f32(resource.position), Real::from_f32(INTERACT_RANGE)),
+            _ => return Plan::Reorder(if unit.cargo > 0 { Order::Gather { node, returning: true } } else { Order::Idle }),
+        },
+        Order::Build { position, .. } => (position, Real::from_f32(INTERACT_RANGE)),
+    };
+    step_towards(world, unit, target, range)
+}
+
+/// A step towards `target`, or `Arrived` once within `range`. Where the
+/// straight way is blocked, units go round along a path, and give up only
+/// if there's no way there at all.
+fn step_towards(world: &World, unit: &Unit, target: Vec2, range: Real) -> Plan {
+    let offset = target - unit.position;
+    let distance = offset.magnitude();
+    if distance <= range {
+        return Plan::Arrived;
+    }
+    let speed = Real::from_f32(world.stats(unit.kind).speed);
+    if speed <= Real::ZERO {
+        return Plan::Wait;
+    }
+
+    let (from, to) = (unit.position.to_f32(), target.to_f32());
+    let mut path = Vec::new();
+    if !world.map.is_clear(from, to) {
+        // Keep to the path found before while it still leads to the target's
+        // cell and its next waypoint is in sight
+        let map = &world.map;
+        let is_current = |path: &[Vec2]| match (path.first(), path.last()) {
+            (Some(goal), Some(next)) => {
+                map.cell_coords(goal.to_f32()) == map.cell_coords(to) && path.len() > 1 && map.is_clear(from, next.to_f32())
+            }
+            _ => false,
+        };
+        path = if is_current(&unit.path) {
+            unit.path.clone()
+        } else {
+            match pathfinding::find_path(map, from, to, range.to_f32()) {
+                Some(found) => found.into_iter().map(Vec2::from_f32).collect(),
+                None => return Plan::Reorder(Order::Idle),
+            }
+        };
+        // Head straight for the one after next whenever it can be seen. The
+        // first waypoint is only where the target was, so it's never headed for.
+        while path.len() > 2 && map.is_clear(from, path[path.len() - 2].to_f32()) {
+            path.pop();
+        }
+    }
+
+    let (offset, step) = match path.last() {
+        None => (offset, if speed < distance - range { speed } else { distance - range }),
+        Some(&waypoint) => {
+            let offset = waypoint - unit.position;
+            let distance = offset.magnitude();
+            (offset, if speed < distance { speed } else { distance })
+        }
+    };
+    let distance = offset.magnitude();
+    if distance <= Real::ZERO {
+        return Plan::Wait;
+    }
+    let position = unit.position + offset / distance * step;
+    if !world.map.is_passable(position.to_f32()) {
+        return Plan::Reorder(Order::Idle);
+    }
+    let facing = offset.to_f32();
+    Plan::Step { position, rotation: (-facing.y).atan2(facing.x).to_degrees(), path }
+}
+
+/// Do what a unit went where it is for. Anything used up by units earlier
+/// in the tick is checked again here.
+fn arrive(world: &mut World, id: UnitId) {
+    let order = world.units[id].order.clone();
+    match order {
+        Order::Move { .. } => set_order(world, id, Order::Idle),
+        Order::Gather { node, returning: true } => {
+            let unit = &mut world.units[id];
+            let player = &mut world.players[unit.owner as usize];
+            player.resources += unit.cargo;
+            player.stats.resources_gathered += unit.cargo;
+            unit.cargo = 0;
+            unit.order = Order::Gather { node, returning: false };
+        }
+        Order::Gather { node, returning: false } => {
+            let resource = &mut world.map.resources[node];
+            if resource.amount == 0 || !world.tick.is_multiple_of(GATHER_TIME) {
+                return;
+            }
+            let unit = &mut world.units[id];
+            resource.amount -= 1;
+            unit.cargo += 1;
+            if unit.cargo >= CARRY_CAPACITY {
+                unit.order = Order::Gather { node, returning: true };
+            }
+        }
+        Order::Build { kind, position } => {
+            let owner = world.units[id].owner;
+            let cost = world.stats(kind).cost;
+            let player = &mut world.players[owner as usize];
+            if player.resources >= cost && world.map.is_passable(position.to_f32()) {
+                player.resources -= cost;
+                player.stats.resources_spent += cost;
+                player.stats.produced[kind as usize] += 1;
+                world.spawn(kind, owner, position, 0.);
+            }
+            set_order(world, id, Order::Idle);
+        }
+        Order::Idle | Order::Attack { .. } => {}
+    }
+}
+
+fn set_order(world: &mut World, id: UnitId, order: Order) {
+    world.units[id].order = order;
+}
+
+/// Nudge moving units apart where they crowd together
+fn avoidance(world: &mut World) {
+    let spacing = Real::from_f32(UNIT_SPACING);
+    let pushes: Vec<(UnitId, Vec2)> = {
+        let world = &*world;
+        let grid = Grid::new(world, GRID_CELL_SIZE);
+        let is_mobile = |unit: &Unit| unit.health > 0 && world.stats(unit.kind).speed > 0.;
+        world.units.par_iter()
+            .filter(|(_, unit)| is_mobile(unit))
+            .filter_map(|(id, unit)| {
+                let mut push = Vec2::new(Real::ZERO, Real::ZERO);
+                for other_id in grid.near(unit.position.to_f32(), UNIT_SPACING).filter(|&other| other != id) {
+                    let other = &world.units[other_id];
+                    if !is_mobile(other) {
+                        continue;
+                    }
+                    let offset = unit.position - other.position;
+                    let distance = offset.magnitude();
+                    if distance >= spacing {
+                        continue;
+                    }
+                    // Units on the very same spot split along x, lower id to the left
+                    let away = if distance > Real::ZERO {
+                        offset / distance
+                    } else if id < other_id {
+                        Vec2::new(Real::from_f32(-1.), Real::ZERO)
+                    } else {
+                        Vec2::new(Real::from_f32(1.), Real::ZERO)
+                    };
+                    push = push + away * (spacing - distance);
+                }
+                if push.x == Real::ZERO && push.y == Real::ZERO {
+                    None
+                } else {
+                    Some((id, push * Real::from_f32(AVOIDANCE_STRENGTH)))
+                }
+            })
+            .collect()
+    };
+    for (id, push) in pushes {
+        let position = world.units[id].position + push;
+        if world.map.is_passable(position.to_f32()) {
+            world.units[id].position = position;
+        }
+    }
+}
+
+/// Have idle armed units take on the closest enemy they can see. Workers
+/// are left alone, so a fight doesn't pull them off the economy.
+fn targeting(world: &mut World) {
+    let targets: Vec<(UnitId, UnitId)> = {
+        let world = &*world;
+        let grid = Grid::new(world, GRID_CELL_SIZE);
+        world.units.par_iter()
+            .filter(|(_, unit)| {
+                unit.health > 0
+                    && matches!(unit.order, Order::Idle)
+                    && unit.kind.builds().is_empty()
+                    && world.stats(unit.kind).weapon.is_some()
+            })
+            .filter_map(|(id, unit)| {
+                let sight2 = Real::from_f32(unit.sight_radius) * Real::from_f32(unit.sight_radius);
+                grid.near(unit.position.to_f32(), unit.sight_radius)
+                    .map(|other| (other, &world.units[other]))
+                    .filter(|(_, other)| other.owner != unit.owner)
+                    .map(|(other, enemy)| (other, (enemy.position - unit.position).magnitude2()))
+                    .filter(|&(_, distance2)| distance2 <= sight2)
+                    .fold(None, |closest: Option<(UnitId, Real)>, (other, distance2)| match closest {
+                        Some((_, best)) if best <= distance2 => closest,
+                        _ => Some((other, distance2)),
+                    })
+                    .map(|(target, _)| (id, target))
+            })
+            .collect()
+    };
+    for (id, target) in targets {
+        set_order(world, id, Order::Attack { target });
+    }
+}
+
+/// Fire at attack targets in range once weapons are ready
+fn combat(world: &mut World) {
+    let ids: Vec<UnitId> = world.units.entities().to_vec();
+    for id in ids {
+        let unit = &mut world.units[id];
+        if unit.health == 0 {
+            continue;
+        }
+        unit.cooldown = unit.cooldown.saturating_sub(1);
+        let target = match unit.order {
+            Order::Attack { target } if unit.cooldown == 0 => target,
+            _ => continue,
+        };
+        let (owner, kind, position) = (unit.owner, unit.kind, unit.position);
+        let weapon = match world.stats(kind).weapon {
+            Some(weapon) => weapon,
+            None => continue,
+        };
+        let in_range = match world.units.get(target) {
+            Some(target) => target.health > 0 && (target.position - position).magnitude() <= Real::from_f32(weapon.range),
+            None => false,
+        };
+        if !in_range {
+            continue;
+        }
+
+        world.units[id].cooldown = weapon.cooldown;
+        let mut damage = weapon.damage;
+        if world.players[owner as usize].has(Tech::Weapons) {
+            damage += 2;
+        }
+        if weapon.range <= MELEE_RANGE {
+            hit(world, owner, target, damage);
+        } else {
+            let projectile = world.entities.create();
+            world.projectiles.insert(projectile, Projectile { owner, target, position, damage });
+        }
+    }
+}
+
+/// Fly projectiles after their targets, and hit on arrival. Ones whose target
+/// died on the way are dropped.
+fn projectiles(world: &mut World) {
+    let speed = Real::from_f32(PROJECTILE_SPEED);
+    let units = &world.units;
+    let mut hits = Vec::new();
+    world.projectiles.retain(|_, projectile| {
+        let target = match units.get(projectile.target) {
+            Some(target) if target.health > 0 => target.position,
+            _ => return false,
+        };
+        let offset = target - projectile.position;
+        let distance = offset.magnitude();
+        if distance <= speed {
+            hits.push((projectile.owner, projectile.target, projectile.damage));
+            return false;
+        }
+        projectile.position = projectile.position + offset / distance * speed;
+        true
+    });
+    for (owner, target, damage) in hits {
+        hit(world, owner, target, damage);
+    }
+}
+
+fn hit(world: &mut World, attacker: PlayerId, target: UnitId, mut damage: u32) {
+    let target = match world.units.get_mut(target) {
+        Some(target) if target.health > 0 => target,
+        _ => return,
+    };
+    if world.players[target.owner as usize].has(Tech::Armor) {
+        damage = damage.saturating_sub(1).max(1);
+    }
+    target.health = target.health.saturating_sub(damage);
+    if target.health == 0 {
+        world.players[attacker as usize].stats.kills += 1;
+    }
+}
+
+fn production(world: &mut World) {
+    let ids: Vec<UnitId> = world.units.entities().to_vec();
+    for id in ids {
+        let unit = &mut world.units[id];
+        let kind = match unit.queue.first() {
+            Some(&kind) => kind,
+            None => continue,
+        };
+        unit.progress += 1;
+        if unit.progress < world.rules.units.get(kind).build_time {
+            continue;
+        }
+        unit.queue.remove(0);
+        unit.progress = 0;
+        let (owner, origin) = (unit.owner, unit.position);
+        let position = world.spawn_point(origin);
+        world.spawn(kind, owner, position, 0.);
+        world.players[owner as usize].stats.produced[kind as usize] += 1;
+    }
+}
+
+fn research(world: &mut World) {
+    for player in &mut world.players {
+        let research = &mut player.research;
+        if let Some(tech) = research.current {
+            research.progress += 1;
+            if research.progress >= tech.research_time() {
+                research.completed.push(tech);
+                research.current = None;
+                research.progress = 0;
+            }
+        }
+    }
+}
+
+/// Remove the dead
+fn cleanup(world: &mut World) {
+    let players = &mut world.players;
+    world.units.retain(|_, unit| {
+        if unit.health == 0 {
+            players[unit.owner as usize].stats.units_lost += 1;
+        }
+        unit.health > 0
+    });
+}
+
+/// Rebuild the fog from where units stand now
+fn vision(world: &mut World) {
+    let sights: Vec<_> = world.units.values().iter()
+        .map(|unit| (unit.owner, unit.position.to_f32(), unit.sight_radius))
+        .collect();
+    world.fog.update(&sights);
+}
@@ -0,0 +1,201 @@
+use cgmath::{InnerSpace, Vector2, Vector3};
+use serde::{Deserialize, Serialize};
+use wgpu::vertex_attr_array;
+
+use super::model::VertexDesc;
+
+/// Grid of heights sampled at the corners of square cells. The map spans
+/// `(width - 1) * cell_size` by `(height - 1) * cell_size` from the origin.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Heightmap {
+    width: u32,
+    height: u32,
+    cell_size: f32,
+    heights: Vec<f32>,
+}
+
+impl Heightmap {
+    pub fn new(width: u32, height: u32, cell_size: f32, heights: Vec<f32>) -> Self {
+        assert!(width >= 2 && height >= 2, "A heightmap needs at least one cell");
+        assert_eq!(heights.len(), (width * height) as usize);
+        Self { width, height, cell_size, heights }
+    }
+
+    pub fn flat(width: u32, height: u32, cell_size: f32) -> Self {
+        Self::new(width, height, cell_size, vec![0.; (width * height) as usize])
+    }
+
+    /// Load a grayscale image, mapping black to 0 and white to `max_height`
+    pub fn from_image(path: impl AsRef<std::path::Path>, cell_size: f32, max_height: f32) -> image::ImageResult<Self> {
+        let image = image::open(path)?.into_luma16();
+        let (width, height) = image.dimensions();
+        let heights = image.pixels()
+            .map(|pixel| pixel.0[0] as f32 / u16::MAX as f32 * max_height)
+            .collect();
+        Ok(Self::new(width, height, cell_size, heights))
+    }
+
+    /// Rolling hills from a few octaves of value noise
+    pub fn generate(width: u32, height: u32, cell_size: f32, max_height: f32, seed: u32) -> Self {
+        let mut heights = Vec::with_capacity((width * height) as usize);
+        for y in 0..height {
+            for x in 0..width {
+                let mut value = 0.;
+                let mut amplitude = 0.5;
+                let mut frequency = 1. / 16.;
+                for octave in 0..4 {
+                    value += amplitude * value_noise(x as f32 * frequency, y as f32 * frequency, seed.wrapping_add(octave));
+                    amplitude *= 0.5;
+                    frequency *= 2.;
+                }
+                heights.push(value / 0.9375 * max_height);
+            }
+        }
+        Self::new(width, height, cell_size, heights)
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn cell_size(&self) -> f32 {
+        self.cell_size
+    }
+
+    pub fn heights(&self) -> &[f32] {
+        &self.heights
+    }
+
+    pub fn heights_mut(&mut self) -> &mut [f32] {
+        &mut self.heights
+    }
+
+    /// Size of the map in world units
+    pub fn extent(&self) -> Vector2<f32> {
+        Vector2::new((self.width - 1) as f32, (self.height - 1) as f32) * self.cell_size
+    }
+
+    /// Height of the grid point at column `x`, row `y`, clamped to the edge of the map
+    pub fn sample(&self, x: i32, y: i32) -> f32 {
+        let x = x.max(0).min(self.width as i32 - 1) as u32;
+        let y = y.max(0).min(self.height as i32 - 1) as u32;
+        self.heights[(y * self.width + x) as usize]
+    }
+
+    /// Bilinearly interpolated ground height under a point on the map
+    pub fn height_at(&self, position: Vector2<f32>) -> f32 {
+        let x = position.x / self.cell_size;
+        let y = position.y / self.cell_size;
+        let (x0, y0) = (x.floor(), y.floor());
+        let (tx, ty) = (x - x0, y - y0);
+        let (x0, y0) = (x0 as i32, y0 as i32);
+
+        let top = lerp(self.sample(x0, y0), self.sample(x0 + 1, y0), tx);
+        let bottom = lerp(self.sample(x0, y0 + 1), self.sample(x0 + 1, y0 + 1), tx);
+        lerp(top, bottom, ty)
+    }
+
+    /// Surface normal at a grid point, from central differences
+    pub fn normal(&self, x: i32, y: i32) -> Vector3<f32> {
+        let dx = self.sample(x + 1, y) - self.sample(x - 1, y);
+        let dy = self.sample(x, y + 1) - self.sample(x, y - 1);
+        Vector3::new(-dx, 2. * self.cell_size, -dy).normalize()
+    }
+
+    /// Split the heightmap into meshes of at most `chunk_cells` by `chunk_cells` cells
+    pub fn build_chunks(&self, chunk_cells: u32) -> Vec<TerrainChunk> {
+        let cells_x = self.width - 1;
+        let cells_y = self.height - 1;
+
+        let mut chunks = Vec::new();
+        for chunk_y in (0..cells_y).step_by(chunk_cells as usize) {
+            for chunk_x in (0..cells_x).step_by(chunk_cells as usize) {
+                let chunk_width = chunk_cells.min(cells_x - chunk_x);
+                let chunk_height = chunk_cells.min(cells_y - chunk_y);
+                chunks.push(self.build_chunk(chunk_x, chunk_y, chunk_width, chunk_height));
+            }
+        }
+        chunks
+    }
+
+    fn build_chunk(&self, origin_x: u32, origin_y: u32, cells_x: u32, cells_y: u32) -> TerrainChunk {
+        let mut vertices = Vec::with_capacity(((cells_x + 1) * (cells_y + 1)) as usize);
+        for y in origin_y..=origin_y + cells_y {
+            for x in origin_x..=origin_x + cells_x {
+                let (x, y) = (x as i32, y as i32);
+                vertices.push(TerrainVertex {
+                    position: [x as f32 * self.cell_size, self.sample(x, y), y as f32 * self.cell_size],
+                    normal: self.normal(x, y).into(),
+                });
+            }
+        }
+
+        let row = cells_x + 1;
+        let mut indices = Vec::with_capacity((cells_x * cells_y * 6) as usize);
+        for y in 0..cells_y {
+            for x in 0..cells_x {
+                let i = y * row + x;
+                // Counter-clockwise when viewed from above
+                indices.extend_from_slice(&[i, i + row, i + 1, i + 1, i + row, i + row + 1]);
+            }
+        }
+
+        TerrainChunk { vertices, indices }
+    }
+}
+
+pub struct TerrainChunk {
+    pub vertices: Vec<TerrainVertex>,
+    pub indices: Vec<u32>,
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct TerrainVertex {
+    position: [f32; 3],
+    normal: [f32; 3],
+}
+
+const TERRAIN_VERTEX_ATTRS: [wgpu::VertexAttribute; 2] = vertex_attr_array![
+    0 => Float32x3,
+    1 => Float32x3,
+];
+
+impl VertexDesc for TerrainVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        use std::mem;
+        wgpu::VertexBufferLayout {
+            array_stride: mem::size_of::<TerrainVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &TERRAIN_VERTEX_ATTRS,
+        }
+    }
+}
+
+fn lerp(a: f32, b: f32, t: f32) -> f32 {
+    a + (b - a) * t
+}
+
+/// Smoothly interpolated lattice noise in [0, 1]
+fn value_noise(x: f32, y: f32, seed: u32) -> f32 {
+    let (x0, y0) = (x.floor(), y.floor());
+    let smooth = |t: f32| t * t * (3. - 2. * t);
+    let (tx, ty) = (smooth(x - x0), smooth(y - y0));
+    let (x0, y0) = (x0 as i32, y0 as i32);
+
+    let top = lerp(lattice(x0, y0, seed), lattice(x0 + 1, y0, seed), tx);
+    let bottom = lerp(lattice(x0, y0 + 1, seed), lattice(x0 + 1, y0 + 1, seed), tx);
+    lerp(top, bottom, ty)
+}
+
+fn lattice(x: i32, y: i32, seed: u32) -> f32 {
+    let mut h = seed ^ (x as u32).wrapping_mul(0x27d4_eb2d) ^ (y as u32).wrapping_mul(0x1656_67b1);
+    h = (h ^ (h >> 15)).wrapping_mul(0x85eb_ca6b);
+    h = (h ^ (h >> 13)).wrapping_mul(0xc2b2_ae35);
+    h ^= h >> 16;
+    h as f32 / u32::MAX as f32
+}
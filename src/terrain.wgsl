@@ -0,0 +1,56 @@
+// Vertex shader
+
+[[block]]
+struct Uniforms {
+    view_proj: mat4x4<f32>;
+    // (scale x, scale z, offset x, offset z) from world to fog texture coordinates
+    fog_transform: vec4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+struct VertexInput {
+    [[location(0)]] position: vec3<f32>;
+    [[location(1)]] normal: vec3<f32>;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] color: vec3<f32>;
+    [[location(1)]] fog_coords: vec2<f32>;
+};
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+) -> VertexOutput {
+    var out: VertexOutput;
+    out.clip_position = uniforms.view_proj * vec4<f32>(model.position, 1.0);
+    out.fog_coords = model.position.xz * uniforms.fog_transform.xy + uniforms.fog_transform.zw;
+
+    // Grass on the flats, rock on the slopes
+    let grass = vec3<f32>(0.25, 0.4, 0.15);
+    let rock = vec3<f32>(0.4, 0.36, 0.3);
+    let steepness = 1. - model.normal.y;
+    let albedo = mix(grass, rock, clamp(steepness * 4., 0., 1.));
+
+    let light_dir = normalize(vec3<f32>(1., 3., 0.5));
+    let ambient = 0.1;
+    let diffuse = max(dot(model.normal, light_dir), 0.);
+    out.color = (ambient + diffuse) * albedo;
+
+    return out;
+}
+
+// Fragment shader
+
+[[group(1), binding(0)]]
+var t_fog: texture_2d<f32>;
+[[group(1), binding(1)]]
+var s_fog: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let fog = textureSample(t_fog, s_fog, in.fog_coords).r;
+    return vec4<f32>(in.color * fog, 1.0);
+}
@@ -1,3 +1,11 @@
+use std::path::Path;
+
+use image::imageops::{self, FilterType};
+use image::RgbaImage;
+use wgpu::util::DeviceExt;
+
+use super::vat::VertexAnimation;
+
 pub struct Texture {
     pub texture: wgpu::Texture,
     pub view: wgpu::TextureView,
@@ -7,7 +15,8 @@ pub struct Texture {
 impl Texture {
     pub const DEPTH_FORMAT: wgpu::TextureFormat = wgpu::TextureFormat::Depth32Float; // 1.
     
-    pub fn create_depth_texture(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor, label: &str) -> Self {
+    /// A depth texture to go with a color target of `sample_count` samples per pixel
+    pub fn create_depth_texture(device: &wgpu::Device, sc_desc: &wgpu::SwapChainDescriptor, sample_count: u32, label: &str) -> Self {
         let size = wgpu::Extent3d {
             width: sc_desc.width,
             height: sc_desc.height,
@@ -17,7 +26,7 @@ impl Texture {
             label: Some(label),
             size,
             mip_level_count: 1,
-            sample_count: 1,
+            sample_count,
             dimension: wgpu::TextureDimension::D2,
             format: Self::DEPTH_FORMAT,
             usage: wgpu::TextureUsage::RENDER_ATTACHMENT // 3.
@@ -43,4 +52,336 @@ impl Texture {
 
         Self { texture, view, sampler }
     }
+
+    /// Single channel texture holding fog of war brightness, one texel per fog cell
+    pub fn create_fog_texture(device: &wgpu::Device, width: u32, height: u32, label: &str) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::R8Unorm,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::ClampToEdge,
+            address_mode_v: wgpu::AddressMode::ClampToEdge,
+            address_mode_w: wgpu::AddressMode::ClampToEdge,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self { texture, view, sampler }
+    }
+
+    /// Overwrite the whole of a single channel texture
+    pub fn write_r8(&self, queue: &wgpu::Queue, width: u32, height: u32, data: &[u8]) {
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &self.texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            data,
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(width),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+            wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: 1,
+            },
+        );
+    }
+
+    /// A texture of four floats per texel, read exactly with `textureLoad`
+    /// rather than sampled. `data` is row-major.
+    pub fn from_rgba32f(device: &wgpu::Device, queue: &wgpu::Queue, width: u32, height: u32, data: &[[f32; 4]], label: &str) -> Self {
+        let size = wgpu::Extent3d {
+            width,
+            height,
+            depth_or_array_layers: 1,
+        };
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size,
+            mip_level_count: 1,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba32Float,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+        queue.write_texture(
+            wgpu::ImageCopyTexture {
+                texture: &texture,
+                mip_level: 0,
+                origin: wgpu::Origin3d::ZERO,
+            },
+            bytemuck::cast_slice(data),
+            wgpu::ImageDataLayout {
+                offset: 0,
+                bytes_per_row: std::num::NonZeroU32::new(16 * width),
+                rows_per_image: std::num::NonZeroU32::new(height),
+            },
+            size,
+        );
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor::default());
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            mag_filter: wgpu::FilterMode::Nearest,
+            min_filter: wgpu::FilterMode::Nearest,
+            mipmap_filter: wgpu::FilterMode::Nearest,
+            ..Default::default()
+        });
+
+        Self { texture, view, sampler }
+    }
+
+    /// An sRGB array texture with one layer per image, each with a full mip
+    /// chain, each level a filtered half of the one before. The images all
+    /// have to be the same size.
+    pub fn from_rgba_array(device: &wgpu::Device, queue: &wgpu::Queue, layers: &[RgbaImage], label: &str) -> Self {
+        let (width, height) = layers[0].dimensions();
+        let mip_level_count = 32 - width.max(height).max(1).leading_zeros();
+        let texture = device.create_texture(&wgpu::TextureDescriptor {
+            label: Some(label),
+            size: wgpu::Extent3d {
+                width,
+                height,
+                depth_or_array_layers: layers.len() as u32,
+            },
+            mip_level_count,
+            sample_count: 1,
+            dimension: wgpu::TextureDimension::D2,
+            format: wgpu::TextureFormat::Rgba8UnormSrgb,
+            usage: wgpu::TextureUsage::SAMPLED | wgpu::TextureUsage::COPY_DST,
+        });
+
+        for (layer, image) in layers.iter().enumerate() {
+            assert_eq!(image.dimensions(), (width, height), "Texture layers differ in size");
+            let mut level = image.clone();
+            for mip_level in 0..mip_level_count {
+                if mip_level > 0 {
+                    let (width, height) = level.dimensions();
+                    level = imageops::resize(&level, (width / 2).max(1), (height / 2).max(1), FilterType::Triangle);
+                }
+                let (width, height) = level.dimensions();
+                queue.write_texture(
+                    wgpu::ImageCopyTexture {
+                        texture: &texture,
+                        mip_level,
+                        origin: wgpu::Origin3d { x: 0, y: 0, z: layer as u32 },
+                    },
+                    &level,
+                    wgpu::ImageDataLayout {
+                        offset: 0,
+                        bytes_per_row: std::num::NonZeroU32::new(4 * width),
+                        rows_per_image: std::num::NonZeroU32::new(height),
+                    },
+                    wgpu::Extent3d {
+                        width,
+                        height,
+                        depth_or_array_layers: 1,
+                    },
+                );
+            }
+        }
+
+        let view = texture.create_view(&wgpu::TextureViewDescriptor {
+            dimension: Some(wgpu::TextureViewDimension::D2Array),
+            ..Default::default()
+        });
+        let sampler = device.create_sampler(&wgpu::SamplerDescriptor {
+            address_mode_u: wgpu::AddressMode::Repeat,
+            address_mode_v: wgpu::AddressMode::Repeat,
+            address_mode_w: wgpu::AddressMode::Repeat,
+            mag_filter: wgpu::FilterMode::Linear,
+            min_filter: wgpu::FilterMode::Linear,
+            mipmap_filter: wgpu::FilterMode::Linear,
+            ..Default::default()
+        });
+
+        Self { texture, view, sampler }
+    }
+}
+
+/// Every unit skin as a layer of one array texture, bound as group 2 of the
+/// model shader, so all models share a single bind group. Each instance
+/// picks its layer. A skin's alpha channel is its team color mask: where it
+/// is opaque the texel is tinted with the instance color, and where it is
+/// clear the texel is drawn as it is.
+pub struct Skins {
+    pub bind_group: wgpu::BindGroup,
+}
+
+impl Skins {
+    /// Width and height every skin is scaled to
+    pub const SIZE: u32 = 256;
+
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                wgpu::BindGroupLayoutEntry {
+                    binding: 0,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Texture {
+                        multisampled: false,
+                        view_dimension: wgpu::TextureViewDimension::D2Array,
+                        sample_type: wgpu::TextureSampleType::Float { filterable: true },
+                    },
+                    count: None,
+                },
+                wgpu::BindGroupLayoutEntry {
+                    binding: 1,
+                    visibility: wgpu::ShaderStage::FRAGMENT,
+                    ty: wgpu::BindingType::Sampler {
+                        comparison: false,
+                        filtering: true,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("skins_bind_group_layout"),
+        })
+    }
+
+    /// Layer 0 is plain white and fully masked, so an instance on it is
+    /// simply its instance color. The PNG or JPEG images at `paths` follow
+    /// from layer 1, scaled to `SIZE`.
+    pub fn load(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, paths: &[impl AsRef<Path>]) -> image::ImageResult<Self> {
+        let mut layers = vec![RgbaImage::from_pixel(Self::SIZE, Self::SIZE, image::Rgba([0xff; 4]))];
+        for path in paths {
+            let image = image::open(path)?.into_rgba8();
+            layers.push(if image.dimensions() == (Self::SIZE, Self::SIZE) {
+                image
+            } else {
+                imageops::resize(&image, Self::SIZE, Self::SIZE, FilterType::Triangle)
+            });
+        }
+
+        let texture = Texture::from_rgba_array(device, queue, &layers, "skins");
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&texture.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::Sampler(&texture.sampler),
+                },
+            ],
+            label: Some("skins_bind_group"),
+        });
+        Ok(Self { bind_group })
+    }
+}
+
+/// A baked model's vertex animation textures with its clip table, bound as
+/// group 3 of the crowd shader. The clip table starts with the shared clock,
+/// which is rewritten every frame.
+pub struct AnimationTextures {
+    pub clips: wgpu::Buffer,
+    pub bind_group: wgpu::BindGroup,
+}
+
+/// A `BakedClip` as the shader reads it
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+struct ClipRaw {
+    first_frame: u32,
+    frames: u32,
+    frame_rate: f32,
+    loops: u32,
+}
+
+impl AnimationTextures {
+    pub fn bind_group_layout(device: &wgpu::Device) -> wgpu::BindGroupLayout {
+        let texture_entry = |binding| wgpu::BindGroupLayoutEntry {
+            binding,
+            visibility: wgpu::ShaderStage::VERTEX,
+            ty: wgpu::BindingType::Texture {
+                multisampled: false,
+                view_dimension: wgpu::TextureViewDimension::D2,
+                sample_type: wgpu::TextureSampleType::Float { filterable: false },
+            },
+            count: None,
+        };
+        device.create_bind_group_layout(&wgpu::BindGroupLayoutDescriptor {
+            entries: &[
+                texture_entry(0),
+                texture_entry(1),
+                wgpu::BindGroupLayoutEntry {
+                    binding: 2,
+                    visibility: wgpu::ShaderStage::VERTEX,
+                    ty: wgpu::BindingType::Buffer {
+                        ty: wgpu::BufferBindingType::Storage { read_only: true },
+                        has_dynamic_offset: false,
+                        min_binding_size: None,
+                    },
+                    count: None,
+                },
+            ],
+            label: Some("animation_textures_bind_group_layout"),
+        })
+    }
+
+    pub fn new(device: &wgpu::Device, queue: &wgpu::Queue, layout: &wgpu::BindGroupLayout, animation: &VertexAnimation) -> Self {
+        let (width, height) = (animation.vertex_count, animation.frame_count());
+        let positions = Texture::from_rgba32f(device, queue, width, height, &animation.positions, "vat_positions");
+        let normals = Texture::from_rgba32f(device, queue, width, height, &animation.normals, "vat_normals");
+
+        let mut contents = bytemuck::cast_slice(&[0f32; 4]).to_vec();
+        for clip in &animation.clips {
+            contents.extend_from_slice(bytemuck::bytes_of(&ClipRaw {
+                first_frame: clip.first_frame,
+                frames: clip.frames,
+                frame_rate: clip.frame_rate,
+                loops: clip.loops as u32,
+            }));
+        }
+        let clips = device.create_buffer_init(&wgpu::util::BufferInitDescriptor {
+            label: Some("vat_clips"),
+            contents: &contents,
+            usage: wgpu::BufferUsage::STORAGE | wgpu::BufferUsage::COPY_DST,
+        });
+
+        let bind_group = device.create_bind_group(&wgpu::BindGroupDescriptor {
+            layout,
+            entries: &[
+                wgpu::BindGroupEntry {
+                    binding: 0,
+                    resource: wgpu::BindingResource::TextureView(&positions.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 1,
+                    resource: wgpu::BindingResource::TextureView(&normals.view),
+                },
+                wgpu::BindGroupEntry {
+                    binding: 2,
+                    resource: clips.as_entire_binding(),
+                },
+            ],
+            label: Some("animation_textures_bind_group"),
+        });
+        Self { clips, bind_group }
+    }
+
+    /// Set the clock every instance's time offset is added to
+    pub fn set_time(&self, queue: &wgpu::Queue, time: f32) {
+        queue.write_buffer(&self.clips, 0, bytemuck::bytes_of(&time));
+    }
 }
@@ -0,0 +1,148 @@
+//! Unreliable datagram transports for lockstep networking
+//!
+//! Peers are addressed by player id. Delivery may drop, duplicate or reorder
+//! packets; `Lockstep` copes with all of that on top.
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::io;
+use std::net::{SocketAddr, UdpSocket};
+use std::rc::Rc;
+use std::time::Duration;
+
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+
+use super::sim::PlayerId;
+
+/// Largest payload a UDP datagram can carry
+pub const MAX_DATAGRAM: usize = 65507;
+
+pub trait Transport {
+    fn send(&mut self, to: PlayerId, bytes: &[u8]);
+    /// The next packet that has arrived, if any. Never blocks.
+    fn recv(&mut self) -> Option<(PlayerId, Vec<u8>)>;
+}
+
+/// Real network transport. Every player's address is known up front.
+pub struct UdpTransport {
+    socket: UdpSocket,
+    peers: Vec<SocketAddr>,
+}
+
+impl UdpTransport {
+    /// Bind to `peers[local]` and talk to all the others
+    pub fn bind(local: PlayerId, peers: Vec<SocketAddr>) -> io::Result<Self> {
+        let socket = UdpSocket::bind(peers[local as usize])?;
+        socket.set_nonblocking(true)?;
+        Ok(Self { socket, peers })
+    }
+}
+
+impl Transport for UdpTransport {
+    fn send(&mut self, to: PlayerId, bytes: &[u8]) {
+        // Losing a packet is expected, so failures are treated the same way
+        let _ = self.socket.send_to(bytes, self.peers[to as usize]);
+    }
+
+    fn recv(&mut self) -> Option<(PlayerId, Vec<u8>)> {
+        let mut buffer = vec![0u8; MAX_DATAGRAM];
+        loop {
+            match self.socket.recv_from(&mut buffer) {
+                Ok((len, from)) => {
+                    // Ignore anything not from a known peer
+                    if let Some(peer) = self.peers.iter().position(|&addr| addr == from) {
+                        return Some((peer as PlayerId, buffer[..len].to_vec()));
+                    }
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return None,
+                // ICMP unreachable and friends show up as errors on some platforms
+                Err(_) => continue,
+            }
+        }
+    }
+}
+
+struct InFlight {
+    deliver_at: Duration,
+    from: PlayerId,
+    to: PlayerId,
+    bytes: Vec<u8>,
+}
+
+struct NetworkState {
+    now: Duration,
+    rng: ChaCha8Rng,
+    loss: f32,
+    latency: (Duration, Duration),
+    in_flight: Vec<InFlight>,
+    inboxes: Vec<VecDeque<(PlayerId, Vec<u8>)>>,
+}
+
+/// In-process network with configurable loss and latency, for exercising
+/// lockstep without sockets. Reproducible for a given seed.
+#[derive(Clone)]
+pub struct SimulatedNetwork {
+    state: Rc<RefCell<NetworkState>>,
+}
+
+impl SimulatedNetwork {
+    /// `loss` is the chance each packet is dropped; latency is uniform in `min_latency..=max_latency`
+    pub fn new(players: usize, loss: f32, min_latency: Duration, max_latency: Duration, seed: u64) -> Self {
+        Self {
+            state: Rc::new(RefCell::new(NetworkState {
+                now: Duration::from_millis(0),
+                rng: ChaCha8Rng::seed_from_u64(seed),
+                loss,
+                latency: (min_latency, max_latency),
+                in_flight: Vec::new(),
+                inboxes: vec![VecDeque::new(); players],
+            })),
+        }
+    }
+
+    pub fn endpoint(&self, player: PlayerId) -> SimulatedTransport {
+        SimulatedTransport {
+            network: self.clone(),
+            player,
+        }
+    }
+
+    /// Move the network clock to `now`, delivering every packet due by then
+    pub fn advance(&self, now: Duration) {
+        let mut state = self.state.borrow_mut();
+        state.now = now;
+        let (due, waiting): (Vec<_>, Vec<_>) = state.in_flight.drain(..).partition(|packet| packet.deliver_at <= now);
+        state.in_flight = waiting;
+        for packet in due {
+            state.inboxes[packet.to as usize].push_back((packet.from, packet.bytes));
+        }
+    }
+}
+
+pub struct SimulatedTransport {
+    network: SimulatedNetwork,
+    player: PlayerId,
+}
+
+impl Transport for SimulatedTransport {
+    fn send(&mut self, to: PlayerId, bytes: &[u8]) {
+        let mut state = self.network.state.borrow_mut();
+        if state.rng.gen::<f32>() < state.loss {
+            return;
+        }
+        let (min, max) = state.latency;
+        let latency = min + (max - min).mul_f32(state.rng.gen());
+        let deliver_at = state.now + latency;
+        state.in_flight.push(InFlight {
+            deliver_at,
+            from: self.player,
+            to,
+            bytes: bytes.to_vec(),
+        });
+    }
+
+    fn recv(&mut self) -> Option<(PlayerId, Vec<u8>)> {
+        self.network.state.borrow_mut().inboxes[self.player as usize].pop_front()
+    }
+}
@@ -0,0 +1,258 @@
+//! Vertex animation textures: skeletal animation baked offline into where
+//! every vertex is on every frame
+//!
+//! Skinning each instance on the CPU costs too much for big crowds. A baked
+//! animation instead keeps one row per frame and one column per vertex, of
+//! positions and of normals, so the vertex shader only has to look up its
+//! own column at the instance's frame. Every instance of a model then draws
+//! in a single instanced call, picking its clip and how far in it is.
+//!
+//! Baked files are stored in a `container` with magic `RTSV`.
+
+use std::fs::File;
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::Path;
+
+use cgmath::{InnerSpace, Matrix4, Vector3, Vector4, Zero};
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+use wgpu::vertex_attr_array;
+
+use super::animation::{AnimationKind, SkinnedMesh, SkinnedVertex};
+use super::container::{self, ContainerError};
+use super::model::{ModelInstance, VertexDesc};
+
+const MAGIC: [u8; 4] = *b"RTSV";
+pub const VERSION: u32 = 1;
+
+/// Frames baked per second of animation unless told otherwise
+pub const DEFAULT_FRAME_RATE: f32 = 30.;
+/// Most vertices or frames a texture can have along one side
+const MAX_TEXTURE_SIZE: u32 = 8192;
+
+/// Where one clip's frames are in the textures
+#[derive(Copy, Clone, Debug, Serialize, Deserialize)]
+pub struct BakedClip {
+    /// Row of the first frame
+    pub first_frame: u32,
+    /// At least 1
+    pub frames: u32,
+    /// Frames per second. Looping clips are baked to a whole number of
+    /// frames, so this is close to but not always the rate asked for.
+    pub frame_rate: f32,
+    /// Whether the last frame runs on into the first, or holds
+    pub loops: bool,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct VertexAnimation {
+    pub vertex_count: u32,
+    pub tex_coords: Vec<[f32; 2]>,
+    pub indices: Vec<u32>,
+    /// By `AnimationKind`. A clip the model doesn't have is its rest pose.
+    pub clips: Vec<BakedClip>,
+    /// Row-major, a row per frame of every clip in turn. w is 1.
+    pub positions: Vec<[f32; 4]>,
+    /// As `positions`, with w 0
+    pub normals: Vec<[f32; 4]>,
+}
+
+impl VertexAnimation {
+    /// Sample every clip of `mesh` `frame_rate` times a second
+    pub fn bake(mesh: &SkinnedMesh, frame_rate: f32) -> Self {
+        let rig = &mesh.rig;
+        let mut clips = Vec::new();
+        // (kind, seconds in) for every row
+        let mut samples = Vec::new();
+        for &kind in AnimationKind::ALL.iter() {
+            let duration = rig.clip(kind).map_or(0., |clip| clip.duration);
+            let (frames, clip_rate) = if duration <= 0. {
+                (1, frame_rate)
+            } else if kind.loops() {
+                let frames = (duration * frame_rate).round().max(1.);
+                (frames as u32, frames / duration)
+            } else {
+                // Non-looping clips also need their very last pose
+                ((duration * frame_rate).ceil() as u32 + 1, frame_rate)
+            };
+            clips.push(BakedClip {
+                first_frame: samples.len() as u32,
+                frames,
+                frame_rate: clip_rate,
+                loops: kind.loops(),
+            });
+            samples.extend((0..frames).map(|frame| (kind, frame as f32 / clip_rate)));
+        }
+
+        let rows: Vec<Vec<([f32; 4], [f32; 4])>> = samples.par_iter()
+            .map(|&(kind, time)| {
+                let mut matrices = Vec::with_capacity(rig.skeleton.joints.len());
+                rig.skeleton.joint_matrices(&rig.sample(kind, time), &mut matrices);
+                mesh.vertices.iter().map(|vertex| skin(vertex, &matrices)).collect()
+            })
+            .collect();
+
+        Self {
+            vertex_count: mesh.vertices.len() as u32,
+            tex_coords: mesh.vertices.iter().map(|vertex| vertex.tex_coords).collect(),
+            indices: mesh.indices.clone(),
+            clips,
+            positions: rows.iter().flatten().map(|&(position, _)| position).collect(),
+            normals: rows.iter().flatten().map(|&(_, normal)| normal).collect(),
+        }
+    }
+
+    /// Rows in each texture
+    pub fn frame_count(&self) -> u32 {
+        self.clips.iter().map(|clip| clip.frames).sum()
+    }
+
+    pub fn clip(&self, kind: AnimationKind) -> &BakedClip {
+        &self.clips[kind as usize]
+    }
+
+    /// Vertices for the mesh the textures animate. Positions and normals
+    /// come from the textures, so only texture coordinates are kept.
+    pub fn vertices(&self) -> Vec<VatVertex> {
+        self.tex_coords.iter().map(|&tex_coords| VatVertex { tex_coords }).collect()
+    }
+}
+
+/// Where a vertex and its normal end up when its joints are at `matrices`
+fn skin(vertex: &SkinnedVertex, matrices: &[[[f32; 4]; 4]]) -> ([f32; 4], [f32; 4]) {
+    let mut skinning = Matrix4::zero();
+    for (&joint, &weight) in vertex.joints.iter().zip(&vertex.weights) {
+        if weight > 0. {
+            skinning += Matrix4::from(matrices[joint as usize]) * weight;
+        }
+    }
+    let [x, y, z] = vertex.position;
+    let position = skinning * Vector4::new(x, y, z, 1.);
+    let normal = (skinning * Vector3::from(vertex.normal).extend(0.)).truncate();
+    let normal = if normal.magnitude2() > 0. { normal.normalize() } else { normal };
+    ([position.x, position.y, position.z, 1.], [normal.x, normal.y, normal.z, 0.])
+}
+
+#[repr(C)]
+#[derive(Copy, Clone, Debug, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct VatVertex {
+    pub tex_coords: [f32; 2],
+}
+
+const VAT_VERTEX_ATTRS: [wgpu::VertexAttribute; 1] = vertex_attr_array![
+    2 => Float32x2,
+];
+
+impl VertexDesc for VatVertex {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<VatVertex>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Vertex,
+            attributes: &VAT_VERTEX_ATTRS,
+        }
+    }
+}
+
+/// An instance of a baked model, with what it's playing
+#[repr(C)]
+#[derive(Copy, Clone, bytemuck::Pod, bytemuck::Zeroable)]
+pub struct VatInstance {
+    pub instance: ModelInstance,
+    /// `AnimationKind` as a number
+    pub clip: u32,
+    /// Seconds added to the shared clock, so instances started at different
+    /// times, or spread out on purpose, aren't in step
+    pub time_offset: f32,
+}
+
+impl VatInstance {
+    /// An instance playing `kind` as if it started at `started`, by the same
+    /// clock later passed to the renderer
+    pub fn new(instance: ModelInstance, kind: AnimationKind, started: f32) -> Self {
+        Self {
+            instance,
+            clip: kind as u32,
+            time_offset: -started,
+        }
+    }
+}
+
+const VAT_INSTANCE_ATTRS: [wgpu::VertexAttribute; 11] = vertex_attr_array![
+    5 => Float32x4,
+    6 => Float32x4,
+    7 => Float32x4,
+    8 => Float32x4,
+    9 => Float32x3,
+    10 => Float32x3,
+    11 => Float32x3,
+    12 => Float32x3,
+    13 => Uint32,
+    14 => Uint32,
+    15 => Float32,
+];
+
+impl VertexDesc for VatInstance {
+    fn desc() -> wgpu::VertexBufferLayout<'static> {
+        wgpu::VertexBufferLayout {
+            array_stride: std::mem::size_of::<VatInstance>() as wgpu::BufferAddress,
+            step_mode: wgpu::InputStepMode::Instance,
+            attributes: &VAT_INSTANCE_ATTRS,
+        }
+    }
+}
+
+pub fn save(animation: &VertexAnimation, path: impl AsRef<Path>) -> Result<(), ContainerError> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write(animation, &mut writer)?;
+    writer.flush()?;
+    Ok(())
+}
+
+pub fn load(path: impl AsRef<Path>) -> Result<VertexAnimation, ContainerError> {
+    read(&mut BufReader::new(File::open(path)?))
+}
+
+pub fn write(animation: &VertexAnimation, writer: &mut impl Write) -> Result<(), ContainerError> {
+    let payload = bincode::serialize(animation).expect("Vertex animation serialization can't fail");
+    container::write(writer, MAGIC, VERSION, &payload)?;
+    Ok(())
+}
+
+pub fn read(reader: &mut impl Read) -> Result<VertexAnimation, ContainerError> {
+    let (_, payload) = container::read(reader, MAGIC, VERSION)?;
+    let animation: VertexAnimation = bincode::deserialize(&payload)?;
+    validate(&animation).map_err(ContainerError::Corrupt)?;
+    Ok(animation)
+}
+
+/// Check a baked animation fits in textures and its parts agree in size
+pub fn validate(animation: &VertexAnimation) -> Result<(), String> {
+    let frames = animation.frame_count();
+    if animation.vertex_count == 0 || animation.vertex_count > MAX_TEXTURE_SIZE {
+        return Err(format!("{} vertices, must be 1 to {}", animation.vertex_count, MAX_TEXTURE_SIZE));
+    }
+    if frames > MAX_TEXTURE_SIZE {
+        return Err(format!("{} frames, at most {} fit", frames, MAX_TEXTURE_SIZE));
+    }
+    if animation.clips.len() != AnimationKind::ALL.len() {
+        return Err(format!("{} clips, expected {}", animation.clips.len(), AnimationKind::ALL.len()));
+    }
+    let mut next_frame = 0;
+    for clip in &animation.clips {
+        if clip.frames == 0 || clip.first_frame != next_frame || clip.frame_rate.is_nan() || clip.frame_rate <= 0. {
+            return Err("clips don't cover the frames in order".to_string());
+        }
+        next_frame += clip.frames;
+    }
+    let texels = animation.vertex_count as usize * frames as usize;
+    if animation.positions.len() != texels || animation.normals.len() != texels {
+        return Err("texture size doesn't match vertex and frame count".to_string());
+    }
+    if animation.tex_coords.len() != animation.vertex_count as usize {
+        return Err("texture coordinates don't match vertex count".to_string());
+    }
+    if animation.indices.iter().any(|&index| index >= animation.vertex_count) {
+        return Err("index past the last vertex".to_string());
+    }
+    Ok(())
+}
@@ -0,0 +1,145 @@
+// Vertex shader for crowds. Same as shader.wgsl, except each vertex's
+// position and normal are read from the model's vertex animation textures at
+// the instance's clip and time.
+
+[[block]]
+struct Uniforms {
+    view_proj: mat4x4<f32>;
+    // (scale x, scale z, offset x, offset z) from world to fog texture coordinates
+    fog_transform: vec4<f32>;
+};
+[[group(0), binding(0)]]
+var<uniform> uniforms: Uniforms;
+
+// One column per vertex, one row per frame of every clip in turn
+[[group(3), binding(0)]]
+var t_positions: texture_2d<f32>;
+[[group(3), binding(1)]]
+var t_normals: texture_2d<f32>;
+
+struct Clip {
+    first_frame: u32;
+    frames: u32;
+    frame_rate: f32;
+    loops: u32;
+};
+
+[[block]]
+struct Clips {
+    // Seconds, shared by every instance
+    time: f32;
+    clips: array<Clip>;
+};
+[[group(3), binding(2)]]
+var<storage> clips: [[access(read)]] Clips;
+
+struct VertexInput {
+    [[location(2)]] tex_coords: vec2<f32>;
+};
+
+struct InstanceInput {
+    [[location(5)]] model_matrix_0: vec4<f32>;
+    [[location(6)]] model_matrix_1: vec4<f32>;
+    [[location(7)]] model_matrix_2: vec4<f32>;
+    [[location(8)]] model_matrix_3: vec4<f32>;
+    [[location(9)]] normal_matrix_0: vec3<f32>;
+    [[location(10)]] normal_matrix_1: vec3<f32>;
+    [[location(11)]] normal_matrix_2: vec3<f32>;
+    [[location(12)]] color: vec3<f32>;
+    [[location(13)]] layer: u32;
+    [[location(14)]] clip: u32;
+    [[location(15)]] time_offset: f32;
+};
+
+struct VertexOutput {
+    [[builtin(position)]] clip_position: vec4<f32>;
+    [[location(0)]] light: vec3<f32>;
+    [[location(1)]] fog_coords: vec2<f32>;
+    [[location(2)]] tex_coords: vec2<f32>;
+    [[location(3)]] team_color: vec3<f32>;
+    [[location(4), interpolate(flat)]] layer: u32;
+};
+
+[[stage(vertex)]]
+fn main(
+    model: VertexInput,
+    instance: InstanceInput,
+    [[builtin(vertex_index)]] vertex_index: u32,
+) -> VertexOutput {
+    let model_matrix = mat4x4<f32>(
+        instance.model_matrix_0,
+        instance.model_matrix_1,
+        instance.model_matrix_2,
+        instance.model_matrix_3,
+    );
+    let normal_matrix = mat3x3<f32>(
+        instance.normal_matrix_0,
+        instance.normal_matrix_1,
+        instance.normal_matrix_2,
+    );
+
+    // Blend the two frames either side of now. Looping clips wrap round to
+    // their first frame, the others hold their last.
+    let clip = clips.clips[instance.clip];
+    let frames = f32(clip.frames);
+    let time = max(clips.time + instance.time_offset, 0.0) * clip.frame_rate;
+    let looped = time - floor(time / frames) * frames;
+    let held = min(time, frames - 1.0);
+    let frame = select(held, looped, clip.loops != 0u);
+    let frame_0 = u32(floor(frame));
+    let frame_1 = select(min(frame_0 + 1u, clip.frames - 1u), (frame_0 + 1u) % clip.frames, clip.loops != 0u);
+    let t = fract(frame);
+
+    let row_0 = i32(clip.first_frame + min(frame_0, clip.frames - 1u));
+    let row_1 = i32(clip.first_frame + frame_1);
+    let column = i32(vertex_index);
+    let position = mix(
+        textureLoad(t_positions, vec2<i32>(column, row_0), 0),
+        textureLoad(t_positions, vec2<i32>(column, row_1), 0),
+        t,
+    );
+    let normal = mix(
+        textureLoad(t_normals, vec2<i32>(column, row_0), 0),
+        textureLoad(t_normals, vec2<i32>(column, row_1), 0),
+        t,
+    );
+
+    var out: VertexOutput;
+    let world_position = model_matrix * vec4<f32>(position.xyz, 1.0);
+    out.clip_position = uniforms.view_proj * world_position;
+    out.fog_coords = world_position.xz * uniforms.fog_transform.xy + uniforms.fog_transform.zw;
+    out.tex_coords = model.tex_coords;
+    out.team_color = instance.color;
+    out.layer = instance.layer;
+
+    let world_normal = normalize(normal_matrix * normal.xyz);
+    let light_dir = normalize(vec3<f32>(1., 3., 0.5));
+
+    let ambient = 0.1;
+    let diffuse = max(dot(world_normal, light_dir), 0.);
+    out.light = vec3<f32>(ambient + diffuse);
+
+    return out;
+}
+
+// Fragment shader
+
+[[group(1), binding(0)]]
+var t_fog: texture_2d<f32>;
+[[group(1), binding(1)]]
+var s_fog: sampler;
+
+// Every unit skin, one per layer. Alpha marks where team color goes.
+// Layer 0 is plain white and all team color.
+[[group(2), binding(0)]]
+var t_skins: texture_2d_array<f32>;
+[[group(2), binding(1)]]
+var s_skins: sampler;
+
+[[stage(fragment)]]
+fn main(in: VertexOutput) -> [[location(0)]] vec4<f32> {
+    let fog = textureSample(t_fog, s_fog, in.fog_coords).r;
+    let skin = textureSample(t_skins, s_skins, in.tex_coords, i32(in.layer));
+    let albedo = mix(skin.rgb, skin.rgb * in.team_color, skin.a);
+    return vec4<f32>(in.light * albedo * fog, 1.0);
+}